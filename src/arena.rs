@@ -1,14 +1,29 @@
 use std::any::Any;
+use std::cmp::Ordering;
+#[cfg(any(feature = "dump", feature = "checks"))]
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::mem::replace;
 use std::ops::Range;
 
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
 use gfx_hal::memory::Requirements;
 use gfx_hal::{Backend, MemoryTypeId};
 
 use block::{Block, RawBlock};
-use {alignment_shift, MemoryAllocator, MemoryError, MemorySubAllocator};
+#[cfg(feature = "checks")]
+use check_free;
+#[cfg(feature = "dump")]
+use dump::{ArenaDump, BlockDump, ChunkDump};
+#[cfg(feature = "dump")]
+use root::memory_key;
+use stats::ArenaStats;
+use {
+    alignment_shift, BlockError, MappingAllocator, MappingSubAllocator, MemoryAllocator,
+    MemoryError, MemorySubAllocator,
+};
 
 /// Sub-allocator that can be used for short-lived objects.
 ///
@@ -82,7 +97,7 @@ impl<T> ArenaAllocator<T> {
 
     /// Get the total size of all blocks allocated by this allocator.
     pub fn used(&self) -> u64 {
-        self.nodes.iter().map(|node| node.used - node.freed).sum()
+        self.all_nodes().map(|node| node.used - node.freed).sum()
     }
 
     /// Get the total size of all chunks allocated by this allocator.
@@ -90,7 +105,84 @@ impl<T> ArenaAllocator<T> {
     where
         T: Block,
     {
-        self.nodes.iter().map(|node| node.block.size()).sum()
+        self.all_nodes().map(|node| node.block.size()).sum()
+    }
+
+    /// Collect detailed statistics of the allocator.
+    pub fn stats(&self) -> ArenaStats
+    where
+        T: Block,
+    {
+        let largest_free = self
+            .hot
+            .as_ref()
+            .map(|hot| hot.block.size() - hot.used)
+            .unwrap_or(0);
+        let used = self.used();
+        let allocated = self.allocated();
+        ArenaStats {
+            nodes: self.all_nodes().count(),
+            blocks: self.all_nodes().map(|node| node.blocks).sum(),
+            used,
+            allocated,
+            wasted: allocated - used - largest_free,
+            largest_free,
+        }
+    }
+
+    /// Dump state of the allocator, including every block in use.
+    #[cfg(feature = "dump")]
+    pub fn dump(&self) -> ArenaDump
+    where
+        T: Block,
+    {
+        ArenaDump {
+            chunk_size: self.chunk_size,
+            nodes: self
+                .all_nodes()
+                .map(|node| ChunkDump {
+                    memory: memory_key(node.block.memory()) as u64,
+                    offset: node.block.range().start,
+                    size: node.block.size(),
+                    blocks: node
+                        .live
+                        .iter()
+                        .map(|(&offset, &size)| BlockDump { offset, size })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Check that the block can be freed by this allocator.
+    pub fn validate<M>(&self, block: &ArenaBlock<M>) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        self.validate_raw(&block.0, block.1)
+    }
+
+    pub(crate) fn validate_raw<M>(&self, block: &RawBlock<M>, index: u64) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        if index < self.freed {
+            return Err(BlockError::ReleasedChunk);
+        }
+        let index = (index - self.freed) as usize;
+        let node = match index.cmp(&self.nodes.len()) {
+            Ordering::Less => &self.nodes[index],
+            Ordering::Equal => self.hot.as_ref().ok_or(BlockError::ForeignBlock)?,
+            Ordering::Greater => return Err(BlockError::ForeignBlock),
+        };
+        node.validate(block)
+    }
+
+    /// Iterate over all nodes including the hot one.
+    fn all_nodes(&self) -> impl Iterator<Item = &ArenaNode<T>> {
+        self.nodes.iter().chain(self.hot.as_ref())
     }
 
     unsafe fn cleanup<B, A>(&mut self, owner: &mut A, device: &B::Device)
@@ -182,6 +274,8 @@ where
     }
 
     unsafe fn free(&mut self, owner: &mut O, device: &B::Device, block: ArenaBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
         let ArenaBlock(block, index) = block;
         let index = (index - self.freed) as usize;
 
@@ -193,7 +287,7 @@ where
                 self.nodes[index].free(block);
                 self.cleanup(owner, device);
             }
-            _ => unreachable!(),
+            _ => panic!("{}", BlockError::ForeignBlock),
         }
     }
 
@@ -213,6 +307,11 @@ where
 struct ArenaNode<T> {
     used: u64,
     freed: u64,
+    /// Number of blocks in use
+    blocks: usize,
+    /// Offsets and sizes of blocks in use, only tracked for dumps and validation
+    #[cfg(any(feature = "dump", feature = "checks"))]
+    live: BTreeMap<u64, u64>,
     block: T,
 }
 
@@ -221,6 +320,9 @@ impl<T> ArenaNode<T> {
         ArenaNode {
             used: 0,
             freed: 0,
+            blocks: 0,
+            #[cfg(any(feature = "dump", feature = "checks"))]
+            live: BTreeMap::new(),
             block,
         }
     }
@@ -231,15 +333,22 @@ impl<T> ArenaNode<T> {
         T: Block<Memory = M>,
     {
         let offset = self.block.range().start + self.used;
-        let total_size = reqs.size + alignment_shift(reqs.alignment, offset);
+        let shift = alignment_shift(reqs.alignment, offset);
+        let total_size = reqs.size + shift;
 
         if self.block.size() - self.used < total_size {
             None
         } else {
+            // Padding is never used, count it as freed right away.
             self.used += total_size;
+            self.freed += shift;
+            let offset = offset + shift;
+            self.blocks += 1;
+            #[cfg(any(feature = "dump", feature = "checks"))]
+            self.live.insert(offset, reqs.size);
             Some(RawBlock::new(
                 self.block.memory(),
-                offset..total_size + offset,
+                offset..reqs.size + offset,
             ))
         }
     }
@@ -251,9 +360,41 @@ impl<T> ArenaNode<T> {
     {
         assert!(self.block.contains(&block));
         self.freed += block.size();
+        self.blocks -= 1;
+        #[cfg(any(feature = "dump", feature = "checks"))]
+        self.live.remove(&block.range().start);
         unsafe { block.dispose() }
     }
 
+    fn validate<M>(&self, block: &RawBlock<M>) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        if !self.block.contains(block) {
+            return Err(BlockError::ForeignBlock);
+        }
+        #[cfg(any(feature = "dump", feature = "checks"))]
+        {
+            match self.live.get(&block.range().start) {
+                Some(&size) if size == block.size() => Ok(()),
+                Some(_) => Err(BlockError::InvalidRange),
+                None => Err(BlockError::DoubleFree),
+            }
+        }
+        // Without the blocks in use, double frees are only detected once the node is empty
+        #[cfg(not(any(feature = "dump", feature = "checks")))]
+        {
+            if block.range().end > self.block.range().start + self.used {
+                Err(BlockError::InvalidRange)
+            } else if self.blocks == 0 {
+                Err(BlockError::DoubleFree)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
     fn is_used(&self) -> bool {
         self.freed != self.used
     }
@@ -273,6 +414,58 @@ impl<T> ArenaNode<T> {
     }
 }
 
+impl<B, O, T> MappingSubAllocator<B, O> for ArenaAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MappingAllocator<B, Block = T>,
+{
+    unsafe fn map(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &ArenaBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.map(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn unmap(&mut self, owner: &mut O, device: &B::Device, block: &ArenaBlock<B::Memory>) {
+        owner.unmap(device, self.underlying_block(block))
+    }
+
+    unsafe fn flush(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &ArenaBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.flush(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &ArenaBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.invalidate(device, chunk, offset + range.start..offset + range.end)
+    }
+}
+
 /// `Block` type returned by `ArenaAllocator`.
 #[derive(Debug)]
 pub struct ArenaBlock<M>(pub(crate) RawBlock<M>, pub(crate) u64);
@@ -302,3 +495,63 @@ fn test_send_sync() {
         foo::<ArenaAllocator<M>>()
     }
 }
+
+#[test]
+fn test_alignment() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut arena = ArenaAllocator::new(MemoryTypeId(0), 4096);
+    let reqs = |size, alignment| Requirements {
+        size,
+        alignment,
+        type_mask: 1,
+    };
+    unsafe {
+        let a = arena.alloc(&mut root, &device, (), reqs(100, 1)).unwrap();
+        let b = arena.alloc(&mut root, &device, (), reqs(100, 256)).unwrap();
+        assert_eq!(b.range().start % 256, 0);
+        assert_eq!(b.size(), 100);
+        assert_eq!(arena.used(), 200);
+
+        // Move on to a new node. The old one becomes unused once its blocks are freed, even
+        // though part of it was skipped to align `b`.
+        let c = arena.alloc(&mut root, &device, (), reqs(4000, 1)).unwrap();
+        arena.free(&mut root, &device, a);
+        arena.free(&mut root, &device, b);
+        assert_eq!(arena.used(), 4000);
+        arena.free(&mut root, &device, c);
+        assert!(!arena.is_used());
+        arena.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+    assert!(device.leaks().is_empty());
+}
+
+#[test]
+fn test_hot_node_usage() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut arena = ArenaAllocator::new(MemoryTypeId(0), 4096);
+    let reqs = Requirements {
+        size: 100,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        // The only node is the hot one, which must be counted too.
+        let block = arena.alloc(&mut root, &device, (), reqs).unwrap();
+        assert_eq!(arena.used(), 100);
+        assert_eq!(arena.allocated(), 4096);
+        assert_eq!(arena.stats().nodes, 1);
+        arena.free(&mut root, &device, block);
+        assert_eq!(arena.used(), 0);
+        arena.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
@@ -0,0 +1,586 @@
+use std::any::Any;
+use std::cmp::max;
+#[cfg(feature = "dump")]
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::ops::Range;
+
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
+use gfx_hal::memory::Requirements;
+use gfx_hal::{Backend, MemoryTypeId};
+
+use block::{Block, RawBlock};
+#[cfg(feature = "checks")]
+use check_free;
+#[cfg(feature = "dump")]
+use dump::{BlockDump, BuddyDump, ChunkDump};
+#[cfg(feature = "dump")]
+use root::memory_key;
+use stats::BuddyStats;
+use {
+    alignment_shift, BlockError, MappingAllocator, MappingSubAllocator, MemoryAllocator,
+    MemoryError, MemorySubAllocator,
+};
+
+/// Chunk allocated from super-allocator
+#[derive(Debug)]
+struct BuddyChunk<T> {
+    /// Block from super-allocator
+    block: T,
+    /// Offsets of free blocks relative to the chunk, for each order
+    free: Vec<BTreeSet<u64>>,
+    /// Total size of blocks in use
+    used: u64,
+    /// Offsets and sizes of blocks in use, only tracked for dumps
+    #[cfg(feature = "dump")]
+    live: BTreeMap<u64, u64>,
+}
+
+/// Sub-allocator that can be used for long-lived objects of varying sizes.
+///
+/// This allocator allocates chunks of `chunk_size` bytes from the underlying allocator and splits
+/// them into power-of-two blocks no smaller than `min_block_size`. When a block is freed it is
+/// coalesced with its free buddy, so blocks of all sizes share the same chunks.
+///
+/// This allocator can only allocate memory `chunk_size` bytes in size or less.
+///
+/// Chunks that become empty are returned to the underlying allocator, except for `spare_chunks`
+/// of them which are kept to avoid reallocating on every spike. `trim` returns all empty chunks.
+///
+/// Requests are still rounded up to a power of two, so a 33 KiB block occupies 64 KiB of a chunk
+/// just like with `ChunkedAllocator`. What this allocator saves is the chunk per size class:
+/// freed space of one size can be reused by blocks of any other size.
+///
+/// `CombinedAllocator` serves `Type::General` requests with this allocator when
+/// `CombinedConfig::buddy` is enabled.
+///
+/// ### Type parameters:
+///
+/// - `T`: type of bigger blocks this allocator sub-allocates from.
+#[derive(Debug)]
+pub struct BuddyAllocator<T> {
+    id: MemoryTypeId,
+    min_block_size: u64,
+    chunk_size: u64,
+    spare_chunks: usize,
+    /// Number of blocks in use
+    blocks: usize,
+    /// Slots of released chunks are reused.
+    chunks: Vec<Option<BuddyChunk<T>>>,
+}
+
+impl<T> BuddyAllocator<T> {
+    /// Create a new buddy allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `id`: ID of the memory type this allocator allocates from.
+    /// - `min_block_size`: The minimum block size used by this allocator in bytes.
+    /// - `chunk_size`: The size of chunks allocated from the underlying allocator in bytes.
+    ///   Blocks larger than this cannot be allocated.
+    ///
+    /// ### Panics
+    ///
+    /// Panics if `min_block_size` or `chunk_size` are not a power of two or `chunk_size` is less
+    /// than `min_block_size`.
+    pub fn new(id: MemoryTypeId, min_block_size: u64, chunk_size: u64) -> Self {
+        assert!(min_block_size.is_power_of_two());
+        assert!(chunk_size.is_power_of_two());
+        assert!(min_block_size <= chunk_size);
+        BuddyAllocator {
+            id,
+            min_block_size,
+            chunk_size,
+            spare_chunks: 1,
+            blocks: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Check if any of the blocks allocated by this allocator are still in use.
+    /// If this function returns `false`, the allocator can be `dispose`d.
+    pub fn is_used(&self) -> bool {
+        self.blocks != 0
+    }
+
+    /// Get memory type of the allocator
+    pub fn memory_type(&self) -> MemoryTypeId {
+        self.id
+    }
+
+    /// Get minimum block size
+    pub fn min_block_size(&self) -> u64 {
+        self.min_block_size
+    }
+
+    /// Get the size of chunks
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /// Get the number of empty chunks kept.
+    pub fn spare_chunks(&self) -> usize {
+        self.spare_chunks
+    }
+
+    /// Set the number of empty chunks kept instead of returning them to the underlying
+    /// allocator. Takes effect the next time a chunk becomes empty.
+    pub fn set_spare_chunks(&mut self, spare_chunks: usize) {
+        self.spare_chunks = spare_chunks;
+    }
+
+    /// Retrieves the block backing an allocation.
+    pub fn underlying_block<M>(&self, block: &BuddyBlock<M>) -> &T {
+        &self.chunk(block.1).block
+    }
+
+    /// Check that the block can be freed by this allocator.
+    pub fn validate<M>(&self, block: &BuddyBlock<M>) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        self.validate_raw(&block.0, block.1)
+    }
+
+    pub(crate) fn validate_raw<M>(
+        &self,
+        block: &RawBlock<M>,
+        index: usize,
+    ) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        let chunk = match self.chunks.get(index) {
+            Some(Some(chunk)) => chunk,
+            Some(None) => return Err(BlockError::ReleasedChunk),
+            None => return Err(BlockError::ForeignBlock),
+        };
+        if !chunk.block.contains(block) {
+            return Err(BlockError::ForeignBlock);
+        }
+        let size = block.size();
+        let offset = block.range().start - chunk.block.range().start;
+        if !size.is_power_of_two() || size < self.min_block_size || offset & (size - 1) != 0 {
+            return Err(BlockError::InvalidRange);
+        }
+        // Neither the block nor a bigger block containing it may be free
+        for order in self.pick_order(size)..self.orders() {
+            let size = self.min_block_size << order;
+            if chunk.free[order].contains(&(offset & !(size - 1))) {
+                return Err(BlockError::DoubleFree);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the total size of all blocks allocated by this allocator.
+    pub fn used(&self) -> u64 {
+        self.chunks.iter().flatten().map(|chunk| chunk.used).sum()
+    }
+
+    /// Get the total size of all chunks allocated by this allocator.
+    pub fn allocated(&self) -> u64 {
+        self.chunks.iter().flatten().count() as u64 * self.chunk_size
+    }
+
+    /// Collect statistics of the allocator.
+    pub fn stats(&self) -> BuddyStats {
+        let free = self.chunks.iter().flatten().flat_map(|chunk| {
+            let min_block_size = self.min_block_size;
+            chunk
+                .free
+                .iter()
+                .enumerate()
+                .flat_map(move |(order, free)| free.iter().map(move |_| min_block_size << order))
+        });
+        let (free_blocks, largest_free) = free.fold((0, 0), |(count, largest), size| {
+            (count + 1, largest.max(size))
+        });
+        BuddyStats {
+            chunks: self.chunks.iter().flatten().count(),
+            blocks: self.blocks,
+            used: self.used(),
+            allocated: self.allocated(),
+            free_blocks,
+            largest_free,
+        }
+    }
+
+    /// Dump state of the allocator, including every block in use.
+    #[cfg(feature = "dump")]
+    pub fn dump(&self) -> BuddyDump
+    where
+        T: Block,
+    {
+        BuddyDump {
+            min_block_size: self.min_block_size,
+            chunk_size: self.chunk_size,
+            chunks: self
+                .chunks
+                .iter()
+                .flatten()
+                .map(|chunk| {
+                    let start = chunk.block.range().start;
+                    ChunkDump {
+                        memory: memory_key(chunk.block.memory()) as u64,
+                        offset: start,
+                        size: chunk.block.size(),
+                        blocks: chunk
+                            .live
+                            .iter()
+                            .map(|(&offset, &size)| BlockDump {
+                                offset: start + offset,
+                                size,
+                            })
+                            .collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Return all empty chunks to the underlying allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the chunks
+    /// - `device`: same device that was used to allocate the chunks
+    ///
+    /// ### Safety
+    ///
+    /// `owner` and `device` must be the ones the chunks were allocated with.
+    pub unsafe fn trim<B, O>(&mut self, owner: &mut O, device: &B::Device)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        self.release_empty(owner, device, 0);
+    }
+
+    fn chunk(&self, index: usize) -> &BuddyChunk<T> {
+        self.chunks[index].as_ref().expect("Chunk was released")
+    }
+
+    /// Number of orders, the biggest order is a whole chunk.
+    fn orders(&self) -> usize {
+        (self.chunk_size / self.min_block_size).trailing_zeros() as usize + 1
+    }
+
+    fn pick_order(&self, size: u64) -> usize {
+        let size = max(size, self.min_block_size).next_power_of_two();
+        (size / self.min_block_size).trailing_zeros() as usize
+    }
+
+    unsafe fn grow<B, O>(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        request: O::Request,
+    ) -> Result<usize, MemoryError>
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        let reqs = Requirements {
+            type_mask: 1 << self.id.0,
+            size: self.chunk_size,
+            alignment: self.chunk_size,
+        };
+        let block = owner.alloc(device, request, reqs)?;
+        assert_eq!(0, alignment_shift(reqs.alignment, block.range().start));
+        assert!(block.size() >= self.chunk_size);
+
+        // The whole chunk is a single free block of the biggest order
+        let mut free = vec![BTreeSet::new(); self.orders()];
+        free[self.orders() - 1].insert(0);
+        let chunk = BuddyChunk {
+            block,
+            free,
+            used: 0,
+            #[cfg(feature = "dump")]
+            live: BTreeMap::new(),
+        };
+
+        // Reuse slot of a released chunk or append a new one
+        match self.chunks.iter().position(Option::is_none) {
+            Some(index) => {
+                self.chunks[index] = Some(chunk);
+                Ok(index)
+            }
+            None => {
+                self.chunks.push(Some(chunk));
+                Ok(self.chunks.len() - 1)
+            }
+        }
+    }
+
+    /// Take a free block of `order` from the chunk, splitting a bigger one if necessary.
+    fn alloc_from<M>(&mut self, chunk_index: usize, order: usize) -> Option<BuddyBlock<M>>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        let min_block_size = self.min_block_size;
+        let chunk = self.chunks[chunk_index].as_mut()?;
+        let found = (order..chunk.free.len()).find(|&found| !chunk.free[found].is_empty())?;
+        let offset = *chunk.free[found].iter().next().unwrap();
+        chunk.free[found].remove(&offset);
+
+        // Return upper halves of split blocks to the free lists
+        for split in (order..found).rev() {
+            chunk.free[split].insert(offset + (min_block_size << split));
+        }
+
+        let size = min_block_size << order;
+        chunk.used += size;
+        #[cfg(feature = "dump")]
+        chunk.live.insert(offset, size);
+        let start = chunk.block.range().start + offset;
+        Some(BuddyBlock(
+            RawBlock::new(chunk.block.memory(), start..start + size),
+            chunk_index,
+        ))
+    }
+
+    /// Return empty chunks to super-allocator, keeping at most `keep` of them.
+    unsafe fn release_empty<B, O>(&mut self, owner: &mut O, device: &B::Device, keep: usize)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        let mut kept = 0;
+        for chunk in &mut self.chunks {
+            match *chunk {
+                Some(BuddyChunk { used: 0, .. }) if kept < keep => kept += 1,
+                Some(BuddyChunk { used: 0, .. }) => {
+                    owner.free(device, chunk.take().unwrap().block);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<B, O, T> MemorySubAllocator<B, O> for BuddyAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MemoryAllocator<B, Block = T>,
+{
+    type Request = O::Request;
+    type Block = BuddyBlock<B::Memory>;
+
+    unsafe fn alloc(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        request: O::Request,
+        reqs: Requirements,
+    ) -> Result<BuddyBlock<B::Memory>, MemoryError> {
+        if (1 << self.id.0) & reqs.type_mask == 0 {
+            return Err(MemoryError::NoCompatibleMemoryType);
+        }
+        // Blocks are aligned to their size
+        if max(reqs.size, reqs.alignment) > self.chunk_size {
+            return Err(MemoryError::TooLarge);
+        }
+        let order = self.pick_order(max(reqs.size, reqs.alignment));
+
+        let block = match (0..self.chunks.len()).find_map(|index| self.alloc_from(index, order)) {
+            Some(block) => block,
+            None => {
+                let index = self.grow(owner, device, request)?;
+                self.alloc_from(index, order).expect("Just growed")
+            }
+        };
+
+        assert!(block.size() >= reqs.size);
+        assert_eq!(block.range().start & (reqs.alignment - 1), 0);
+        self.blocks += 1;
+        Ok(block)
+    }
+
+    unsafe fn free(&mut self, owner: &mut O, device: &B::Device, block: BuddyBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
+        let size = block.size();
+        assert!(size.is_power_of_two() && size >= self.min_block_size);
+        let mut order = self.pick_order(size);
+        let orders = self.orders();
+        let min_block_size = self.min_block_size;
+        self.blocks -= 1;
+
+        let BuddyBlock(block, chunk_index) = block;
+        let chunk = self.chunks[chunk_index]
+            .as_mut()
+            .expect("Block of released chunk");
+        assert!(chunk.block.contains(&block));
+        let mut offset = block.range().start - chunk.block.range().start;
+        block.dispose();
+        chunk.used -= size;
+        #[cfg(feature = "dump")]
+        chunk.live.remove(&offset);
+
+        // Coalesce with free buddies
+        while order + 1 < orders {
+            let buddy = offset ^ (min_block_size << order);
+            if !chunk.free[order].remove(&buddy) {
+                break;
+            }
+            offset &= !(min_block_size << order);
+            order += 1;
+        }
+        chunk.free[order].insert(offset);
+
+        if chunk.used == 0 {
+            let keep = self.spare_chunks;
+            self.release_empty(owner, device, keep);
+        }
+    }
+
+    unsafe fn dispose(mut self, owner: &mut O, device: &B::Device) -> Result<(), Self> {
+        if self.is_used() {
+            Err(self)
+        } else {
+            for chunk in self.chunks.drain(..).flatten() {
+                owner.free(device, chunk.block);
+            }
+            Ok(())
+        }
+    }
+}
+
+impl<B, O, T> MappingSubAllocator<B, O> for BuddyAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MappingAllocator<B, Block = T>,
+{
+    unsafe fn map(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &BuddyBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.map(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn unmap(&mut self, owner: &mut O, device: &B::Device, block: &BuddyBlock<B::Memory>) {
+        owner.unmap(device, self.underlying_block(block))
+    }
+
+    unsafe fn flush(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &BuddyBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.flush(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &BuddyBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.invalidate(device, chunk, offset + range.start..offset + range.end)
+    }
+}
+
+/// `Block` type returned by `BuddyAllocator`.
+#[derive(Debug)]
+pub struct BuddyBlock<M>(pub(crate) RawBlock<M>, pub(crate) usize);
+
+impl<M> Block for BuddyBlock<M>
+where
+    M: Debug + Any,
+{
+    type Memory = M;
+
+    #[inline(always)]
+    fn memory(&self) -> &M {
+        self.0.memory()
+    }
+
+    #[inline(always)]
+    fn range(&self) -> Range<u64> {
+        self.0.range()
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn test_send_sync() {
+    fn foo<T: Send + Sync>() {}
+    fn bar<M: Send + Sync>() {
+        foo::<BuddyAllocator<M>>()
+    }
+}
+
+#[test]
+fn test_split_and_coalesce() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut buddy = BuddyAllocator::new(MemoryTypeId(0), 256, 4096);
+    let reqs = |size| Requirements {
+        size,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        let small = buddy.alloc(&mut root, &device, (), reqs(100)).unwrap();
+        let medium = buddy.alloc(&mut root, &device, (), reqs(1000)).unwrap();
+        let large = buddy.alloc(&mut root, &device, (), reqs(2048)).unwrap();
+        // All sizes share one chunk.
+        assert_eq!(device.object_count(), 1);
+        assert_eq!(small.size(), 256);
+        assert_eq!(medium.size(), 1024);
+        assert_eq!(medium.range().start % 1024, 0);
+        assert_eq!(buddy.used(), 256 + 1024 + 2048);
+
+        match buddy.alloc(&mut root, &device, (), reqs(8192)) {
+            Err(MemoryError::TooLarge) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        buddy.free(&mut root, &device, large);
+        buddy.free(&mut root, &device, small);
+        buddy.free(&mut root, &device, medium);
+        assert!(!buddy.is_used());
+
+        // Freed blocks are coalesced back into the whole chunk.
+        let whole = buddy.alloc(&mut root, &device, (), reqs(4096)).unwrap();
+        assert_eq!(device.object_count(), 1);
+        buddy.free(&mut root, &device, whole);
+
+        buddy.trim(&mut root, &device);
+        assert_eq!(device.object_count(), 0);
+        buddy.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use backtrace::Backtrace;
+use gfx_hal::MemoryTypeId;
+
+use block::Block;
+use combined::Type;
+use root::memory_key;
+
+/// Block in use, recorded by `CombinedAllocator` when the `checks` feature is enabled.
+///
+/// Collect blocks still in use when `dispose` fails to find out where they were allocated.
+#[derive(Clone, Debug)]
+pub struct LiveBlock {
+    /// Memory type the block was allocated from.
+    pub memory_type: MemoryTypeId,
+
+    /// Identifier of the device memory object the block belongs to.
+    pub memory: u64,
+
+    /// Offset of the block in the memory object.
+    pub offset: u64,
+
+    /// Size of the block.
+    pub size: u64,
+
+    /// Kind of allocation requested for the block.
+    pub request: Type,
+
+    /// Label given to the block, if any.
+    pub label: Option<String>,
+
+    /// Backtrace of the allocation.
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for LiveBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref label) = self.label {
+            write!(f, "\"{}\": ", label)?;
+        }
+        writeln!(
+            f,
+            "{} bytes at offset {} of memory {:#x} of type {} ({:?}), allocated at:",
+            self.size, self.offset, self.memory, self.memory_type.0, self.request,
+        )?;
+        write!(f, "{:?}", self.backtrace)
+    }
+}
+
+/// Blocks in use, keyed by memory object and offset.
+#[derive(Debug, Default)]
+pub(crate) struct LiveBlocks {
+    blocks: HashMap<(usize, u64), LiveBlock>,
+}
+
+impl LiveBlocks {
+    /// Record an allocated block, capturing the backtrace of the allocation.
+    pub(crate) fn insert<T>(&mut self, memory_type: MemoryTypeId, block: &T, request: Type)
+    where
+        T: Block,
+    {
+        let memory = memory_key(block.memory());
+        let offset = block.range().start;
+        self.blocks.insert(
+            (memory, offset),
+            LiveBlock {
+                memory_type,
+                memory: memory as u64,
+                offset,
+                size: block.size(),
+                request,
+                label: None,
+                backtrace: Backtrace::new_unresolved(),
+            },
+        );
+    }
+
+    /// Forget a freed block.
+    pub(crate) fn remove<T>(&mut self, block: &T)
+    where
+        T: Block,
+    {
+        self.blocks
+            .remove(&(memory_key(block.memory()), block.range().start));
+    }
+
+    /// Label a block in use.
+    pub(crate) fn set_label<T>(&mut self, block: &T, label: String)
+    where
+        T: Block,
+    {
+        if let Some(live) = self
+            .blocks
+            .get_mut(&(memory_key(block.memory()), block.range().start))
+        {
+            live.label = Some(label);
+        }
+    }
+
+    /// Collect blocks in use with resolved backtraces, sorted by memory and offset.
+    pub(crate) fn collect(&self) -> Vec<LiveBlock> {
+        let mut blocks: Vec<_> = self.blocks.values().cloned().collect();
+        blocks.sort_by_key(|block| (block.memory, block.offset));
+        for block in &mut blocks {
+            block.backtrace.resolve();
+        }
+        blocks
+    }
+}
+
+#[test]
+fn test_live_blocks() {
+    use combined::ResourceKind;
+    use gfx_hal::memory::{Properties, Requirements};
+    use mock::{MockBackend, MockDevice};
+    use smart::SmartAllocator;
+    use MemoryAllocator;
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 256,
+        type_mask: !0,
+    };
+    unsafe {
+        let freed = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        let leaked = allocator
+            .alloc(
+                &device,
+                (
+                    Type::ShortLived,
+                    Properties::CPU_VISIBLE.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        allocator.set_label(&leaked, "staging");
+        allocator.free(&device, freed);
+
+        let allocator = allocator.dispose(&device).unwrap_err();
+        let live = allocator.live_blocks();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].size, leaked.size());
+        assert_eq!(live[0].label, Some("staging".into()));
+        assert!(live[0].to_string().contains("test_live_blocks"));
+
+        allocator.free(&device, leaked);
+        assert!(allocator.live_blocks().is_empty());
+        allocator.dispose(&device).unwrap();
+    }
+}
@@ -1,14 +1,29 @@
 use std::any::Any;
-use std::cmp::{max, min};
+use std::cmp::{max, min, Reverse};
+#[cfg(feature = "dump")]
+use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::ops::Range;
 
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
 use gfx_hal::memory::Requirements;
 use gfx_hal::{Backend, MemoryTypeId};
 
 use block::{Block, RawBlock};
-use {alignment_shift, MemoryAllocator, MemoryError, MemorySubAllocator};
+#[cfg(feature = "checks")]
+use check_free;
+use defrag::{DefragBudget, DefragMove};
+#[cfg(feature = "dump")]
+use dump::{BlockDump, ChunkDump, ChunkedDump, SizeClassDump};
+#[cfg(feature = "dump")]
+use root::memory_key;
+use stats::{ChunkedStats, SizeClassStats};
+use {
+    alignment_shift, BlockError, MappingAllocator, MappingSubAllocator, MemoryAllocator,
+    MemoryError, MemorySubAllocator,
+};
 
 /// Chunks are super-allocator blocks,
 /// which are then divided into smaller 'blocks'
@@ -20,6 +35,18 @@ struct FreeBlock {
     block_index: u64,
 }
 
+/// Chunk allocated from super-allocator
+#[derive(Debug)]
+struct Chunk<T> {
+    /// Block from super-allocator
+    block: T,
+    /// Number of blocks in use
+    used: usize,
+    /// Indices of free blocks, kept out of the free list while the chunk is evacuated by
+    /// defragmentation
+    drained: Option<Vec<u64>>,
+}
+
 #[derive(Debug)]
 struct ChunkedNode<T> {
     id: MemoryTypeId,
@@ -27,18 +54,21 @@ struct ChunkedNode<T> {
     chunk_size: u64,
     /// Size of small blocks
     block_size: u64,
+    /// Number of empty chunks kept instead of returning them to super-allocator
+    spare_chunks: usize,
     /// List of free blocks
     free: VecDeque<FreeBlock>,
-    /// List of allocated chunks
-    chunks: Vec<T>,
+    /// List of allocated chunks. Slots of released chunks are reused.
+    chunks: Vec<Option<Chunk<T>>>,
 }
 
 impl<T> ChunkedNode<T> {
-    fn new(id: MemoryTypeId, chunk_size: u64, block_size: u64) -> Self {
+    fn new(id: MemoryTypeId, chunk_size: u64, block_size: u64, spare_chunks: usize) -> Self {
         ChunkedNode {
             id,
             chunk_size,
             block_size,
+            spare_chunks,
             free: VecDeque::new(),
             chunks: Vec::new(),
         }
@@ -46,12 +76,28 @@ impl<T> ChunkedNode<T> {
 
     fn is_used(&self) -> bool {
         // All blocks are free
-        self.count() != self.free.len()
+        self.count() != self.free_count()
+    }
+
+    fn free_count(&self) -> usize {
+        // Free blocks of evacuated chunks are not in the free list
+        let drained: usize = self
+            .chunks
+            .iter()
+            .flatten()
+            .filter_map(|chunk| chunk.drained.as_ref())
+            .map(Vec::len)
+            .sum();
+        self.free.len() + drained
     }
 
     fn count(&self) -> usize {
         // Blocks count is chunk count multiplied by blocks per chunk
-        self.chunks.len() * self.blocks_per_chunk()
+        self.chunk_count() * self.blocks_per_chunk()
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.chunks.iter().filter(|chunk| chunk.is_some()).count()
     }
 
     fn blocks_per_chunk(&self) -> usize {
@@ -60,11 +106,108 @@ impl<T> ChunkedNode<T> {
     }
 
     fn used(&self) -> u64 {
-        (self.count() - self.free.len()) as u64 * self.block_size
+        (self.count() - self.free_count()) as u64 * self.block_size
     }
 
     fn allocated(&self) -> u64 {
-        self.chunks.len() as u64 * self.chunk_size
+        self.chunk_count() as u64 * self.chunk_size
+    }
+
+    fn stats(&self) -> SizeClassStats {
+        SizeClassStats {
+            block_size: self.block_size,
+            chunk_size: self.chunk_size,
+            chunks: self.chunk_count(),
+            blocks: self.count(),
+            used_blocks: self.count() - self.free_count(),
+        }
+    }
+
+    #[cfg(feature = "dump")]
+    fn dump(&self) -> SizeClassDump
+    where
+        T: Block,
+    {
+        let drained = self
+            .chunks
+            .iter()
+            .enumerate()
+            .flat_map(|(chunk_index, chunk)| {
+                chunk
+                    .iter()
+                    .flat_map(|chunk| chunk.drained.iter().flatten())
+                    .map(move |&block_index| (chunk_index, block_index))
+            });
+        let free: HashSet<(usize, u64)> = self
+            .free
+            .iter()
+            .map(|free_block| (free_block.chunk_index, free_block.block_index))
+            .chain(drained)
+            .collect();
+        let chunks = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(chunk_index, chunk)| chunk.as_ref().map(|chunk| (chunk_index, chunk)))
+            .map(|(chunk_index, chunk)| {
+                let start = chunk.block.range().start;
+                ChunkDump {
+                    memory: memory_key(chunk.block.memory()) as u64,
+                    offset: start,
+                    size: chunk.block.size(),
+                    blocks: (0..self.blocks_per_chunk() as u64)
+                        .filter(|&block_index| !free.contains(&(chunk_index, block_index)))
+                        .map(|block_index| BlockDump {
+                            offset: start + block_index * self.block_size,
+                            size: self.block_size,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        SizeClassDump {
+            block_size: self.block_size,
+            chunk_size: self.chunk_size,
+            chunks,
+        }
+    }
+
+    fn chunk(&self, index: usize) -> &Chunk<T> {
+        self.chunks[index].as_ref().expect("Chunk was released")
+    }
+
+    fn validate<M>(&self, block: &RawBlock<M>, chunk_index: usize) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        let chunk = match self.chunks.get(chunk_index) {
+            Some(Some(chunk)) => chunk,
+            Some(None) => return Err(BlockError::ReleasedChunk),
+            None => return Err(BlockError::ForeignBlock),
+        };
+        if !chunk.block.contains(block) {
+            return Err(BlockError::ForeignBlock);
+        }
+        let offset = block.range().start - chunk.block.range().start;
+        let block_index = offset / self.block_size;
+        if block_index * self.block_size != offset || block.size() != self.block_size {
+            return Err(BlockError::InvalidRange);
+        }
+        let free = self
+            .free
+            .iter()
+            .any(|free| free.chunk_index == chunk_index && free.block_index == block_index)
+            || chunk
+                .drained
+                .iter()
+                .flatten()
+                .any(|&index| index == block_index);
+        if free {
+            Err(BlockError::DoubleFree)
+        } else {
+            Ok(())
+        }
     }
 
     unsafe fn grow<B, A>(
@@ -90,8 +233,14 @@ impl<T> ChunkedNode<T> {
 
         let blocks_per_chunk = self.blocks_per_chunk();
 
-        // `len()` will return the next index to use
-        let chunk_index = self.chunks.len();
+        // Reuse slot of a released chunk or append a new one
+        let chunk_index = match self.chunks.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.chunks.push(None);
+                self.chunks.len() - 1
+            }
+        };
 
         // Fill the free list with new blocks
         self.free.extend((0..blocks_per_chunk).map(|i| FreeBlock {
@@ -100,7 +249,11 @@ impl<T> ChunkedNode<T> {
         }));
 
         // Place the new chunk in the list
-        self.chunks.push(chunk);
+        self.chunks[chunk_index] = Some(Chunk {
+            block: chunk,
+            used: 0,
+            drained: None,
+        });
 
         Ok(())
     }
@@ -111,18 +264,139 @@ impl<T> ChunkedNode<T> {
         T: Block<Memory = M>,
     {
         // Find a free block
-        self.free.pop_front().map(|free_block| {
-            // Memory offset is block index times block size
-            // plus chunk memory offset
-            let offset = free_block.block_index * self.block_size
-                + self.chunks[free_block.chunk_index].range().start;
-            let block = RawBlock::new(
-                self.chunks[free_block.chunk_index].memory(),
-                offset..self.block_size + offset,
-            );
-            // Remember what chunk the block came from
-            ChunkedBlock(block, free_block.chunk_index)
-        })
+        let free_block = self.free.pop_front()?;
+        let chunk = self.chunks[free_block.chunk_index]
+            .as_mut()
+            .expect("Free block of released chunk");
+        chunk.used += 1;
+        // Memory offset is block index times block size
+        // plus chunk memory offset
+        let offset = free_block.block_index * self.block_size + chunk.block.range().start;
+        let block = RawBlock::new(chunk.block.memory(), offset..self.block_size + offset);
+        // Remember what chunk the block came from
+        Some(ChunkedBlock(block, free_block.chunk_index))
+    }
+
+    /// Plan moves of blocks that empty the least used chunks into free blocks of other chunks.
+    ///
+    /// `blocks` are indices of movable blocks paired with indices of their chunks. Only chunks
+    /// whose blocks are all movable are evacuated. Evacuated chunks are excluded from allocation
+    /// until `end_defrag`.
+    fn plan_defrag<M>(
+        &mut self,
+        blocks: Vec<(usize, usize)>,
+        budget: &mut DefragBudget,
+    ) -> Vec<DefragMove<ChunkedBlock<M>>>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        let mut movable = vec![0; self.chunks.len()];
+        for &(_, chunk_index) in &blocks {
+            movable[chunk_index] += 1;
+        }
+        let mut free = vec![0; self.chunks.len()];
+        for free_block in &self.free {
+            free[free_block.chunk_index] += 1;
+        }
+
+        // Evacuate the least used chunks first
+        let mut candidates: Vec<usize> = (0..self.chunks.len())
+            .filter(|&chunk_index| match self.chunks[chunk_index] {
+                Some(ref chunk) => {
+                    chunk.drained.is_none() && chunk.used > 0 && chunk.used == movable[chunk_index]
+                }
+                None => false,
+            })
+            .collect();
+        candidates.sort_by_key(|&chunk_index| self.chunk(chunk_index).used);
+
+        let mut sources = Vec::new();
+        let mut moved = 0;
+        let mut available = self.free.len();
+        for chunk_index in candidates {
+            let used = self.chunk(chunk_index).used;
+            let bytes = used as u64 * self.block_size;
+            // Blocks must fit into free blocks of the chunks that stay
+            if moved + used > available - free[chunk_index] || !budget.allows(bytes, used) {
+                break;
+            }
+            budget.spend(bytes, used);
+            moved += used;
+            available -= free[chunk_index];
+            sources.push(chunk_index);
+        }
+
+        for &chunk_index in &sources {
+            let drained = self
+                .free
+                .iter()
+                .filter(|free_block| free_block.chunk_index == chunk_index)
+                .map(|free_block| free_block.block_index)
+                .collect();
+            self.free
+                .retain(|free_block| free_block.chunk_index != chunk_index);
+            self.chunks[chunk_index].as_mut().unwrap().drained = Some(drained);
+        }
+
+        // Fill the most used chunks first
+        {
+            let chunks = &self.chunks;
+            self.free.make_contiguous().sort_by_key(|free_block| {
+                Reverse(chunks[free_block.chunk_index].as_ref().unwrap().used)
+            });
+        }
+
+        blocks
+            .into_iter()
+            .filter(|&(_, chunk_index)| sources.contains(&chunk_index))
+            .map(|(index, _)| DefragMove {
+                index,
+                block: self.alloc_no_grow().expect("Free blocks were counted"),
+            })
+            .collect()
+    }
+
+    /// Return evacuated chunks to allocation.
+    unsafe fn end_defrag<B, A>(&mut self, owner: &mut A, device: &B::Device)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        A: MemoryAllocator<B, Block = T>,
+    {
+        for (chunk_index, chunk) in self.chunks.iter_mut().enumerate() {
+            if let Some(drained) = chunk.as_mut().and_then(|chunk| chunk.drained.take()) {
+                self.free
+                    .extend(drained.into_iter().map(|block_index| FreeBlock {
+                        chunk_index,
+                        block_index,
+                    }));
+            }
+        }
+        let keep = self.spare_chunks;
+        self.release_empty(owner, device, keep);
+    }
+
+    /// Return empty chunks to super-allocator, keeping at most `keep` of them.
+    unsafe fn release_empty<B, A>(&mut self, owner: &mut A, device: &B::Device, keep: usize)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        A: MemoryAllocator<B, Block = T>,
+    {
+        let mut kept = 0;
+        for chunk_index in 0..self.chunks.len() {
+            match self.chunks[chunk_index] {
+                Some(Chunk { used: 0, .. }) if kept < keep => kept += 1,
+                Some(Chunk { used: 0, .. }) => {
+                    let chunk = self.chunks[chunk_index].take().unwrap();
+                    self.free
+                        .retain(|free_block| free_block.chunk_index != chunk_index);
+                    owner.free(device, chunk.block);
+                }
+                _ => {}
+            }
+        }
     }
 }
 
@@ -163,7 +437,7 @@ where
         Ok(block)
     }
 
-    unsafe fn free(&mut self, _owner: &mut O, _device: &B::Device, block: ChunkedBlock<B::Memory>) {
+    unsafe fn free(&mut self, owner: &mut O, device: &B::Device, block: ChunkedBlock<B::Memory>) {
         assert_eq!(block.range().start % self.block_size, 0);
         assert_eq!(block.size(), self.block_size);
         let offset = block.range().start;
@@ -177,26 +451,44 @@ where
 
         // Confirm the chunk index
         assert!(::std::ptr::eq(
-            self.chunks[chunk_index].memory(),
+            self.chunk(chunk_index).block.memory(),
             block_memory
         ));
 
         // Calculate the block index inside the chunk
-        let block_index = (offset - self.chunks[chunk_index].range().start) / self.block_size;
+        let block_index = (offset - self.chunk(chunk_index).block.range().start) / self.block_size;
 
-        // Push the block back into the 'free blocks' list
-        self.free.push_front(FreeBlock {
-            block_index,
-            chunk_index,
-        });
+        let chunk = self.chunks[chunk_index].as_mut().unwrap();
+        chunk.used -= 1;
+        match chunk.drained {
+            // Evacuated chunk is returned as soon as it is empty
+            Some(ref mut drained) => {
+                drained.push(block_index);
+                if chunk.used == 0 {
+                    let chunk = self.chunks[chunk_index].take().unwrap();
+                    owner.free(device, chunk.block);
+                }
+            }
+            None => {
+                // Push the block back into the 'free blocks' list
+                self.free.push_front(FreeBlock {
+                    block_index,
+                    chunk_index,
+                });
+                if chunk.used == 0 {
+                    let keep = self.spare_chunks;
+                    self.release_empty(owner, device, keep);
+                }
+            }
+        }
     }
 
     unsafe fn dispose(mut self, owner: &mut O, device: &B::Device) -> Result<(), Self> {
         if self.is_used() {
             Err(self)
         } else {
-            for chunk in self.chunks.drain(..) {
-                owner.free(device, chunk);
+            for chunk in self.chunks.drain(..).flatten() {
+                owner.free(device, chunk.block);
             }
             Ok(())
         }
@@ -212,6 +504,10 @@ where
 ///
 /// This allocator can only allocate memory `max_chunk_size` bytes in size or less.
 ///
+/// Chunks that become empty are returned to the underlying allocator, except for
+/// `spare_chunks` of them per block size which are kept to avoid reallocating on every spike.
+/// `trim` returns all empty chunks.
+///
 /// ### Type parameters:
 ///
 /// - `T`: type of bigger blocks this allocator sub-allocates from.
@@ -221,6 +517,7 @@ pub struct ChunkedAllocator<T> {
     blocks_per_chunk: usize,
     min_block_size: u64,
     max_chunk_size: u64,
+    spare_chunks: usize,
     nodes: Vec<ChunkedNode<T>>,
 }
 
@@ -253,10 +550,159 @@ impl<T> ChunkedAllocator<T> {
             blocks_per_chunk,
             min_block_size,
             max_chunk_size,
+            spare_chunks: 1,
             nodes: Vec::new(),
         }
     }
 
+    /// Get the number of empty chunks kept per block size.
+    pub fn spare_chunks(&self) -> usize {
+        self.spare_chunks
+    }
+
+    /// Set the number of empty chunks kept per block size instead of returning them to the
+    /// underlying allocator. Takes effect the next time a chunk becomes empty.
+    pub fn set_spare_chunks(&mut self, spare_chunks: usize) {
+        self.spare_chunks = spare_chunks;
+        for node in &mut self.nodes {
+            node.spare_chunks = spare_chunks;
+        }
+    }
+
+    /// Return all empty chunks to the underlying allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the chunks
+    /// - `device`: same device that was used to allocate the chunks
+    ///
+    /// ### Safety
+    ///
+    /// `owner` and `device` must be the ones the chunks were allocated with.
+    pub unsafe fn trim<B, O>(&mut self, owner: &mut O, device: &B::Device)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        for node in &mut self.nodes {
+            node.release_empty(owner, device, 0);
+        }
+    }
+
+    /// Plan moves of blocks that compact them into fewer chunks.
+    ///
+    /// Only chunks whose blocks are all in `blocks` can be emptied. Blocks are moved into free
+    /// blocks of other chunks of the same size, no new chunks are allocated. Evacuated chunks are
+    /// not used for allocation until the defragmentation is committed or cancelled, so only one
+    /// defragmentation can be in progress at a time.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `blocks`: blocks that may be moved
+    /// - `budget`: limits of the work to plan, reduced by the planned moves
+    pub fn plan_defrag<M>(
+        &mut self,
+        blocks: &[&ChunkedBlock<M>],
+        budget: &mut DefragBudget,
+    ) -> Vec<DefragMove<ChunkedBlock<M>>>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        self.plan_defrag_blocks(
+            blocks
+                .iter()
+                .enumerate()
+                .map(|(index, block)| (index, block.size(), block.1)),
+            budget,
+        )
+    }
+
+    /// Plan moves of blocks given by their index, size and chunk index. See `plan_defrag`.
+    pub(crate) fn plan_defrag_blocks<M, I>(
+        &mut self,
+        blocks: I,
+        budget: &mut DefragBudget,
+    ) -> Vec<DefragMove<ChunkedBlock<M>>>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+        I: IntoIterator<Item = (usize, u64, usize)>,
+    {
+        let mut per_node = vec![Vec::new(); self.nodes.len()];
+        for (index, size, chunk_index) in blocks {
+            per_node[self.pick_node(size) as usize].push((index, chunk_index));
+        }
+        let mut moves = Vec::new();
+        for (node, blocks) in self.nodes.iter_mut().zip(per_node) {
+            moves.extend(node.plan_defrag(blocks, budget));
+        }
+        moves
+    }
+
+    /// Free the old blocks of the planned moves once their contents are copied, returning the
+    /// evacuated chunks to the underlying allocator. Evacuated chunks that still have blocks
+    /// in use are used for allocation again.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the chunks
+    /// - `device`: same device that was used to allocate the chunks
+    /// - `blocks`: old blocks of the planned moves
+    ///
+    /// ### Safety
+    ///
+    /// `owner` and `device` must be the ones the blocks were allocated with. Blocks must not be
+    /// used by the device anymore.
+    pub unsafe fn commit_defrag<B, O, I>(&mut self, owner: &mut O, device: &B::Device, blocks: I)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+        I: IntoIterator<Item = ChunkedBlock<B::Memory>>,
+    {
+        self.end_defrag(owner, device, blocks)
+    }
+
+    /// Abandon the planned moves, freeing their new blocks and using the evacuated chunks for
+    /// allocation again.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the chunks
+    /// - `device`: same device that was used to allocate the chunks
+    /// - `blocks`: new blocks of the planned moves
+    ///
+    /// ### Safety
+    ///
+    /// `owner` and `device` must be the ones the blocks were allocated with. Blocks must not be
+    /// used by the device anymore.
+    pub unsafe fn cancel_defrag<B, O, I>(&mut self, owner: &mut O, device: &B::Device, blocks: I)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+        I: IntoIterator<Item = ChunkedBlock<B::Memory>>,
+    {
+        self.end_defrag(owner, device, blocks)
+    }
+
+    unsafe fn end_defrag<B, O, I>(&mut self, owner: &mut O, device: &B::Device, blocks: I)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+        I: IntoIterator<Item = ChunkedBlock<B::Memory>>,
+    {
+        for block in blocks {
+            self.free(owner, device, block);
+        }
+        for node in &mut self.nodes {
+            node.end_defrag(owner, device);
+        }
+    }
+
     /// Check if any of the blocks allocated by this allocator are still in use.
     /// If this function returns `false`, the allocator can be `dispose`d.
     pub fn is_used(&self) -> bool {
@@ -286,7 +732,34 @@ impl<T> ChunkedAllocator<T> {
     /// Retrieves the block backing an allocation.
     pub fn underlying_block<M: Debug + Any>(&self, block: &ChunkedBlock<M>) -> &T {
         let index = self.pick_node(block.size());
-        &self.nodes[index as usize].chunks[block.1]
+        &self.nodes[index as usize].chunk(block.1).block
+    }
+
+    /// Check that the block can be freed by this allocator.
+    pub fn validate<M>(&self, block: &ChunkedBlock<M>) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        self.validate_raw(&block.0, block.1)
+    }
+
+    pub(crate) fn validate_raw<M>(
+        &self,
+        block: &RawBlock<M>,
+        chunk_index: usize,
+    ) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        if block.size() == 0 || block.size() > self.max_chunk_size {
+            return Err(BlockError::InvalidRange);
+        }
+        self.nodes
+            .get(self.pick_node(block.size()) as usize)
+            .ok_or(BlockError::ForeignBlock)?
+            .validate(block, chunk_index)
     }
 
     /// Get the total size of all blocks allocated by this allocator.
@@ -299,6 +772,35 @@ impl<T> ChunkedAllocator<T> {
         self.nodes.iter().map(|node| node.allocated()).sum()
     }
 
+    /// Collect detailed statistics of the allocator.
+    pub fn stats(&self) -> ChunkedStats {
+        let size_classes: Vec<_> = self.nodes.iter().map(ChunkedNode::stats).collect();
+        ChunkedStats {
+            chunks: size_classes.iter().map(|class| class.chunks).sum(),
+            blocks: size_classes.iter().map(|class| class.used_blocks).sum(),
+            used: self.used(),
+            allocated: self.allocated(),
+            largest_free: size_classes
+                .iter()
+                .filter(|class| class.used_blocks < class.blocks)
+                .map(|class| class.block_size)
+                .max()
+                .unwrap_or(0),
+            size_classes,
+        }
+    }
+
+    /// Dump state of the allocator, including every block in use.
+    #[cfg(feature = "dump")]
+    pub fn dump(&self) -> ChunkedDump
+    where
+        T: Block,
+    {
+        ChunkedDump {
+            size_classes: self.nodes.iter().map(ChunkedNode::dump).collect(),
+        }
+    }
+
     fn block_size(&self, index: u8) -> u64 {
         self.min_block_size * (1u64 << (index as u8))
     }
@@ -329,7 +831,12 @@ impl<T> ChunkedAllocator<T> {
         let range = len..index + 1;
         self.nodes.reserve(range.len());
         for index in range {
-            let node = ChunkedNode::new(id, self.chunk_size(index), self.block_size(index));
+            let node = ChunkedNode::new(
+                id,
+                self.chunk_size(index),
+                self.block_size(index),
+                self.spare_chunks,
+            );
             self.nodes.push(node);
         }
     }
@@ -352,7 +859,7 @@ where
         reqs: Requirements,
     ) -> Result<ChunkedBlock<B::Memory>, MemoryError> {
         if max(reqs.size, reqs.alignment) > self.max_chunk_size {
-            return Err(MemoryError::OutOfMemory);
+            return Err(MemoryError::TooLarge);
         }
         let index = self.pick_node(max(reqs.size, reqs.alignment));
         self.grow(index);
@@ -360,6 +867,8 @@ where
     }
 
     unsafe fn free(&mut self, owner: &mut O, device: &B::Device, block: ChunkedBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
         let index = self.pick_node(block.size());
         self.nodes[index as usize].free(owner, device, block);
     }
@@ -376,6 +885,58 @@ where
     }
 }
 
+impl<B, O, T> MappingSubAllocator<B, O> for ChunkedAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MappingAllocator<B, Block = T>,
+{
+    unsafe fn map(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &ChunkedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.map(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn unmap(&mut self, owner: &mut O, device: &B::Device, block: &ChunkedBlock<B::Memory>) {
+        owner.unmap(device, self.underlying_block(block))
+    }
+
+    unsafe fn flush(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &ChunkedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.flush(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &ChunkedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.invalidate(device, chunk, offset + range.start..offset + range.end)
+    }
+}
+
 /// `Block` type returned by `ChunkedAllocator`.
 #[derive(Debug)]
 pub struct ChunkedBlock<M>(pub(crate) RawBlock<M>, pub(crate) usize);
@@ -405,3 +966,124 @@ fn test_send_sync() {
         foo::<ChunkedAllocator<M>>()
     }
 }
+
+#[test]
+fn test_release_empty_chunks() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut chunked = ChunkedAllocator::new(MemoryTypeId(0), 4, 256, 1 << 20);
+    let reqs = Requirements {
+        size: 256,
+        alignment: 256,
+        type_mask: 1,
+    };
+    unsafe {
+        let blocks = (0..8)
+            .map(|_| chunked.alloc(&mut root, &device, (), reqs).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(device.object_count(), 2);
+        for block in blocks {
+            chunked.free(&mut root, &device, block);
+        }
+        // One spare chunk is kept.
+        assert_eq!(device.object_count(), 1);
+        chunked.trim(&mut root, &device);
+        assert_eq!(device.object_count(), 0);
+        chunked.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_defrag() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut chunked = ChunkedAllocator::new(MemoryTypeId(0), 4, 256, 1 << 20);
+    let reqs = Requirements {
+        size: 256,
+        alignment: 256,
+        type_mask: 1,
+    };
+    unsafe {
+        let mut blocks = (0..8)
+            .map(|_| chunked.alloc(&mut root, &device, (), reqs).unwrap())
+            .collect::<Vec<_>>();
+        // Leave one block in the first chunk and three in the second.
+        for &index in &[5, 3, 2, 1] {
+            let block = blocks.remove(index);
+            chunked.free(&mut root, &device, block);
+        }
+        assert_eq!(device.object_count(), 2);
+
+        let mut budget = DefragBudget { bytes: 0, moves: 1 };
+        assert!(chunked
+            .plan_defrag(&blocks.iter().collect::<Vec<_>>(), &mut budget)
+            .is_empty());
+
+        let mut budget = DefragBudget::unlimited();
+        let moves = chunked.plan_defrag(&blocks.iter().collect::<Vec<_>>(), &mut budget);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].index, 0);
+        assert_eq!(budget.moves, !0 - 1);
+        assert_eq!(chunked.used(), 5 * 256);
+
+        for DefragMove { index, block } in moves {
+            let old = ::std::mem::replace(&mut blocks[index], block);
+            chunked.commit_defrag(&mut root, &device, Some(old));
+        }
+        assert_eq!(device.object_count(), 1);
+        assert_eq!(chunked.used(), 4 * 256);
+
+        for block in blocks {
+            chunked.free(&mut root, &device, block);
+        }
+        chunked.trim(&mut root, &device);
+        chunked.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_validate() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+    use BlockError;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut chunked = ChunkedAllocator::new(MemoryTypeId(0), 4, 256, 1 << 20);
+    let mut other = ChunkedAllocator::new(MemoryTypeId(0), 4, 256, 1 << 20);
+    let reqs = Requirements {
+        size: 256,
+        alignment: 256,
+        type_mask: 1,
+    };
+    unsafe {
+        let block = chunked.alloc(&mut root, &device, (), reqs).unwrap();
+        let other_block = other.alloc(&mut root, &device, (), reqs).unwrap();
+        assert_eq!(chunked.validate(&block), Ok(()));
+        assert_eq!(
+            chunked.validate(&other_block),
+            Err(BlockError::ForeignBlock)
+        );
+
+        // Copy of the block as left behind by a cloned pointer.
+        let copy = ChunkedBlock(RawBlock::new(block.memory(), block.range()), block.1);
+        chunked.free(&mut root, &device, block);
+        assert_eq!(chunked.validate(&copy), Err(BlockError::DoubleFree));
+        copy.0.dispose();
+
+        other.free(&mut root, &device, other_block);
+        chunked.trim(&mut root, &device);
+        other.trim(&mut root, &device);
+        chunked.dispose(&mut root, &device).unwrap();
+        other.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
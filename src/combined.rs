@@ -1,15 +1,36 @@
 use std::any::Any;
+use std::cmp::max;
+#[cfg(feature = "dump")]
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
 use gfx_hal::memory::Requirements;
 use gfx_hal::{Backend, MemoryTypeId};
 
 use arena::{ArenaAllocator, ArenaBlock};
 use block::{Block, RawBlock};
+use buddy::{BuddyAllocator, BuddyBlock};
+#[cfg(feature = "checks")]
+use checks::{LiveBlock, LiveBlocks};
 use chunked::{ChunkedAllocator, ChunkedBlock};
-use root::RootAllocator;
-use {MemoryAllocator, MemoryError, MemorySubAllocator};
+use config::CombinedConfig;
+use defrag::{DefragBudget, DefragMove};
+#[cfg(feature = "dump")]
+use dump::{BlockDump, ChunkDump, CombinedDump};
+use guard::{CorruptedBlock, GuardedBlock, Guards};
+use root::{LockedRoot, RootAllocator};
+use smart::HeapUsage;
+use stats::CombinedStats;
+use tlsf::{TlsfAllocator, TlsfBlock};
+use {
+    shift_for_alignment, BlockError, MappingAllocator, MappingSubAllocator, MemoryAllocator,
+    MemoryError, MemorySubAllocator,
+};
 
 /// Controls what sub allocator is used for an allocation by `CombinedAllocator`
 #[derive(Clone, Copy, Debug)]
@@ -19,12 +40,52 @@ pub enum Type {
 
     /// General purpose.
     General,
+
+    /// For mid-sized objects with medium lifetime, such as streamed textures and mesh pages,
+    /// which come in arbitrary sizes and are freed in arbitrary order.
+    MediumLived,
+
+    /// Separate device memory object for a single resource.
+    /// Suitable for big resources such as render targets and huge buffers.
+    Dedicated,
+}
+
+/// Kind of resource a block is allocated for, which matters for the buffer-image granularity.
+///
+/// Linear and optimal resources must not share a page of `buffer_image_granularity` bytes in
+/// the same memory object, or they may alias on some hardware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    /// Buffers and images with linear tiling.
+    Linear,
+
+    /// Images with optimal tiling.
+    Optimal,
+
+    /// Resource of unknown kind, e.g. memory that is aliased by several resources.
+    Unknown,
 }
 
 /// Allocator with support for both short-lived and long-lived allocations.
 ///
-/// This allocator allocates blocks using either an `ArenaAllocator` or a `ChunkedAllocator`
-/// depending on which kind of allocation is requested.
+/// This allocator allocates blocks using an `ArenaAllocator`, a `ChunkedAllocator`, a
+/// `TlsfAllocator` or a `BuddyAllocator` depending on which kind of allocation is requested and which sub-allocators
+/// are enabled by the `CombinedConfig`. Dedicated allocations and blocks bigger than the
+/// dedicated threshold are allocated directly from the `RootAllocator`.
+///
+/// Sub-allocated blocks are aligned and padded to the non-coherent atom size, so flushing or
+/// invalidating one block never touches its neighbours. Blocks for resources other than
+/// `ResourceKind::Linear` are also aligned and padded to the buffer-image granularity, so they
+/// never share a page with linear resources.
+///
+/// For debugging of host-visible memory types, sub-allocated blocks can be surrounded by guard
+/// bands filled with `GUARD_PATTERN`. Bands are checked when the block is freed and by
+/// `check_corruption`, catching writes past either end of a block. See `set_guard_size`.
+///
+/// Each sub-allocator is locked separately, so blocks can also be allocated and freed through
+/// `&self` from several threads, as `SmartAllocator` does. Only requests served by the same
+/// sub-allocator contend. The `RootAllocator` is locked on its own, only while memory objects
+/// are allocated, freed or mapped.
 ///
 /// ### Type parameters:
 ///
@@ -34,18 +95,28 @@ pub struct CombinedAllocator<B>
 where
     B: Backend,
 {
-    root: RootAllocator<B>,
-    root_used: u64,
-    arenas: ArenaAllocator<RawBlock<B::Memory>>,
-    chunks: ChunkedAllocator<RawBlock<B::Memory>>,
-    allocations: usize,
+    memory_type: MemoryTypeId,
+    non_coherent_atom_size: u64,
+    root: Mutex<RootAllocator<B>>,
+    root_used: AtomicU64,
+    config: CombinedConfig,
+    buffer_image_granularity: u64,
+    arenas: Mutex<ArenaAllocator<RawBlock<B::Memory>>>,
+    chunks: Mutex<ChunkedAllocator<RawBlock<B::Memory>>>,
+    tlsf: Mutex<TlsfAllocator<RawBlock<B::Memory>>>,
+    buddy: Mutex<BuddyAllocator<RawBlock<B::Memory>>>,
+    allocations: AtomicUsize,
+    guards: Mutex<Guards<B::Memory>>,
+    #[cfg(feature = "checks")]
+    live: Mutex<LiveBlocks>,
 }
 
 impl<B> CombinedAllocator<B>
 where
     B: Backend,
 {
-    /// Create a combined allocator.
+    /// Create a combined allocator with all sub-allocators but the `BuddyAllocator` enabled.
+    /// See `CombinedConfig::new`.
     ///
     /// ### Parameters:
     ///
@@ -53,119 +124,922 @@ where
     /// - `arena_chunk_size`: see `ArenaAllocator`
     /// - `blocks_per_chunk`: see `ChunkedAllocator`
     /// - `min_block_size`: see `ChunkedAllocator`
-    /// - `max_chunk_size`: see `ChunkedAllocator`
+    /// - `max_chunk_size`: see `ChunkedAllocator`. Also the chunk size of the `TlsfAllocator`.
+    /// - `non_coherent_atom_size`: see `RootAllocator`. Pass `1` unless the memory type is
+    ///   `CPU_VISIBLE` and not `COHERENT`, to disable padding of sub-allocated blocks.
+    /// - `buffer_image_granularity`: `buffer_image_granularity` from device `Limits`. Pass `1`
+    ///   to place blocks of all kinds next to each other.
+    ///
+    /// ### Panics
+    ///
+    /// Panics if `buffer_image_granularity` is not a power of two.
     pub fn new(
         memory_type_id: MemoryTypeId,
         arena_chunk_size: u64,
         blocks_per_chunk: usize,
         min_block_size: u64,
         max_chunk_size: u64,
+        non_coherent_atom_size: u64,
+        buffer_image_granularity: u64,
     ) -> Self {
-        CombinedAllocator {
-            root: RootAllocator::new(memory_type_id),
-            root_used: 0,
-            arenas: ArenaAllocator::new(memory_type_id, arena_chunk_size),
-            chunks: ChunkedAllocator::new(
-                memory_type_id,
+        CombinedAllocator::with_config(
+            memory_type_id,
+            CombinedConfig::new(
+                arena_chunk_size,
                 blocks_per_chunk,
                 min_block_size,
                 max_chunk_size,
             ),
-            allocations: 0,
+            non_coherent_atom_size,
+            buffer_image_granularity,
+        )
+    }
+
+    /// Create a combined allocator from a configuration.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `memory_type_id`: ID of the memory type this allocator allocates from.
+    /// - `config`: sizes and enabled sub-allocators
+    /// - `non_coherent_atom_size`: see `CombinedAllocator::new`
+    /// - `buffer_image_granularity`: see `CombinedAllocator::new`
+    ///
+    /// ### Panics
+    ///
+    /// Panics if `buffer_image_granularity`, `config.min_block_size` or `config.max_chunk_size`
+    /// are not a power of two.
+    pub fn with_config(
+        memory_type_id: MemoryTypeId,
+        config: CombinedConfig,
+        non_coherent_atom_size: u64,
+        buffer_image_granularity: u64,
+    ) -> Self {
+        assert!(buffer_image_granularity.is_power_of_two());
+        CombinedAllocator {
+            memory_type: memory_type_id,
+            non_coherent_atom_size,
+            root: Mutex::new(RootAllocator::new(memory_type_id, non_coherent_atom_size)),
+            root_used: AtomicU64::new(0),
+            config,
+            buffer_image_granularity,
+            arenas: Mutex::new(ArenaAllocator::new(memory_type_id, config.arena_chunk_size)),
+            chunks: Mutex::new(ChunkedAllocator::new(
+                memory_type_id,
+                config.blocks_per_chunk,
+                config.min_block_size,
+                config.max_chunk_size,
+            )),
+            tlsf: Mutex::new(TlsfAllocator::new(memory_type_id, config.tlsf_chunk_size)),
+            buddy: Mutex::new(BuddyAllocator::new(
+                memory_type_id,
+                config.min_block_size,
+                config.max_chunk_size,
+            )),
+            allocations: AtomicUsize::new(0),
+            guards: Mutex::new(Guards::new()),
+            #[cfg(feature = "checks")]
+            live: Mutex::new(LiveBlocks::default()),
         }
     }
 
     /// Get memory type id
     pub fn memory_type(&self) -> MemoryTypeId {
-        self.root.memory_type()
+        self.memory_type
+    }
+
+    /// Get granularity of flush and invalidate operations blocks are padded to.
+    pub fn non_coherent_atom_size(&self) -> u64 {
+        self.non_coherent_atom_size
+    }
+
+    /// Count memory objects allocated from the device in `usage`. See `RootAllocator`.
+    pub(crate) fn set_heap_usage(&mut self, usage: Arc<HeapUsage>) {
+        self.root.get_mut().unwrap().set_heap_usage(usage);
+    }
+
+    /// Get the configuration the allocator was created with.
+    pub fn config(&self) -> &CombinedConfig {
+        &self.config
     }
 
     /// Get the total size of all blocks allocated by this allocator.
     pub fn used(&self) -> u64 {
-        self.root_used + self.arenas.used() + self.chunks.used()
+        self.dedicated() + self.sub_allocated()
     }
 
     /// Get the total size of all chunks allocated by this allocator.
     pub fn allocated(&self) -> u64 {
-        self.root_used + self.arenas.allocated() + self.chunks.allocated()
+        self.dedicated()
+            + self.arenas.lock().unwrap().allocated()
+            + self.chunks.lock().unwrap().allocated()
+            + self.tlsf.lock().unwrap().allocated()
+            + self.buddy.lock().unwrap().allocated()
     }
-}
 
-impl<B> MemoryAllocator<B> for CombinedAllocator<B>
-where
-    B: Backend,
-{
-    type Request = Type;
-    type Block = CombinedBlock<B::Memory>;
+    /// Collect detailed statistics of the allocator.
+    pub fn stats(&self) -> CombinedStats {
+        let arena = self.arenas.lock().unwrap().stats();
+        let chunked = self.chunks.lock().unwrap().stats();
+        let tlsf = self.tlsf.lock().unwrap().stats();
+        let buddy = self.buddy.lock().unwrap().stats();
+        CombinedStats {
+            memory_type: self.memory_type(),
+            device_objects: self.root.lock().unwrap().object_count(),
+            blocks: self.allocations.load(Ordering::Relaxed),
+            used: self.used(),
+            allocated: self.allocated(),
+            dedicated: self.dedicated(),
+            largest_free: max(
+                max(arena.largest_free, chunked.largest_free),
+                max(tlsf.largest_free, buddy.largest_free),
+            ),
+            arena,
+            chunked,
+            tlsf,
+            buddy,
+        }
+    }
 
-    unsafe fn alloc(
-        &mut self,
+    /// Dump state of the allocator, including every chunk and block in use.
+    #[cfg(feature = "dump")]
+    pub fn dump(&self) -> CombinedDump {
+        let mut arena = self.arenas.lock().unwrap().dump();
+        let mut chunked = self.chunks.lock().unwrap().dump();
+        let mut tlsf = self.tlsf.lock().unwrap().dump();
+        let mut buddy = self.buddy.lock().unwrap().dump();
+        let root = self.root.lock().unwrap();
+        // Sub-allocators identify memory objects by address, which differs from run to run.
+        let ids: HashMap<u64, u64> = root.objects().map(|(key, id, _)| (key, id)).collect();
+        let mut chunks = HashSet::new();
+        for chunk in arena
+            .nodes
+            .iter_mut()
+            .chain(
+                chunked
+                    .size_classes
+                    .iter_mut()
+                    .flat_map(|class| &mut class.chunks),
+            )
+            .chain(&mut tlsf.chunks)
+            .chain(&mut buddy.chunks)
+        {
+            chunk.memory = ids[&chunk.memory];
+            chunks.insert(chunk.memory);
+        }
+        let mut dedicated: Vec<_> = root
+            .objects()
+            .filter(|&(_, memory, _)| !chunks.contains(&memory))
+            .map(|(_, memory, size)| ChunkDump {
+                memory,
+                offset: 0,
+                size,
+                blocks: vec![BlockDump { offset: 0, size }],
+            })
+            .collect();
+        dedicated.sort_by_key(|chunk| chunk.memory);
+        CombinedDump {
+            dedicated,
+            arena,
+            chunked,
+            tlsf,
+            buddy,
+        }
+    }
+
+    /// Collect blocks in use with backtraces of their allocations, e.g. to find out why
+    /// `dispose` failed.
+    #[cfg(feature = "checks")]
+    pub fn live_blocks(&self) -> Vec<LiveBlock> {
+        self.live.lock().unwrap().collect()
+    }
+
+    /// Label a block in use, to be reported by `live_blocks`.
+    #[cfg(feature = "checks")]
+    pub fn set_label<L>(&self, block: &CombinedBlock<B::Memory>, label: L)
+    where
+        L: Into<String>,
+    {
+        self.live.lock().unwrap().set_label(block, label.into());
+    }
+
+    /// Get the minimal size of guard bands around sub-allocated blocks. Zero if disabled.
+    pub fn guard_size(&self) -> u64 {
+        self.guards.lock().unwrap().size()
+    }
+
+    /// Set the minimal size of guard bands around sub-allocated blocks. Zero disables them.
+    /// Only blocks allocated afterwards get guard bands.
+    ///
+    /// Bands are written through mapped pointers, so the memory type must be host-visible.
+    /// Bands are rounded up to the alignment of the block and the non-coherent atom size.
+    /// Dedicated blocks have no neighbours and never get guard bands.
+    pub fn set_guard_size(&mut self, size: u64) {
+        self.guards.get_mut().unwrap().set_size(size);
+    }
+
+    /// Check guard bands of all blocks in use, returning the blocks whose bands were overwritten.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the blocks
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the blocks were allocated with.
+    pub unsafe fn check_corruption(&self, device: &B::Device) -> Vec<CorruptedBlock> {
+        let memory_type = self.memory_type();
+        let guards = self.guards.lock().unwrap();
+        let mut root = self.root.lock().unwrap();
+        let mut corrupted: Vec<_> = guards
+            .blocks()
+            .filter_map(|guarded| match guarded.check(&mut root, device) {
+                (true, true) => None,
+                intact => Some(guarded.corrupted(memory_type, intact)),
+            })
+            .collect();
+        corrupted.sort_by_key(|block| (block.memory, block.offset));
+        corrupted
+    }
+
+    /// Check that the block can be freed by this allocator.
+    pub fn validate(&self, block: &CombinedBlock<B::Memory>) -> Result<(), BlockError> {
+        let guards = self.guards.lock().unwrap();
+        let raw = guards.get(block).map_or(&block.0, |guarded| &guarded.outer);
+        match block.1 {
+            CombinedTag::Arena(tag) => self.arenas.lock().unwrap().validate_raw(raw, tag),
+            CombinedTag::Chunked(tag) => self.chunks.lock().unwrap().validate_raw(raw, tag),
+            CombinedTag::Tlsf(tag) => self.tlsf.lock().unwrap().validate_raw(raw, tag),
+            CombinedTag::Buddy(tag) => self.buddy.lock().unwrap().validate_raw(raw, tag),
+            CombinedTag::Root => self.root.lock().unwrap().validate(raw),
+        }
+    }
+
+    /// Get the total size of all blocks allocated as dedicated memory objects.
+    pub fn dedicated(&self) -> u64 {
+        self.root_used.load(Ordering::Relaxed)
+    }
+
+    /// Get the total size of all blocks sub-allocated from bigger chunks.
+    pub fn sub_allocated(&self) -> u64 {
+        self.arenas.lock().unwrap().used()
+            + self.chunks.lock().unwrap().used()
+            + self.tlsf.lock().unwrap().used()
+            + self.buddy.lock().unwrap().used()
+    }
+
+    /// Set the number of empty chunks kept per block size by the `ChunkedAllocator`
+    /// and in total by the `TlsfAllocator` and the `BuddyAllocator`.
+    pub fn set_spare_chunks(&mut self, spare_chunks: usize) {
+        self.chunks
+            .get_mut()
+            .unwrap()
+            .set_spare_chunks(spare_chunks);
+        self.tlsf.get_mut().unwrap().set_spare_chunks(spare_chunks);
+        self.buddy.get_mut().unwrap().set_spare_chunks(spare_chunks);
+    }
+
+    /// Return all empty chunks of the sub-allocators to the device.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the chunks
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the chunks were allocated with.
+    pub unsafe fn trim(&self, device: &B::Device) {
+        let mut root = LockedRoot(&self.root);
+        self.chunks.lock().unwrap().trim(&mut root, device);
+        self.tlsf.lock().unwrap().trim(&mut root, device);
+        self.buddy.lock().unwrap().trim(&mut root, device);
+    }
+
+    /// Plan moves of blocks that compact the chunks of the `ChunkedAllocator`.
+    /// See `ChunkedAllocator::plan_defrag`.
+    ///
+    /// Only blocks of the `ChunkedAllocator` without guard bands are moved. In particular,
+    /// dedicated blocks are never moved into chunks: they were requested as dedicated or are
+    /// bigger than the dedicated threshold, and each one is freed with its own memory object,
+    /// so they don't fragment memory in the first place.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `blocks`: blocks that may be moved
+    /// - `budget`: limits of the work to plan, reduced by the planned moves
+    pub fn plan_defrag(
+        &self,
+        blocks: &[&CombinedBlock<B::Memory>],
+        budget: &mut DefragBudget,
+    ) -> Vec<DefragMove<CombinedBlock<B::Memory>>> {
+        let guards = self.guards.lock().unwrap();
+        let chunked = blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| guards.get(**block).is_none())
+            .filter_map(|(index, block)| match block.1 {
+                CombinedTag::Chunked(tag) => Some((index, block.size(), tag)),
+                _ => None,
+            });
+        let moves = self
+            .chunks
+            .lock()
+            .unwrap()
+            .plan_defrag_blocks(chunked, budget);
+        self.allocations.fetch_add(moves.len(), Ordering::Relaxed);
+        #[cfg(feature = "checks")]
+        for DefragMove { ref block, .. } in &moves {
+            self.live
+                .lock()
+                .unwrap()
+                .insert(self.memory_type(), block, Type::General);
+        }
+        moves
+            .into_iter()
+            .map(|DefragMove { index, block }| DefragMove {
+                index,
+                block: CombinedBlock(block.0, CombinedTag::Chunked(block.1)),
+            })
+            .collect()
+    }
+
+    /// Free the old blocks of the planned moves once their contents are copied, returning the
+    /// evacuated chunks to the device. See `ChunkedAllocator::commit_defrag`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the blocks
+    /// - `blocks`: old blocks of the planned moves
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the blocks were allocated with. Blocks must not be used by the
+    /// device anymore.
+    pub unsafe fn commit_defrag<I>(&self, device: &B::Device, blocks: I)
+    where
+        I: IntoIterator<Item = CombinedBlock<B::Memory>>,
+    {
+        for block in blocks {
+            self.free(device, block);
+        }
+        self.chunks
+            .lock()
+            .unwrap()
+            .commit_defrag(&mut LockedRoot(&self.root), device, None);
+    }
+
+    /// Abandon the planned moves, freeing their new blocks.
+    /// See `ChunkedAllocator::cancel_defrag`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the blocks
+    /// - `blocks`: new blocks of the planned moves
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the blocks were allocated with. Blocks must not be used by the
+    /// device anymore.
+    pub unsafe fn cancel_defrag<I>(&self, device: &B::Device, blocks: I)
+    where
+        I: IntoIterator<Item = CombinedBlock<B::Memory>>,
+    {
+        for block in blocks {
+            self.free(device, block);
+        }
+        self.chunks
+            .lock()
+            .unwrap()
+            .cancel_defrag(&mut LockedRoot(&self.root), device, None);
+    }
+
+    /// Allocate a block of memory. Takes `&self`, so it can be called from several threads.
+    /// See `MemoryAllocator::alloc`.
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one used for all other calls to this allocator.
+    pub unsafe fn alloc(
+        &self,
         device: &B::Device,
-        request: Type,
+        (request, kind): (Type, ResourceKind),
         reqs: Requirements,
     ) -> Result<CombinedBlock<B::Memory>, MemoryError> {
-        let block = match request {
+        // Linear blocks may share granularity pages with each other, but not with other kinds,
+        // which therefore get whole pages.
+        let pad = match kind {
+            ResourceKind::Linear => self.non_coherent_atom_size,
+            _ => max(self.non_coherent_atom_size, self.buffer_image_granularity),
+        };
+        let alignment = max(reqs.alignment, pad);
+        // Guard bands keep the block aligned and padded
+        let guard_size = self.guard_size();
+        let padded = shift_for_alignment(pad, reqs.size);
+        let front = shift_for_alignment(alignment, guard_size);
+        let back = shift_for_alignment(pad, guard_size);
+        let sub_reqs = Requirements {
+            size: front + padded + back,
+            alignment,
+            ..reqs
+        };
+        let dedicated = reqs.size + front + back > self.config.dedicated_threshold;
+        let mut root = LockedRoot(&self.root);
+        let block = match self.route(request) {
             Type::ShortLived => self
                 .arenas
-                .alloc(&mut self.root, device, (), reqs)
+                .lock()
+                .unwrap()
+                .alloc(&mut root, device, (), sub_reqs)
                 .map(|ArenaBlock(block, tag)| CombinedBlock(block, CombinedTag::Arena(tag)))?,
+            Type::Dedicated => self.alloc_dedicated(device, reqs)?,
             Type::General => {
-                if reqs.size > self.chunks.max_chunk_size() / 2 {
-                    let block = self
-                        .root
-                        .alloc(device, (), reqs)
-                        .map(|block| CombinedBlock(block, CombinedTag::Root))?;
-                    self.root_used += block.size();
-                    block
+                if dedicated {
+                    self.alloc_dedicated(device, reqs)?
+                } else if self.config.buddy {
+                    self.buddy
+                        .lock()
+                        .unwrap()
+                        .alloc(&mut root, device, (), sub_reqs)
+                        .map(|BuddyBlock(block, tag)| {
+                            CombinedBlock(block, CombinedTag::Buddy(tag))
+                        })?
                 } else {
-                    self.chunks.alloc(&mut self.root, device, (), reqs).map(
-                        |ChunkedBlock(block, tag)| CombinedBlock(block, CombinedTag::Chunked(tag)),
-                    )?
+                    self.chunks
+                        .lock()
+                        .unwrap()
+                        .alloc(&mut root, device, (), sub_reqs)
+                        .map(|ChunkedBlock(block, tag)| {
+                            CombinedBlock(block, CombinedTag::Chunked(tag))
+                        })?
                 }
             }
+            Type::MediumLived => {
+                if dedicated {
+                    self.alloc_dedicated(device, reqs)?
+                } else {
+                    self.tlsf
+                        .lock()
+                        .unwrap()
+                        .alloc(&mut root, device, (), sub_reqs)
+                        .map(|TlsfBlock(block, tag)| CombinedBlock(block, CombinedTag::Tlsf(tag)))?
+                }
+            }
+        };
+        let block = match block.1 {
+            CombinedTag::Root => block,
+            _ if guard_size == 0 => block,
+            tag => CombinedBlock(self.guard(device, block.0, front, padded), tag),
         };
-        self.allocations += 1;
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "checks")]
+        self.live
+            .lock()
+            .unwrap()
+            .insert(self.memory_type(), &block, request);
         Ok(block)
     }
 
-    unsafe fn free(&mut self, device: &B::Device, block: CombinedBlock<B::Memory>) {
+    /// Free a block of memory. Takes `&self`, so it can be called from several threads.
+    /// See `MemoryAllocator::free`.
+    ///
+    /// ### Safety
+    ///
+    /// The block must be allocated by this allocator using the same `device`, and must not be
+    /// used by the device anymore.
+    pub unsafe fn free(&self, device: &B::Device, block: CombinedBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        self.live.lock().unwrap().remove(&block);
+        let block = self.unguard(device, block);
+        let mut root = LockedRoot(&self.root);
         match block.1 {
             CombinedTag::Arena(tag) => {
                 self.arenas
-                    .free(&mut self.root, device, ArenaBlock(block.0, tag))
+                    .lock()
+                    .unwrap()
+                    .free(&mut root, device, ArenaBlock(block.0, tag))
             }
             CombinedTag::Chunked(tag) => {
                 self.chunks
-                    .free(&mut self.root, device, ChunkedBlock(block.0, tag))
+                    .lock()
+                    .unwrap()
+                    .free(&mut root, device, ChunkedBlock(block.0, tag))
+            }
+            CombinedTag::Tlsf(tag) => {
+                self.tlsf
+                    .lock()
+                    .unwrap()
+                    .free(&mut root, device, TlsfBlock(block.0, tag))
+            }
+            CombinedTag::Buddy(tag) => {
+                self.buddy
+                    .lock()
+                    .unwrap()
+                    .free(&mut root, device, BuddyBlock(block.0, tag))
             }
             CombinedTag::Root => {
-                self.root_used -= block.size();
-                self.root.free(device, block.0)
+                self.root_used.fetch_sub(block.size(), Ordering::Relaxed);
+                self.root.lock().unwrap().free(device, block.0)
             }
         }
-        self.allocations -= 1;
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Map a range of a block into host address space. See `MappingAllocator::map`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::map`.
+    pub unsafe fn map(
+        &self,
+        device: &B::Device,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let (raw, range) = self.sub_block(block, range);
+        let mut root = LockedRoot(&self.root);
+        let (raw, result) = match block.1 {
+            CombinedTag::Arena(tag) => {
+                let sub = ArenaBlock(raw, tag);
+                let result = self
+                    .arenas
+                    .lock()
+                    .unwrap()
+                    .map(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Chunked(tag) => {
+                let sub = ChunkedBlock(raw, tag);
+                let result = self
+                    .chunks
+                    .lock()
+                    .unwrap()
+                    .map(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Tlsf(tag) => {
+                let sub = TlsfBlock(raw, tag);
+                let result = self
+                    .tlsf
+                    .lock()
+                    .unwrap()
+                    .map(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Buddy(tag) => {
+                let sub = BuddyBlock(raw, tag);
+                let result = self
+                    .buddy
+                    .lock()
+                    .unwrap()
+                    .map(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Root => {
+                let result = root.map(device, &raw, range);
+                (raw, result)
+            }
+        };
+        raw.dispose();
+        result
+    }
+
+    /// Release a mapping acquired with `map`. See `MappingAllocator::unmap`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::unmap`.
+    pub unsafe fn unmap(&self, device: &B::Device, block: &CombinedBlock<B::Memory>) {
+        let (raw, _) = self.sub_block(block, 0..0);
+        let mut root = LockedRoot(&self.root);
+        let raw = match block.1 {
+            CombinedTag::Arena(tag) => {
+                let sub = ArenaBlock(raw, tag);
+                self.arenas.lock().unwrap().unmap(&mut root, device, &sub);
+                sub.0
+            }
+            CombinedTag::Chunked(tag) => {
+                let sub = ChunkedBlock(raw, tag);
+                self.chunks.lock().unwrap().unmap(&mut root, device, &sub);
+                sub.0
+            }
+            CombinedTag::Tlsf(tag) => {
+                let sub = TlsfBlock(raw, tag);
+                self.tlsf.lock().unwrap().unmap(&mut root, device, &sub);
+                sub.0
+            }
+            CombinedTag::Buddy(tag) => {
+                let sub = BuddyBlock(raw, tag);
+                self.buddy.lock().unwrap().unmap(&mut root, device, &sub);
+                sub.0
+            }
+            CombinedTag::Root => {
+                root.unmap(device, &raw);
+                raw
+            }
+        };
+        raw.dispose();
+    }
+
+    /// Flush a mapped range of a block. See `MappingAllocator::flush`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::flush`.
+    pub unsafe fn flush(
+        &self,
+        device: &B::Device,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let (raw, range) = self.sub_block(block, range);
+        let mut root = LockedRoot(&self.root);
+        let (raw, result) = match block.1 {
+            CombinedTag::Arena(tag) => {
+                let sub = ArenaBlock(raw, tag);
+                let result = self
+                    .arenas
+                    .lock()
+                    .unwrap()
+                    .flush(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Chunked(tag) => {
+                let sub = ChunkedBlock(raw, tag);
+                let result = self
+                    .chunks
+                    .lock()
+                    .unwrap()
+                    .flush(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Tlsf(tag) => {
+                let sub = TlsfBlock(raw, tag);
+                let result = self
+                    .tlsf
+                    .lock()
+                    .unwrap()
+                    .flush(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Buddy(tag) => {
+                let sub = BuddyBlock(raw, tag);
+                let result = self
+                    .buddy
+                    .lock()
+                    .unwrap()
+                    .flush(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Root => {
+                let result = root.flush(device, &raw, range);
+                (raw, result)
+            }
+        };
+        raw.dispose();
+        result
+    }
+
+    /// Invalidate a mapped range of a block. See `MappingAllocator::invalidate`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::invalidate`.
+    pub unsafe fn invalidate(
+        &self,
+        device: &B::Device,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let (raw, range) = self.sub_block(block, range);
+        let mut root = LockedRoot(&self.root);
+        let (raw, result) = match block.1 {
+            CombinedTag::Arena(tag) => {
+                let sub = ArenaBlock(raw, tag);
+                let result = self
+                    .arenas
+                    .lock()
+                    .unwrap()
+                    .invalidate(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Chunked(tag) => {
+                let sub = ChunkedBlock(raw, tag);
+                let result = self
+                    .chunks
+                    .lock()
+                    .unwrap()
+                    .invalidate(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Tlsf(tag) => {
+                let sub = TlsfBlock(raw, tag);
+                let result = self
+                    .tlsf
+                    .lock()
+                    .unwrap()
+                    .invalidate(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Buddy(tag) => {
+                let sub = BuddyBlock(raw, tag);
+                let result = self
+                    .buddy
+                    .lock()
+                    .unwrap()
+                    .invalidate(&mut root, device, &sub, range);
+                (sub.0, result)
+            }
+            CombinedTag::Root => {
+                let result = root.invalidate(device, &raw, range);
+                (raw, result)
+            }
+        };
+        raw.dispose();
+        result
+    }
+
+    /// Pick the sub-allocator for a request, falling back when the requested one is disabled.
+    fn route(&self, request: Type) -> Type {
+        match request {
+            Type::ShortLived if self.config.arena => Type::ShortLived,
+            Type::MediumLived if self.config.tlsf => Type::MediumLived,
+            Type::Dedicated => Type::Dedicated,
+            _ if self.config.chunked || self.config.buddy => Type::General,
+            _ if self.config.tlsf => Type::MediumLived,
+            _ => Type::Dedicated,
+        }
+    }
+
+    unsafe fn alloc_dedicated(
+        &self,
+        device: &B::Device,
+        reqs: Requirements,
+    ) -> Result<CombinedBlock<B::Memory>, MemoryError> {
+        let block = self
+            .root
+            .lock()
+            .unwrap()
+            .alloc(device, (), reqs)
+            .map(|block| CombinedBlock(block, CombinedTag::Root))?;
+        self.root_used.fetch_add(block.size(), Ordering::Relaxed);
+        Ok(block)
+    }
+
+    /// Carve the block to give to the user out of a sub-allocated block and fill the guard bands
+    /// around it.
+    unsafe fn guard(
+        &self,
+        device: &B::Device,
+        block: RawBlock<B::Memory>,
+        front: u64,
+        size: u64,
+    ) -> RawBlock<B::Memory> {
+        let start = block.range().start + front;
+        let guarded = GuardedBlock {
+            outer: block,
+            inner: start..start + size,
+        };
+        guarded.fill(&mut self.root.lock().unwrap(), device);
+        self.guards.lock().unwrap().insert(guarded)
+    }
+
+    /// Make a copy of the block as handed out by its sub-allocator, including guard bands, to
+    /// pass to the sub-allocator for mapping. `range` is made relative to the copy.
+    /// The copy must be disposed rather than freed.
+    fn sub_block(
+        &self,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> (RawBlock<B::Memory>, Range<u64>) {
+        let guards = self.guards.lock().unwrap();
+        let outer = guards.get(block).map_or(&block.0, |guarded| &guarded.outer);
+        let offset = block.range().start - outer.range().start;
+        (
+            RawBlock::new(outer.memory(), outer.range()),
+            offset + range.start..offset + range.end,
+        )
+    }
+
+    /// Check the guard bands of a block being freed and restore the sub-allocated block.
+    unsafe fn unguard(
+        &self,
+        device: &B::Device,
+        block: CombinedBlock<B::Memory>,
+    ) -> CombinedBlock<B::Memory> {
+        let guarded = self.guards.lock().unwrap().remove(&block);
+        match guarded {
+            Some(guarded) => {
+                match guarded.check(&mut self.root.lock().unwrap(), device) {
+                    (true, true) => {}
+                    intact => panic!("{}", guarded.corrupted(self.memory_type(), intact)),
+                }
+                block.0.dispose();
+                CombinedBlock(guarded.outer, block.1)
+            }
+            None => block,
+        }
+    }
+}
+
+impl<B> MemoryAllocator<B> for CombinedAllocator<B>
+where
+    B: Backend,
+{
+    type Request = (Type, ResourceKind);
+    type Block = CombinedBlock<B::Memory>;
+
+    unsafe fn alloc(
+        &mut self,
+        device: &B::Device,
+        request: (Type, ResourceKind),
+        reqs: Requirements,
+    ) -> Result<CombinedBlock<B::Memory>, MemoryError> {
+        CombinedAllocator::alloc(self, device, request, reqs)
+    }
+
+    unsafe fn free(&mut self, device: &B::Device, block: CombinedBlock<B::Memory>) {
+        CombinedAllocator::free(self, device, block)
     }
 
     fn is_used(&self) -> bool {
-        if self.allocations == 0 {
-            debug_assert!(!self.arenas.is_used() && !self.chunks.is_used());
+        if self.allocations.load(Ordering::Relaxed) == 0 {
+            debug_assert!(self.used() == 0);
             false
         } else {
             true
         }
     }
 
-    unsafe fn dispose(mut self, device: &B::Device) -> Result<(), Self> {
+    unsafe fn dispose(self, device: &B::Device) -> Result<(), Self> {
         if self.is_used() {
             return Err(self);
         }
-        self.arenas.dispose(&mut self.root, device).unwrap();
-        self.chunks.dispose(&mut self.root, device).unwrap();
-        self.root.dispose(device).unwrap();
+        let mut root = self.root.into_inner().unwrap();
+        self.arenas
+            .into_inner()
+            .unwrap()
+            .dispose(&mut root, device)
+            .unwrap();
+        self.chunks
+            .into_inner()
+            .unwrap()
+            .dispose(&mut root, device)
+            .unwrap();
+        self.tlsf
+            .into_inner()
+            .unwrap()
+            .dispose(&mut root, device)
+            .unwrap();
+        self.buddy
+            .into_inner()
+            .unwrap()
+            .dispose(&mut root, device)
+            .unwrap();
+        root.dispose(device).unwrap();
         Ok(())
     }
 }
 
+impl<B> MappingAllocator<B> for CombinedAllocator<B>
+where
+    B: Backend,
+{
+    unsafe fn map(
+        &mut self,
+        device: &B::Device,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        CombinedAllocator::map(self, device, block, range)
+    }
+
+    unsafe fn unmap(&mut self, device: &B::Device, block: &CombinedBlock<B::Memory>) {
+        CombinedAllocator::unmap(self, device, block)
+    }
+
+    unsafe fn flush(
+        &mut self,
+        device: &B::Device,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        CombinedAllocator::flush(self, device, block, range)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        device: &B::Device,
+        block: &CombinedBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        CombinedAllocator::invalidate(self, device, block, range)
+    }
+}
+
 /// `Block` type returned by `CombinedAllocator`.
 #[derive(Debug)]
 pub struct CombinedBlock<M>(pub(crate) RawBlock<M>, pub(crate) CombinedTag);
@@ -174,6 +1048,8 @@ pub struct CombinedBlock<M>(pub(crate) RawBlock<M>, pub(crate) CombinedTag);
 pub(crate) enum CombinedTag {
     Arena(u64),
     Chunked(usize),
+    Tlsf(usize),
+    Buddy(usize),
     Root,
 }
 
@@ -202,3 +1078,150 @@ fn test_send_sync() {
         foo::<CombinedAllocator<B>>()
     }
 }
+
+#[test]
+fn test_buffer_image_granularity() {
+    use mock::{MockBackend, MockDevice};
+    use std::ptr;
+
+    let device = MockDevice::default();
+    let granularity = device.limits().buffer_image_granularity;
+    let allocator = CombinedAllocator::<MockBackend>::new(
+        MemoryTypeId(0),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        1,
+        granularity,
+    );
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 256,
+        type_mask: !0,
+    };
+    let page = |range: Range<u64>| range.start / granularity..(range.end - 1) / granularity + 1;
+    unsafe {
+        let mut blocks = Vec::new();
+        for &kind in &[
+            ResourceKind::Linear,
+            ResourceKind::Optimal,
+            ResourceKind::Linear,
+            ResourceKind::Unknown,
+            ResourceKind::Linear,
+        ] {
+            let block = allocator
+                .alloc(&device, (Type::General, kind), reqs)
+                .unwrap();
+            blocks.push((kind, block));
+        }
+        for &(kind, ref block) in &blocks {
+            if kind == ResourceKind::Linear {
+                continue;
+            }
+            assert_eq!(block.range().start % granularity, 0);
+            for (_, other) in &blocks {
+                if ptr::eq(other.memory(), block.memory()) && other.range() != block.range() {
+                    let (pages, other) = (page(block.range()), page(other.range()));
+                    assert!(pages.end <= other.start || other.end <= pages.start);
+                }
+            }
+        }
+        for (_, block) in blocks {
+            allocator.free(&device, block);
+        }
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_mapping() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let mut allocator = CombinedAllocator::<MockBackend>::new(
+        MemoryTypeId(2),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        1,
+    );
+    allocator.set_guard_size(16);
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 4,
+        type_mask: !0,
+    };
+    unsafe {
+        for &ty in &[
+            Type::ShortLived,
+            Type::General,
+            Type::MediumLived,
+            Type::Dedicated,
+        ] {
+            let block = allocator
+                .alloc(&device, (ty, ResourceKind::Linear), reqs)
+                .unwrap();
+            let ptr = allocator.map(&device, &block, 0..1000).unwrap();
+            *ptr.add(999) = 42;
+            allocator.flush(&device, &block, 0..1000).unwrap();
+            allocator.invalidate(&device, &block, 999..1000).unwrap();
+            assert_eq!(*ptr.add(999), 42);
+            let size = block.size();
+            assert!(allocator.map(&device, &block, 0..size + 1).is_err());
+            allocator.unmap(&device, &block);
+            assert!(!allocator.root.lock().unwrap().is_mapped(&block));
+            allocator.free(&device, block);
+        }
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_defrag() {
+    use mock::{MockBackend, MockDevice};
+    use std::iter::once;
+
+    let device = MockDevice::default();
+    let allocator =
+        CombinedAllocator::<MockBackend>::new(MemoryTypeId(0), 1 << 16, 64, 256, 1 << 20, 1, 1);
+    let reqs = Requirements {
+        size: 256,
+        alignment: 1,
+        type_mask: !0,
+    };
+    unsafe {
+        // Fill a chunk and put one block into a second one.
+        let mut blocks: Vec<_> = (0..65)
+            .map(|_| {
+                allocator
+                    .alloc(&device, (Type::General, ResourceKind::Linear), reqs)
+                    .unwrap()
+            })
+            .collect();
+        let dedicated = allocator
+            .alloc(&device, (Type::Dedicated, ResourceKind::Linear), reqs)
+            .unwrap();
+        let first = blocks.remove(0);
+        allocator.free(&device, first);
+
+        let moves = {
+            let candidates: Vec<_> = blocks.iter().chain(once(&dedicated)).collect();
+            allocator.plan_defrag(&candidates, &mut DefragBudget::unlimited())
+        };
+        // Only the block of the second chunk moves, the dedicated block stays.
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].index, 63);
+        let old = blocks.pop().unwrap();
+        blocks.extend(moves.into_iter().map(|DefragMove { block, .. }| block));
+        allocator.commit_defrag(&device, once(old));
+        assert_eq!(allocator.stats().chunked.chunks, 1);
+
+        for block in blocks.into_iter().chain(once(dedicated)) {
+            allocator.free(&device, block);
+        }
+        allocator.dispose(&device).unwrap();
+    }
+}
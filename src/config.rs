@@ -0,0 +1,739 @@
+use gfx_hal::MemoryProperties;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use smart::Budget;
+
+/// Configuration of a `CombinedAllocator`.
+///
+/// Disabled sub-allocators never allocate chunks. Requests for them are served by the
+/// `BuddyAllocator` or the `ChunkedAllocator`, or the `TlsfAllocator` if those are disabled too,
+/// or as dedicated memory objects if all are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CombinedConfig {
+    /// Whether the `ArenaAllocator` serves `Type::ShortLived` requests.
+    pub arena: bool,
+
+    /// Whether the `ChunkedAllocator` serves `Type::General` requests.
+    pub chunked: bool,
+
+    /// Whether the `TlsfAllocator` serves `Type::MediumLived` requests.
+    pub tlsf: bool,
+
+    /// Whether the `BuddyAllocator` serves `Type::General` requests instead of the
+    /// `ChunkedAllocator`. It splits chunks of `max_chunk_size` bytes into blocks no smaller than
+    /// `min_block_size`.
+    pub buddy: bool,
+
+    /// Size of the chunks of the `ArenaAllocator`.
+    pub arena_chunk_size: u64,
+
+    /// See `ChunkedAllocator`.
+    pub blocks_per_chunk: usize,
+
+    /// See `ChunkedAllocator`. Also the minimum block size of the `BuddyAllocator`.
+    /// Must be a power of two.
+    pub min_block_size: u64,
+
+    /// See `ChunkedAllocator`. Also the chunk size of the `BuddyAllocator`.
+    /// Must be a power of two.
+    pub max_chunk_size: u64,
+
+    /// Size of the chunks of the `TlsfAllocator`.
+    pub tlsf_chunk_size: u64,
+
+    /// Blocks bigger than this are allocated as dedicated memory objects instead of from the
+    /// `ChunkedAllocator`, the `TlsfAllocator` or the `BuddyAllocator`.
+    pub dedicated_threshold: u64,
+}
+
+impl CombinedConfig {
+    /// Create a configuration with all sub-allocators but the `BuddyAllocator` enabled.
+    /// The `TlsfAllocator` uses chunks of `max_chunk_size` bytes, and blocks bigger than half of
+    /// `max_chunk_size` are dedicated.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `arena_chunk_size`: see `ArenaAllocator`
+    /// - `blocks_per_chunk`: see `ChunkedAllocator`
+    /// - `min_block_size`: see `ChunkedAllocator`
+    /// - `max_chunk_size`: see `ChunkedAllocator`
+    pub fn new(
+        arena_chunk_size: u64,
+        blocks_per_chunk: usize,
+        min_block_size: u64,
+        max_chunk_size: u64,
+    ) -> Self {
+        CombinedConfig {
+            arena: true,
+            chunked: true,
+            tlsf: true,
+            buddy: false,
+            arena_chunk_size,
+            blocks_per_chunk,
+            min_block_size,
+            max_chunk_size,
+            tlsf_chunk_size: max_chunk_size,
+            dedicated_threshold: max_chunk_size / 2,
+        }
+    }
+
+    /// Derive a configuration from the size of the heap the memory type belongs to.
+    ///
+    /// Chunks are a sixteenth of the heap rounded down to a power of two, between 1 MiB and
+    /// 64 MiB, so small heaps are not exhausted by a few partially used chunks.
+    pub fn for_heap(heap_size: u64) -> Self {
+        let max_chunk_size = floor_power_of_two(heap_size / 16).clamp(1 << 20, 64 << 20);
+        CombinedConfig::new(max_chunk_size / 4, 64, 256, max_chunk_size)
+    }
+
+    /// Enable or disable the `ArenaAllocator`.
+    pub fn with_arena(mut self, enabled: bool) -> Self {
+        self.arena = enabled;
+        self
+    }
+
+    /// Enable or disable the `ChunkedAllocator`.
+    pub fn with_chunked(mut self, enabled: bool) -> Self {
+        self.chunked = enabled;
+        self
+    }
+
+    /// Enable or disable the `TlsfAllocator`.
+    pub fn with_tlsf(mut self, enabled: bool) -> Self {
+        self.tlsf = enabled;
+        self
+    }
+
+    /// Enable or disable the `BuddyAllocator`.
+    pub fn with_buddy(mut self, enabled: bool) -> Self {
+        self.buddy = enabled;
+        self
+    }
+
+    /// Set the size of the chunks of the `TlsfAllocator`.
+    pub fn with_tlsf_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.tlsf_chunk_size = chunk_size;
+        self
+    }
+
+    /// Set the size above which blocks are allocated as dedicated memory objects.
+    pub fn with_dedicated_threshold(mut self, threshold: u64) -> Self {
+        self.dedicated_threshold = threshold;
+        self
+    }
+
+    /// Check the configuration of a memory type.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `memory_type`: index of the memory type, to be reported in errors
+    pub fn validate(&self, memory_type: usize) -> Result<(), ConfigError> {
+        let zero = |field, value| {
+            if value == 0 {
+                Err(ConfigError::Zero { memory_type, field })
+            } else {
+                Ok(())
+            }
+        };
+        zero("arena_chunk_size", self.arena_chunk_size)?;
+        zero("blocks_per_chunk", self.blocks_per_chunk as u64)?;
+        zero("tlsf_chunk_size", self.tlsf_chunk_size)?;
+        for &(field, value) in &[
+            ("min_block_size", self.min_block_size),
+            ("max_chunk_size", self.max_chunk_size),
+        ] {
+            if !value.is_power_of_two() {
+                return Err(ConfigError::NotPowerOfTwo {
+                    memory_type,
+                    field,
+                    value,
+                });
+            }
+        }
+        if self.min_block_size > self.max_chunk_size {
+            return Err(ConfigError::BlockBiggerThanChunk(memory_type));
+        }
+        if ((self.chunked || self.buddy) && self.dedicated_threshold > self.max_chunk_size)
+            || (self.tlsf && self.dedicated_threshold > self.tlsf_chunk_size)
+        {
+            return Err(ConfigError::ThresholdTooLarge(memory_type));
+        }
+        Ok(())
+    }
+}
+
+/// Configuration of a `SmartAllocator`, holding a `CombinedConfig` for each memory type, and the
+/// budgets of heaps.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmartConfig {
+    memory_types: Vec<(usize, CombinedConfig)>,
+    heap_budgets: Vec<Option<Budget>>,
+    dedicated_threshold: Option<u64>,
+    spill: bool,
+}
+
+impl SmartConfig {
+    /// Create a configuration with defaults derived from heap sizes by
+    /// `CombinedConfig::for_heap`.
+    pub fn new(memory_properties: &MemoryProperties) -> Self {
+        let mut config = SmartConfig::uniform(memory_properties, CombinedConfig::for_heap(0));
+        for &mut (heap_index, ref mut memory_type) in &mut config.memory_types {
+            *memory_type = CombinedConfig::for_heap(memory_properties.memory_heaps[heap_index]);
+        }
+        config
+    }
+
+    /// Create a configuration using the same `CombinedConfig` for all memory types.
+    pub fn uniform(memory_properties: &MemoryProperties, config: CombinedConfig) -> Self {
+        SmartConfig {
+            memory_types: memory_properties
+                .memory_types
+                .iter()
+                .map(|memory_type| (memory_type.heap_index, config))
+                .collect(),
+            heap_budgets: vec![None; memory_properties.memory_heaps.len()],
+            dedicated_threshold: None,
+            spill: false,
+        }
+    }
+
+    /// Use `config` for all memory types of a heap.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `heap_index`: index of the heap in `MemoryProperties::memory_heaps`
+    pub fn with_heap(mut self, heap_index: usize, config: CombinedConfig) -> Self {
+        for &mut (heap, ref mut memory_type) in &mut self.memory_types {
+            if heap == heap_index {
+                *memory_type = config;
+            }
+        }
+        self
+    }
+
+    /// Use `config` for a memory type.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `index`: index of the memory type in `MemoryProperties::memory_types`
+    pub fn with_memory_type(mut self, index: usize, config: CombinedConfig) -> Self {
+        self.memory_types[index].1 = config;
+        self
+    }
+
+    /// Set the budget of a heap. See `SmartAllocator::set_heap_budget`.
+    pub fn with_heap_budget(mut self, heap_index: usize, budget: Option<Budget>) -> Self {
+        self.heap_budgets[heap_index] = budget;
+        self
+    }
+
+    /// Set the size above which allocations are promoted to dedicated memory objects.
+    /// See `SmartAllocator::set_dedicated_threshold`.
+    pub fn with_dedicated_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.dedicated_threshold = threshold;
+        self
+    }
+
+    /// Allow allocations to spill out of device-local memory. See `SmartAllocator::set_spill`.
+    pub fn with_spill(mut self, spill: bool) -> Self {
+        self.spill = spill;
+        self
+    }
+
+    /// Apply a profile over this configuration, e.g. one loaded from a file.
+    /// Fails if the profile refers to heaps or memory types that don't exist, or if the
+    /// resulting configuration is invalid.
+    pub fn with_profile(mut self, profile: &Profile) -> Result<Self, ConfigError> {
+        for heap in &profile.heaps {
+            if heap.index >= self.heap_budgets.len() {
+                return Err(ConfigError::UnknownHeap(heap.index));
+            }
+        }
+        for memory_type in &profile.memory_types {
+            if memory_type.index >= self.memory_types.len() {
+                return Err(ConfigError::UnknownMemoryType(memory_type.index));
+            }
+        }
+        for &mut (heap_index, ref mut config) in &mut self.memory_types {
+            profile.config.apply(config);
+            for heap in profile.heaps.iter().filter(|heap| heap.index == heap_index) {
+                heap.config.apply(config);
+            }
+        }
+        for memory_type in &profile.memory_types {
+            memory_type
+                .config
+                .apply(&mut self.memory_types[memory_type.index].1);
+        }
+        for heap in &profile.heaps {
+            if heap.budget.is_some() {
+                self.heap_budgets[heap.index] = heap.budget;
+            }
+        }
+        if profile.dedicated_threshold.is_some() {
+            self.dedicated_threshold = profile.dedicated_threshold;
+        }
+        if let Some(spill) = profile.spill {
+            self.spill = spill;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Get the configuration of a memory type.
+    pub fn memory_type(&self, index: usize) -> &CombinedConfig {
+        &self.memory_types[index].1
+    }
+
+    /// Get the configuration of a memory type for modification.
+    pub fn memory_type_mut(&mut self, index: usize) -> &mut CombinedConfig {
+        &mut self.memory_types[index].1
+    }
+
+    /// Get the budget of a heap.
+    pub fn heap_budget(&self, heap_index: usize) -> Option<Budget> {
+        self.heap_budgets[heap_index]
+    }
+
+    /// Get the size above which allocations are promoted to dedicated memory objects.
+    pub fn dedicated_threshold(&self) -> Option<u64> {
+        self.dedicated_threshold
+    }
+
+    /// Check if allocations may spill out of device-local memory.
+    pub fn spill(&self) -> bool {
+        self.spill
+    }
+
+    /// Check the configuration of all memory types and heaps.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (index, (_, config)) in self.memory_types.iter().enumerate() {
+            config.validate(index)?;
+        }
+        for (heap, budget) in self.heap_budgets.iter().enumerate() {
+            if let Some(Budget::Fraction(fraction)) = *budget {
+                if !(0.0..=1.0).contains(&fraction) {
+                    return Err(ConfigError::InvalidBudget { heap, fraction });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Overrides of `CombinedConfig` fields. Fields that are `None` are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct CombinedProfile {
+    /// See `CombinedConfig::arena`.
+    pub arena: Option<bool>,
+
+    /// See `CombinedConfig::chunked`.
+    pub chunked: Option<bool>,
+
+    /// See `CombinedConfig::tlsf`.
+    pub tlsf: Option<bool>,
+
+    /// See `CombinedConfig::buddy`.
+    pub buddy: Option<bool>,
+
+    /// See `CombinedConfig::arena_chunk_size`.
+    pub arena_chunk_size: Option<u64>,
+
+    /// See `CombinedConfig::blocks_per_chunk`.
+    pub blocks_per_chunk: Option<usize>,
+
+    /// See `CombinedConfig::min_block_size`.
+    pub min_block_size: Option<u64>,
+
+    /// See `CombinedConfig::max_chunk_size`.
+    pub max_chunk_size: Option<u64>,
+
+    /// See `CombinedConfig::tlsf_chunk_size`.
+    pub tlsf_chunk_size: Option<u64>,
+
+    /// See `CombinedConfig::dedicated_threshold`.
+    pub dedicated_threshold: Option<u64>,
+}
+
+impl CombinedProfile {
+    /// Override fields of `config` that are set in the profile.
+    pub fn apply(&self, config: &mut CombinedConfig) {
+        fn set<T: Copy>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        set(&mut config.arena, self.arena);
+        set(&mut config.chunked, self.chunked);
+        set(&mut config.tlsf, self.tlsf);
+        set(&mut config.buddy, self.buddy);
+        set(&mut config.arena_chunk_size, self.arena_chunk_size);
+        set(&mut config.blocks_per_chunk, self.blocks_per_chunk);
+        set(&mut config.min_block_size, self.min_block_size);
+        set(&mut config.max_chunk_size, self.max_chunk_size);
+        set(&mut config.tlsf_chunk_size, self.tlsf_chunk_size);
+        set(&mut config.dedicated_threshold, self.dedicated_threshold);
+    }
+}
+
+/// Overrides for a heap and its memory types.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(deny_unknown_fields)
+)]
+pub struct HeapProfile {
+    /// Index of the heap in `MemoryProperties::memory_heaps`. Required.
+    pub index: usize,
+
+    /// Budget of the heap.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub budget: Option<Budget>,
+
+    /// Overrides for all memory types of the heap.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: CombinedProfile,
+}
+
+/// Overrides for a memory type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(deny_unknown_fields)
+)]
+pub struct MemoryTypeProfile {
+    /// Index of the memory type in `MemoryProperties::memory_types`. Required.
+    pub index: usize,
+
+    /// Overrides for the memory type.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub config: CombinedProfile,
+}
+
+/// Tuning of a `SmartAllocator` applied over a `SmartConfig` by `SmartConfig::with_profile`,
+/// such as a set of sizes for a class of devices.
+///
+/// With the `serde` feature it can be loaded from any format supported by serde, for example
+/// from TOML:
+///
+/// ```toml
+/// spill = true
+///
+/// [config]
+/// max_chunk_size = 16777216
+/// dedicated_threshold = 8388608
+///
+/// [[heaps]]
+/// index = 0
+/// budget = { Fraction = 0.8 }
+/// config = { tlsf = false }
+///
+/// [[memory_types]]
+/// index = 2
+/// config = { dedicated_threshold = 65536 }
+/// ```
+///
+/// Overrides for all memory types are applied first, then for heaps, then for memory types.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct Profile {
+    /// Overrides for all memory types.
+    pub config: CombinedProfile,
+
+    /// Overrides for heaps.
+    pub heaps: Vec<HeapProfile>,
+
+    /// Overrides for memory types.
+    pub memory_types: Vec<MemoryTypeProfile>,
+
+    /// See `SmartConfig::with_dedicated_threshold`.
+    pub dedicated_threshold: Option<u64>,
+
+    /// See `SmartConfig::with_spill`.
+    pub spill: Option<bool>,
+}
+
+/// Invalid configuration found by `SmartConfig::validate`.
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum ConfigError {
+    /// Size that must be a power of two isn't.
+    #[fail(
+        display = "{} of memory type {} is {}, which is not a power of two",
+        field, memory_type, value
+    )]
+    NotPowerOfTwo {
+        /// Index of the memory type.
+        memory_type: usize,
+        /// Name of the field of `CombinedConfig`.
+        field: &'static str,
+        /// Value of the field.
+        value: u64,
+    },
+
+    /// Size or count is zero.
+    #[fail(display = "{} of memory type {} is zero", field, memory_type)]
+    Zero {
+        /// Index of the memory type.
+        memory_type: usize,
+        /// Name of the field of `CombinedConfig`.
+        field: &'static str,
+    },
+
+    /// Minimal block size of the `ChunkedAllocator` is bigger than its maximal chunk size.
+    #[fail(
+        display = "min_block_size of memory type {} is bigger than max_chunk_size",
+        _0
+    )]
+    BlockBiggerThanChunk(usize),
+
+    /// Dedicated threshold is bigger than the chunks of an enabled sub-allocator, so blocks
+    /// between the two sizes could not be allocated.
+    #[fail(
+        display = "dedicated_threshold of memory type {} is bigger than the chunk size",
+        _0
+    )]
+    ThresholdTooLarge(usize),
+
+    /// Budget fraction outside of `0.0..=1.0`.
+    #[fail(
+        display = "Budget fraction {} of heap {} is not between 0 and 1",
+        fraction, heap
+    )]
+    InvalidBudget {
+        /// Index of the heap.
+        heap: usize,
+        /// Fraction of the budget.
+        fraction: f32,
+    },
+
+    /// Profile refers to a heap that doesn't exist.
+    #[fail(display = "No heap {}", _0)]
+    UnknownHeap(usize),
+
+    /// Profile refers to a memory type that doesn't exist.
+    #[fail(display = "No memory type {}", _0)]
+    UnknownMemoryType(usize),
+}
+
+/// Round down to a power of two, or zero.
+fn floor_power_of_two(value: u64) -> u64 {
+    if value == 0 {
+        0
+    } else {
+        1 << (63 - value.leading_zeros())
+    }
+}
+
+#[test]
+fn test_smart_config() {
+    use combined::{ResourceKind, Type};
+    use gfx_hal::memory::{Properties, Requirements};
+    use mock::{MockBackend, MockDevice};
+    use smart::SmartAllocator;
+    use MemoryAllocator;
+
+    let device = MockDevice::default();
+    let memory_properties = device.memory_properties();
+    let config = SmartConfig::new(&memory_properties);
+    assert_eq!(config.memory_type(0).max_chunk_size, 64 << 20);
+    assert_eq!(config.memory_type(1).max_chunk_size, 16 << 20);
+
+    // No sub-allocators for the host-visible heap, and a lower threshold for device-local memory.
+    let config = config
+        .with_heap(
+            1,
+            CombinedConfig::for_heap(256 << 20)
+                .with_chunked(false)
+                .with_tlsf(false),
+        )
+        .with_memory_type(
+            0,
+            CombinedConfig::for_heap(1 << 30).with_dedicated_threshold(1 << 12),
+        );
+    // Invalid configurations are reported instead of panicking.
+    let invalid = config
+        .clone()
+        .with_heap_budget(0, Some(Budget::Fraction(2.0)));
+    assert_eq!(
+        SmartAllocator::<MockBackend>::try_with_config(
+            memory_properties.clone(),
+            &invalid,
+            device.non_coherent_atom_size(),
+            device.limits().buffer_image_granularity,
+        )
+        .err(),
+        Some(ConfigError::InvalidBudget {
+            heap: 0,
+            fraction: 2.0,
+        })
+    );
+
+    let allocator = SmartAllocator::<MockBackend>::with_config(
+        memory_properties,
+        &config,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = |size| Requirements {
+        size,
+        alignment: 256,
+        type_mask: !0,
+    };
+    unsafe {
+        let small = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(1 << 10),
+            )
+            .unwrap();
+        let big = allocator
+            .alloc(
+                &device,
+                (
+                    Type::MediumLived,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(1 << 16),
+            )
+            .unwrap();
+        let staging = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::CPU_VISIBLE.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(1 << 10),
+            )
+            .unwrap();
+        let stats = allocator.stats();
+        assert_eq!(stats.memory_types[0].allocator.chunked.blocks, 1);
+        assert_eq!(stats.memory_types[0].allocator.dedicated, 1 << 16);
+        assert_eq!(stats.memory_types[1].allocator.dedicated, 1 << 10);
+
+        allocator.free(&device, small);
+        allocator.free(&device, big);
+        allocator.free(&device, staging);
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_profile() {
+    use mock::MockDevice;
+
+    let memory_properties = MockDevice::default().memory_properties();
+    let profile = Profile {
+        config: CombinedProfile {
+            max_chunk_size: Some(16 << 20),
+            dedicated_threshold: Some(8 << 20),
+            ..CombinedProfile::default()
+        },
+        heaps: vec![HeapProfile {
+            index: 1,
+            budget: Some(Budget::Fraction(0.5)),
+            config: CombinedProfile {
+                tlsf: Some(false),
+                ..CombinedProfile::default()
+            },
+        }],
+        memory_types: vec![MemoryTypeProfile {
+            index: 2,
+            config: CombinedProfile {
+                dedicated_threshold: Some(1 << 16),
+                ..CombinedProfile::default()
+            },
+        }],
+        dedicated_threshold: None,
+        spill: Some(true),
+    };
+    let config = SmartConfig::new(&memory_properties)
+        .with_profile(&profile)
+        .unwrap();
+    assert_eq!(config.memory_type(0).max_chunk_size, 16 << 20);
+    assert!(config.memory_type(0).tlsf && !config.memory_type(1).tlsf);
+    assert_eq!(config.memory_type(1).dedicated_threshold, 8 << 20);
+    assert_eq!(config.memory_type(2).dedicated_threshold, 1 << 16);
+    assert_eq!(config.heap_budget(1), Some(Budget::Fraction(0.5)));
+    assert!(config.spill());
+
+    let invalid = |profile: Profile| {
+        SmartConfig::new(&memory_properties)
+            .with_profile(&profile)
+            .unwrap_err()
+    };
+    assert_eq!(
+        invalid(Profile {
+            memory_types: vec![MemoryTypeProfile {
+                index: 1,
+                config: CombinedProfile {
+                    min_block_size: Some(300),
+                    ..CombinedProfile::default()
+                },
+            }],
+            ..Profile::default()
+        }),
+        ConfigError::NotPowerOfTwo {
+            memory_type: 1,
+            field: "min_block_size",
+            value: 300,
+        }
+    );
+    assert_eq!(
+        invalid(Profile {
+            heaps: vec![HeapProfile {
+                index: 2,
+                ..HeapProfile::default()
+            }],
+            ..Profile::default()
+        }),
+        ConfigError::UnknownHeap(2)
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_deserialize_profile() {
+    use serde_json;
+
+    let profile: Profile = serde_json::from_str(
+        r#"{
+            "config": { "max_chunk_size": 16777216, "arena": false },
+            "heaps": [{ "index": 0, "budget": { "Bytes": 1048576 } }],
+            "spill": true
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(profile.config.max_chunk_size, Some(16 << 20));
+    assert_eq!(profile.config.arena, Some(false));
+    assert_eq!(profile.heaps[0].budget, Some(Budget::Bytes(1 << 20)));
+    assert!(profile.memory_types.is_empty());
+
+    // Overrides must say which heap or memory type they apply to.
+    assert!(serde_json::from_str::<Profile>(r#"{ "heaps": [{ "budget": null }] }"#).is_err());
+    assert!(serde_json::from_str::<Profile>(r#"{ "memory_types": [{ "config": {} }] }"#).is_err());
+    let profile: Profile = serde_json::from_str(r#"{ "memory_types": [{ "index": 2 }] }"#).unwrap();
+    assert_eq!(profile.memory_types[0].config, CombinedProfile::default());
+
+    // Misspelled fields are rejected rather than ignored.
+    assert!(serde_json::from_str::<Profile>(r#"{ "config": { "max_chunk": 1 } }"#).is_err());
+}
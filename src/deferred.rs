@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::mem::replace;
+
+use gfx_hal::buffer::Usage as BufferUsage;
+use gfx_hal::format::Format;
+use gfx_hal::image::{Kind, Level, Tiling, Usage as ImageUsage, ViewCapabilities};
+use gfx_hal::memory::Requirements;
+use gfx_hal::Backend;
+
+use factory::{Factory, FactoryError, Item};
+use {MemoryAllocator, MemoryError};
+
+/// Block or resource waiting for the device to be done with it.
+#[derive(Debug)]
+enum Retired<B: Backend, T> {
+    Block(T),
+    Buffer(Item<B::Buffer, T>),
+    Image(Item<B::Image, T>),
+}
+
+/// Layer over a `MemoryAllocator` that defers freeing of blocks until the device is done with
+/// them.
+///
+/// Blocks, buffers and images are queued with the epoch (e.g. frame index) they were last used
+/// during and actually freed by `cleanup` once that epoch is completed, usually after waiting on
+/// the fence of the frame. Allocation is passed directly to the inner allocator.
+///
+/// Pending blocks must be freed with `cleanup` or `into_inner` before the layer is dropped.
+///
+/// ### Type parameters:
+///
+/// - `B`: hal `Backend`
+/// - `A`: inner allocator
+#[derive(Debug)]
+pub struct Deferred<B, A>
+where
+    B: Backend,
+    A: MemoryAllocator<B>,
+{
+    allocator: A,
+    pending: BTreeMap<u64, Vec<Retired<B, A::Block>>>,
+}
+
+impl<B, A> Deferred<B, A>
+where
+    B: Backend,
+    A: MemoryAllocator<B>,
+{
+    /// Wrap an allocator.
+    pub fn new(allocator: A) -> Self {
+        Deferred {
+            allocator,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Get the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.allocator
+    }
+
+    /// Get the inner allocator mutably, e.g. to map blocks.
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.allocator
+    }
+
+    /// Get the number of blocks, buffers and images waiting to be freed.
+    pub fn pending(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Allocate a block of memory from the inner allocator.
+    /// See `MemoryAllocator::alloc`.
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the inner allocator allocates from.
+    pub unsafe fn alloc(
+        &mut self,
+        device: &B::Device,
+        request: A::Request,
+        reqs: Requirements,
+    ) -> Result<A::Block, MemoryError> {
+        self.allocator.alloc(device, request, reqs)
+    }
+
+    /// Queue the block to be freed once `epoch` is completed.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `block`: block allocated by the inner allocator
+    /// - `epoch`: last epoch the device may use the block during
+    pub fn free(&mut self, block: A::Block, epoch: u64) {
+        self.retire(Retired::Block(block), epoch);
+    }
+
+    /// Create a buffer with memory from the inner allocator.
+    /// See `Factory::create_buffer`.
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the inner allocator allocates from.
+    pub unsafe fn create_buffer(
+        &mut self,
+        device: &B::Device,
+        request: A::Request,
+        size: u64,
+        usage: BufferUsage,
+    ) -> Result<Item<B::Buffer, A::Block>, FactoryError> {
+        self.allocator.create_buffer(device, request, size, usage)
+    }
+
+    /// Create an image with memory from the inner allocator.
+    /// See `Factory::create_image`.
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the inner allocator allocates from.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn create_image(
+        &mut self,
+        device: &B::Device,
+        request: A::Request,
+        kind: Kind,
+        level: Level,
+        format: Format,
+        tiling: Tiling,
+        usage: ImageUsage,
+        view_caps: ViewCapabilities,
+    ) -> Result<Item<B::Image, A::Block>, FactoryError> {
+        self.allocator.create_image(
+            device, request, kind, level, format, tiling, usage, view_caps,
+        )
+    }
+
+    /// Queue the buffer to be destroyed and its memory freed once `epoch` is completed.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `buffer`: buffer created by `create_buffer`
+    /// - `epoch`: last epoch the device may use the buffer during
+    pub fn destroy_buffer(&mut self, buffer: Item<B::Buffer, A::Block>, epoch: u64) {
+        self.retire(Retired::Buffer(buffer), epoch);
+    }
+
+    /// Queue the image to be destroyed and its memory freed once `epoch` is completed.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `image`: image created by `create_image`
+    /// - `epoch`: last epoch the device may use the image during
+    pub fn destroy_image(&mut self, image: Item<B::Image, A::Block>, epoch: u64) {
+        self.retire(Retired::Image(image), epoch);
+    }
+
+    /// Free blocks and destroy resources queued with epochs up to `completed`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: device the blocks were allocated from
+    /// - `completed`: the last epoch the device is done with
+    ///
+    /// ### Safety
+    ///
+    /// The device must be done with everything queued with epochs up to `completed`, e.g. after
+    /// waiting on the fence of that epoch.
+    pub unsafe fn cleanup(&mut self, device: &B::Device, completed: u64) {
+        let later = match completed.checked_add(1) {
+            Some(later) => self.pending.split_off(&later),
+            None => BTreeMap::new(),
+        };
+        let done = replace(&mut self.pending, later);
+        for retired in done.into_values().flatten() {
+            match retired {
+                Retired::Block(block) => self.allocator.free(device, block),
+                Retired::Buffer(buffer) => self.allocator.destroy_buffer(device, buffer),
+                Retired::Image(image) => self.allocator.destroy_image(device, image),
+            }
+        }
+    }
+
+    /// Free everything queued and unwrap the inner allocator.
+    ///
+    /// ### Safety
+    ///
+    /// The device must be done with all queued blocks, e.g. after waiting for it to become idle.
+    pub unsafe fn into_inner(mut self, device: &B::Device) -> A {
+        self.cleanup(device, !0);
+        self.allocator
+    }
+
+    fn retire(&mut self, retired: Retired<B, A::Block>, epoch: u64) {
+        self.pending.entry(epoch).or_default().push(retired);
+    }
+}
+
+#[test]
+fn test_deferred_destroy() {
+    use gfx_hal::MemoryTypeId;
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut deferred = Deferred::new(RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1));
+    unsafe {
+        let buffer = deferred
+            .create_buffer(&device, (), 1024, BufferUsage::UNIFORM)
+            .unwrap();
+        let block = deferred
+            .alloc(
+                &device,
+                (),
+                Requirements {
+                    size: 256,
+                    alignment: 1,
+                    type_mask: 1,
+                },
+            )
+            .unwrap();
+        deferred.destroy_buffer(buffer, 2);
+        deferred.free(block, 1);
+        assert_eq!(deferred.pending(), 2);
+
+        // Nothing is freed before its epoch completes.
+        deferred.cleanup(&device, 0);
+        assert_eq!(device.object_count(), 2);
+        deferred.cleanup(&device, 1);
+        assert_eq!(device.object_count(), 1);
+        deferred.cleanup(&device, 2);
+        assert_eq!(device.object_count(), 0);
+        assert_eq!(deferred.pending(), 0);
+
+        deferred.into_inner(&device).dispose(&device).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+/// Limits of a single defragmentation pass.
+///
+/// Planning stops before exceeding either limit, so large heaps can be defragmented
+/// incrementally, a few moves per frame. Planners subtract the work they planned from the budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DefragBudget {
+    /// Maximum number of bytes to copy.
+    pub bytes: u64,
+
+    /// Maximum number of blocks to move.
+    pub moves: usize,
+}
+
+impl DefragBudget {
+    /// Create a budget without limits.
+    pub fn unlimited() -> Self {
+        DefragBudget {
+            bytes: !0,
+            moves: !0,
+        }
+    }
+
+    /// Check if the budget allows moving `moves` blocks of `bytes` in total.
+    pub(crate) fn allows(&self, bytes: u64, moves: usize) -> bool {
+        bytes <= self.bytes && moves <= self.moves
+    }
+
+    /// Take the work from the budget.
+    pub(crate) fn spend(&mut self, bytes: u64, moves: usize) {
+        self.bytes -= bytes;
+        self.moves -= moves;
+    }
+}
+
+/// Move of a block planned by defragmentation.
+///
+/// The caller copies the contents of the block at `index` of the slice passed to the planner into
+/// `block`, rebinds resources to `block`, and then returns the old block with `commit_defrag`.
+/// To abandon the move, return `block` with `cancel_defrag` instead.
+#[derive(Debug)]
+pub struct DefragMove<T> {
+    /// Index of the moved block in the slice passed to the planner.
+    pub index: usize,
+
+    /// New location of the block.
+    pub block: T,
+}
@@ -0,0 +1,292 @@
+use std::io::Write;
+
+use gfx_hal::memory::Properties;
+use serde::Serialize;
+use serde_json;
+
+use smart::Heap;
+
+/// Version of the dump schema. Incremented on incompatible changes.
+pub const DUMP_VERSION: u32 = 1;
+
+/// Full state of a `SmartAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct AllocatorDump {
+    /// Version of the dump schema, see `DUMP_VERSION`.
+    pub version: u32,
+
+    /// State of each memory heap.
+    pub heaps: Vec<HeapDump>,
+
+    /// State of each memory type.
+    pub memory_types: Vec<MemoryTypeDump>,
+}
+
+impl AllocatorDump {
+    /// Collect state of the heaps and combine it with state of the memory types.
+    pub(crate) fn new(memory_types: Vec<MemoryTypeDump>, heaps: &[Heap]) -> Self {
+        AllocatorDump {
+            version: DUMP_VERSION,
+            heaps: heaps
+                .iter()
+                .enumerate()
+                .map(|(index, heap)| HeapDump {
+                    index,
+                    size: heap.size(),
+                    budget: heap.limit(),
+                    used: heap.used(),
+                    external: heap.external(),
+                })
+                .collect(),
+            memory_types,
+        }
+    }
+
+    /// Serialize the dump as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Dump is always serializable")
+    }
+
+    /// Write the dump as JSON.
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<(), serde_json::Error> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+}
+
+/// State of a memory heap.
+#[derive(Clone, Debug, Serialize)]
+pub struct HeapDump {
+    /// Index of the heap.
+    pub index: usize,
+
+    /// Size of the heap.
+    pub size: u64,
+
+    /// Budget of the heap.
+    pub budget: u64,
+
+    /// Total size of memory objects the allocator allocated from the heap.
+    pub used: u64,
+
+    /// Memory of the heap used outside of the allocator.
+    pub external: u64,
+}
+
+/// State of a memory type.
+#[derive(Clone, Debug, Serialize)]
+pub struct MemoryTypeDump {
+    /// Index of the memory type.
+    pub index: usize,
+
+    /// Names of properties of the memory type.
+    pub properties: Vec<&'static str>,
+
+    /// Index of the heap the memory type allocates from.
+    pub heap_index: usize,
+
+    /// State of the allocator of the memory type.
+    pub allocator: CombinedDump,
+}
+
+/// State of a `CombinedAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CombinedDump {
+    /// Device memory objects allocated for a single block.
+    pub dedicated: Vec<ChunkDump>,
+
+    /// State of the arena sub-allocator.
+    pub arena: ArenaDump,
+
+    /// State of the chunked sub-allocator.
+    pub chunked: ChunkedDump,
+
+    /// State of the TLSF sub-allocator.
+    pub tlsf: TlsfDump,
+
+    /// State of the buddy sub-allocator.
+    pub buddy: BuddyDump,
+}
+
+/// Chunk of memory and live blocks sub-allocated from it.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChunkDump {
+    /// Number of the device memory object the chunk belongs to. Memory objects of each memory
+    /// type are numbered in allocation order, starting from zero.
+    pub memory: u64,
+
+    /// Offset of the chunk in the memory object.
+    pub offset: u64,
+
+    /// Size of the chunk.
+    pub size: u64,
+
+    /// Blocks in use, sorted by offset.
+    pub blocks: Vec<BlockDump>,
+}
+
+/// Block of memory in use.
+#[derive(Clone, Debug, Serialize)]
+pub struct BlockDump {
+    /// Offset of the block in the memory object.
+    pub offset: u64,
+
+    /// Size of the block.
+    pub size: u64,
+}
+
+/// State of an `ArenaAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ArenaDump {
+    /// Minimum size of arena chunks.
+    pub chunk_size: u64,
+
+    /// Chunk of each arena node, from the oldest to the hot one.
+    pub nodes: Vec<ChunkDump>,
+}
+
+/// State of a `ChunkedAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChunkedDump {
+    /// State of each size class, from the smallest to the biggest.
+    pub size_classes: Vec<SizeClassDump>,
+}
+
+/// State of a size class of a `ChunkedAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SizeClassDump {
+    /// Size of blocks of the size class.
+    pub block_size: u64,
+
+    /// Size of chunks of the size class.
+    pub chunk_size: u64,
+
+    /// Chunks allocated for the size class.
+    pub chunks: Vec<ChunkDump>,
+}
+
+/// State of a `TlsfAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TlsfDump {
+    /// Minimum size of chunks.
+    pub chunk_size: u64,
+
+    /// Chunks allocated from the underlying allocator.
+    pub chunks: Vec<ChunkDump>,
+}
+
+/// State of a `BuddyAllocator`.
+#[derive(Clone, Debug, Serialize)]
+pub struct BuddyDump {
+    /// Size of the smallest blocks.
+    pub min_block_size: u64,
+
+    /// Size of chunks.
+    pub chunk_size: u64,
+
+    /// Chunks allocated from the underlying allocator.
+    pub chunks: Vec<ChunkDump>,
+}
+
+/// Get names of memory properties.
+pub(crate) fn property_names(properties: Properties) -> Vec<&'static str> {
+    [
+        (Properties::DEVICE_LOCAL, "DEVICE_LOCAL"),
+        (Properties::CPU_VISIBLE, "CPU_VISIBLE"),
+        (Properties::COHERENT, "COHERENT"),
+        (Properties::CPU_CACHED, "CPU_CACHED"),
+        (Properties::LAZILY_ALLOCATED, "LAZILY_ALLOCATED"),
+    ]
+    .iter()
+    .filter(|&&(flag, _)| properties.contains(flag))
+    .map(|&(_, name)| name)
+    .collect()
+}
+
+#[test]
+fn test_json() {
+    use gfx_hal::memory::Requirements;
+    use mock::{MockBackend, MockDevice};
+    use serde_json::Value;
+    use smart::SmartAllocator;
+    use {MemoryAllocator, ResourceKind, Type};
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = |size| Requirements {
+        size,
+        alignment: 256,
+        type_mask: 1,
+    };
+    unsafe {
+        let blocks = vec![
+            allocator
+                .alloc(
+                    &device,
+                    (
+                        Type::General,
+                        Properties::DEVICE_LOCAL.into(),
+                        ResourceKind::Linear,
+                    ),
+                    reqs(1000),
+                )
+                .unwrap(),
+            allocator
+                .alloc(
+                    &device,
+                    (
+                        Type::Dedicated,
+                        Properties::DEVICE_LOCAL.into(),
+                        ResourceKind::Linear,
+                    ),
+                    reqs(1 << 20),
+                )
+                .unwrap(),
+        ];
+        let dump: Value = serde_json::from_str(&allocator.dump().to_json()).unwrap();
+        assert_eq!(dump["version"], DUMP_VERSION);
+        assert_eq!(dump["heaps"].as_array().unwrap().len(), 2);
+        assert_eq!(dump["heaps"][0]["used"], device.heap_usage(0));
+        let memory_type = &dump["memory_types"][0];
+        assert_eq!(
+            memory_type["properties"],
+            serde_json::json!(["DEVICE_LOCAL"])
+        );
+        assert_eq!(memory_type["heap_index"], 0);
+
+        // Memory objects are numbered in allocation order: the chunk first, then the
+        // dedicated block.
+        let allocator_dump = &memory_type["allocator"];
+        let chunks: Vec<_> = allocator_dump["chunked"]["size_classes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|class| class["chunks"].as_array().unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0]["memory"], 0);
+        assert_eq!(chunks[0]["blocks"].as_array().unwrap().len(), 1);
+        assert_eq!(chunks[0]["blocks"][0]["size"], 1024);
+        assert_eq!(
+            allocator_dump["dedicated"],
+            serde_json::json!([{
+                "memory": 1,
+                "offset": 0,
+                "size": 1 << 20,
+                "blocks": [{ "offset": 0, "size": 1 << 20 }],
+            }])
+        );
+
+        for block in blocks {
+            allocator.free(&device, block);
+        }
+        allocator.dispose(&device).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::ops::Range;
+use std::slice;
+
+use gfx_hal::{Backend, MemoryTypeId};
+
+use block::{Block, RawBlock};
+use root::{memory_key, RootAllocator};
+use MappingAllocator;
+
+/// Value every byte of the guard bands is filled with.
+pub const GUARD_PATTERN: u8 = 0xAB;
+
+/// Block with overwritten guard bands, reported by `CombinedAllocator::check_corruption`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptedBlock {
+    /// Memory type the block was allocated from.
+    pub memory_type: MemoryTypeId,
+
+    /// Identifier of the device memory object the block belongs to.
+    pub memory: u64,
+
+    /// Offset of the block in the memory object.
+    pub offset: u64,
+
+    /// Size of the block.
+    pub size: u64,
+
+    /// Whether the guard band before the block was overwritten.
+    pub underrun: bool,
+
+    /// Whether the guard band after the block was overwritten.
+    pub overrun: bool,
+}
+
+impl fmt::Display for CorruptedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let band = match (self.underrun, self.overrun) {
+            (true, true) => "before and after",
+            (true, false) => "before",
+            _ => "after",
+        };
+        write!(
+            f,
+            "Guard band {} block of {} bytes at offset {} of memory {:#x} of type {} was overwritten",
+            band, self.size, self.offset, self.memory, self.memory_type.0,
+        )
+    }
+}
+
+/// Block handed out with guard bands, along with the bigger block it was carved from.
+#[derive(Debug)]
+pub(crate) struct GuardedBlock<M> {
+    /// Block returned by the sub-allocator, including the guard bands.
+    pub(crate) outer: RawBlock<M>,
+
+    /// Range of the memory object given to the user.
+    pub(crate) inner: Range<u64>,
+}
+
+impl<M> GuardedBlock<M>
+where
+    M: Debug + Any,
+{
+    /// Fill the guard bands with the pattern through a mapped pointer.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `root`: allocator the memory object of the block was allocated from
+    /// - `device`: same device that was used to allocate the block
+    pub(crate) unsafe fn fill<B>(&self, root: &mut RootAllocator<B>, device: &B::Device)
+    where
+        B: Backend<Memory = M>,
+    {
+        let ptr = self.map(root, device);
+        let (before, after) = self.bands(ptr);
+        for byte in before.iter_mut().chain(after) {
+            *byte = GUARD_PATTERN;
+        }
+        if root.non_coherent_atom_size() > 1 {
+            for range in self.band_ranges() {
+                root.flush(device, &self.outer, range)
+                    .expect("Failed to flush guard bands");
+            }
+        }
+        root.unmap(device, &self.outer);
+    }
+
+    /// Check the pattern of the guard bands, returning whether the bands before and after the
+    /// block are intact.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `root`: allocator the memory object of the block was allocated from
+    /// - `device`: same device that was used to allocate the block
+    pub(crate) unsafe fn check<B>(
+        &self,
+        root: &mut RootAllocator<B>,
+        device: &B::Device,
+    ) -> (bool, bool)
+    where
+        B: Backend<Memory = M>,
+    {
+        let ptr = self.map(root, device);
+        if root.non_coherent_atom_size() > 1 {
+            // Only the bands are invalidated, so unflushed writes to the block are kept
+            for range in self.band_ranges() {
+                root.invalidate(device, &self.outer, range)
+                    .expect("Failed to invalidate guard bands");
+            }
+        }
+        let (before, after) = self.bands(ptr);
+        let intact = |band: &[u8]| band.iter().all(|&byte| byte == GUARD_PATTERN);
+        let result = (intact(before), intact(after));
+        root.unmap(device, &self.outer);
+        result
+    }
+
+    /// Describe the block for a report of corrupted guard bands.
+    pub(crate) fn corrupted(
+        &self,
+        memory_type: MemoryTypeId,
+        (before, after): (bool, bool),
+    ) -> CorruptedBlock {
+        CorruptedBlock {
+            memory_type,
+            memory: memory_key(self.outer.memory()) as u64,
+            offset: self.inner.start,
+            size: self.inner.end - self.inner.start,
+            underrun: !before,
+            overrun: !after,
+        }
+    }
+
+    unsafe fn map<B>(&self, root: &mut RootAllocator<B>, device: &B::Device) -> *mut u8
+    where
+        B: Backend<Memory = M>,
+    {
+        root.map(device, &self.outer, 0..self.outer.size())
+            .unwrap_or_else(|err| panic!("Failed to map guard bands: {:?}", err))
+    }
+
+    /// Ranges of the bands relative to the outer block.
+    fn band_ranges(&self) -> [Range<u64>; 2] {
+        let outer = self.outer.range();
+        [
+            0..self.inner.start - outer.start,
+            self.inner.end - outer.start..outer.end - outer.start,
+        ]
+    }
+
+    unsafe fn bands<'a>(&self, ptr: *mut u8) -> (&'a mut [u8], &'a mut [u8]) {
+        let [before, after] = self.band_ranges();
+        (
+            slice::from_raw_parts_mut(ptr, before.end as usize),
+            slice::from_raw_parts_mut(
+                ptr.add(after.start as usize),
+                (after.end - after.start) as usize,
+            ),
+        )
+    }
+}
+
+/// Blocks allocated with guard bands, keyed by memory object and offset of the inner block.
+#[derive(Debug)]
+pub(crate) struct Guards<M> {
+    size: u64,
+    blocks: HashMap<(usize, u64), GuardedBlock<M>>,
+}
+
+impl<M> Guards<M>
+where
+    M: Debug + Any,
+{
+    pub(crate) fn new() -> Self {
+        Guards {
+            size: 0,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Get the minimal size of each guard band. Zero if guard bands are disabled.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn set_size(&mut self, size: u64) {
+        self.size = size;
+    }
+
+    /// Get the guarded block a user block was carved from.
+    pub(crate) fn get<T>(&self, block: &T) -> Option<&GuardedBlock<M>>
+    where
+        T: Block<Memory = M>,
+    {
+        self.blocks
+            .get(&(memory_key(block.memory()), block.range().start))
+    }
+
+    /// Record a guarded block, returning the block to give to the user.
+    pub(crate) fn insert(&mut self, guarded: GuardedBlock<M>) -> RawBlock<M> {
+        let inner = RawBlock::new(guarded.outer.memory(), guarded.inner.clone());
+        self.blocks
+            .insert((memory_key(inner.memory()), guarded.inner.start), guarded);
+        inner
+    }
+
+    /// Forget a guarded block once the user block is freed.
+    pub(crate) fn remove<T>(&mut self, block: &T) -> Option<GuardedBlock<M>>
+    where
+        T: Block<Memory = M>,
+    {
+        self.blocks
+            .remove(&(memory_key(block.memory()), block.range().start))
+    }
+
+    /// Iterate over all guarded blocks.
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = &GuardedBlock<M>> {
+        self.blocks.values()
+    }
+}
+
+#[test]
+fn test_corruption() {
+    use combined::{ResourceKind, Type};
+    use gfx_hal::memory::{Properties, Requirements};
+    use mock::{MockBackend, MockDevice};
+    use smart::SmartAllocator;
+    use MemoryAllocator;
+
+    let device = MockDevice::default();
+    let mut allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    allocator.set_guard_size(16);
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 4,
+        type_mask: !0,
+    };
+    unsafe {
+        let coherent = allocator
+            .alloc(
+                &device,
+                (
+                    Type::ShortLived,
+                    (Properties::CPU_VISIBLE | Properties::COHERENT).into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        let cached = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    (Properties::CPU_VISIBLE | Properties::CPU_CACHED).into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        assert!(allocator.check_corruption(&device).is_empty());
+
+        // Overrun the coherent block and underrun the non-coherent one.
+        let ptr = allocator.map(&device, &coherent, 0..1000).unwrap();
+        *ptr.add(1000) = 0;
+        allocator.unmap(&device, &coherent);
+        let ptr = allocator.map(&device, &cached, 0..1024).unwrap();
+        *ptr.offset(-1) = 0;
+        allocator.flush(&device, &cached, 0..1024).unwrap();
+        allocator.unmap(&device, &cached);
+
+        let corrupted = allocator.check_corruption(&device);
+        assert_eq!(corrupted.len(), 2);
+        assert_eq!((corrupted[0].underrun, corrupted[0].overrun), (false, true));
+        assert_eq!((corrupted[1].underrun, corrupted[1].overrun), (true, false));
+        assert_eq!(corrupted[1].offset, cached.range().start);
+
+        // Repair the bands so the blocks can be freed.
+        *allocator
+            .map(&device, &coherent, 0..1000)
+            .unwrap()
+            .add(1000) = GUARD_PATTERN;
+        allocator.unmap(&device, &coherent);
+        *allocator.map(&device, &cached, 0..1024).unwrap().offset(-1) = GUARD_PATTERN;
+        allocator.flush(&device, &cached, 0..1024).unwrap();
+        allocator.unmap(&device, &cached);
+        allocator.free(&device, coherent);
+        allocator.free(&device, cached);
+        allocator.dispose(&device).unwrap();
+    }
+}
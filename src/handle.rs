@@ -0,0 +1,236 @@
+use std::mem::take;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use gfx_hal::memory::Requirements;
+use gfx_hal::Backend;
+
+use block::Block;
+use {MemoryAllocator, MemoryError};
+
+/// Blocks of dropped handles, shared between an allocator and its handles.
+type DropQueue<T> = Arc<Mutex<Vec<T>>>;
+
+fn lock<T>(queue: &Mutex<Vec<T>>) -> MutexGuard<'_, Vec<T>> {
+    // The queue is never left in an inconsistent state, so poisoning is harmless
+    queue
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Layer over a `MemoryAllocator` that allocates blocks wrapped in `BlockHandle`s, which return
+/// their blocks on drop.
+///
+/// Blocks of dropped handles are queued and returned to the inner allocator by `cleanup`, which
+/// needs the device and exclusive access to the allocator. Call it regularly, e.g. once per frame.
+/// Handles are `Send` and can be dropped on any thread.
+///
+/// Since handles may be dropped at any time, the device must not use their blocks anymore at
+/// that point. Keep the handles alive until the frames using them are done.
+///
+/// ### Type parameters:
+///
+/// - `B`: hal `Backend`
+/// - `A`: inner allocator
+#[derive(Debug)]
+pub struct HandleAllocator<B, A>
+where
+    B: Backend,
+    A: MemoryAllocator<B>,
+{
+    allocator: A,
+    dropped: DropQueue<A::Block>,
+}
+
+impl<B, A> HandleAllocator<B, A>
+where
+    B: Backend,
+    A: MemoryAllocator<B>,
+{
+    /// Wrap an allocator.
+    pub fn new(allocator: A) -> Self {
+        HandleAllocator {
+            allocator,
+            dropped: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.allocator
+    }
+
+    /// Get the inner allocator mutably, e.g. to map blocks.
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.allocator
+    }
+
+    /// Get the number of blocks of dropped handles waiting to be freed.
+    pub fn pending(&self) -> usize {
+        lock(&self.dropped).len()
+    }
+
+    /// Allocate a block of memory from the inner allocator, owned by a handle.
+    /// See `MemoryAllocator::alloc`.
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the inner allocator allocates from.
+    pub unsafe fn alloc(
+        &mut self,
+        device: &B::Device,
+        request: A::Request,
+        reqs: Requirements,
+    ) -> Result<BlockHandle<A::Block>, MemoryError> {
+        let block = self.allocator.alloc(device, request, reqs)?;
+        Ok(BlockHandle {
+            block: Some(block),
+            dropped: self.dropped.clone(),
+        })
+    }
+
+    /// Return blocks of dropped handles to the inner allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: device the blocks were allocated from
+    ///
+    /// ### Safety
+    ///
+    /// The device must be done with the blocks of dropped handles.
+    pub unsafe fn cleanup(&mut self, device: &B::Device) {
+        let dropped = take(&mut *lock(&self.dropped));
+        for block in dropped {
+            self.allocator.free(device, block);
+        }
+    }
+
+    /// Free blocks of dropped handles and unwrap the inner allocator.
+    ///
+    /// ### Panics
+    ///
+    /// Panics if any handle is still alive. Drop them or take their blocks with `into_block`
+    /// first.
+    ///
+    /// ### Safety
+    ///
+    /// The device must be done with the blocks of dropped handles.
+    pub unsafe fn into_inner(mut self, device: &B::Device) -> A {
+        assert_eq!(
+            Arc::strong_count(&self.dropped),
+            1,
+            "Block handles are still alive"
+        );
+        self.cleanup(device);
+        self.allocator
+    }
+}
+
+/// Block that is returned to its `HandleAllocator` when dropped.
+///
+/// ### Type parameters:
+///
+/// - `T`: block type of the allocator
+#[derive(Debug)]
+pub struct BlockHandle<T> {
+    /// Taken only on drop or by `into_block`
+    block: Option<T>,
+    dropped: DropQueue<T>,
+}
+
+impl<T> BlockHandle<T> {
+    /// Get the owned block, e.g. to map it with the inner allocator.
+    pub fn block(&self) -> &T {
+        self.block.as_ref().unwrap()
+    }
+
+    /// Take the block out of the handle. The block must then be freed manually.
+    pub fn into_block(mut self) -> T {
+        self.block.take().unwrap()
+    }
+}
+
+impl<T> Block for BlockHandle<T>
+where
+    T: Block,
+{
+    type Memory = T::Memory;
+
+    #[inline(always)]
+    fn memory(&self) -> &T::Memory {
+        self.block().memory()
+    }
+
+    #[inline(always)]
+    fn range(&self) -> Range<u64> {
+        self.block().range()
+    }
+}
+
+impl<T> Drop for BlockHandle<T> {
+    fn drop(&mut self) {
+        if let Some(block) = self.block.take() {
+            lock(&self.dropped).push(block);
+        }
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn test_send_sync() {
+    fn foo<T: Send + Sync>() {}
+    fn bar<B: Backend, A: MemoryAllocator<B>>() {
+        foo::<BlockHandle<A::Block>>()
+    }
+}
+
+#[test]
+fn test_free_on_drop() {
+    use gfx_hal::MemoryTypeId;
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut allocator = HandleAllocator::new(RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1));
+    let reqs = Requirements {
+        size: 256,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        let first = allocator.alloc(&device, (), reqs).unwrap();
+        let second = allocator.alloc(&device, (), reqs).unwrap();
+        ::std::thread::spawn(move || drop(first)).join().unwrap();
+        assert_eq!(allocator.pending(), 1);
+        assert_eq!(device.object_count(), 2);
+
+        allocator.cleanup(&device);
+        assert_eq!(device.object_count(), 1);
+
+        // Taken blocks are freed manually.
+        let block = second.into_block();
+        allocator.inner_mut().free(&device, block);
+        assert_eq!(allocator.pending(), 0);
+        allocator.into_inner(&device).dispose(&device).unwrap();
+    }
+}
+
+#[test]
+#[should_panic(expected = "Block handles are still alive")]
+fn test_into_inner_with_handles() {
+    use gfx_hal::MemoryTypeId;
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut allocator = HandleAllocator::new(RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1));
+    let reqs = Requirements {
+        size: 256,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        let _handle = allocator.alloc(&device, (), reqs).unwrap();
+        allocator.into_inner(&device);
+    }
+}
@@ -11,8 +11,7 @@
 //!
 //! use gfx_hal::{Backend, Device};
 //! use gfx_hal::buffer::Usage;
-//! use gfx_hal::memory::Properties;
-//! use gfx_memory::{MemoryAllocator, SmartAllocator, SmartBlock, Type, Block};
+//! use gfx_memory::{MemoryAllocator, SmartAllocator, SmartBlock, Type, MemoryUsage, ResourceKind, Block};
 //!
 //! fn make_vertex_buffer<B: Backend>(device: &B::Device,
 //!                                   allocator: &mut SmartAllocator<B>,
@@ -23,8 +22,9 @@
 //!     let mut buf = unsafe { device.create_buffer(size, Usage::VERTEX)? };
 //!     // Ger memory requirements for the buffer.
 //!     let reqs = unsafe { device.get_buffer_requirements(&buf) };
-//!     // Allocate block of device-local memory that satisfy requirements for buffer.
-//!     let block = unsafe { allocator.alloc(device, (Type::General, Properties::DEVICE_LOCAL), reqs)? };
+//!     // Allocate block of memory for use by the device only that satisfy requirements for buffer.
+//!     let request = (Type::General, MemoryUsage::GpuOnly.into(), ResourceKind::Linear);
+//!     let block = unsafe { allocator.alloc(device, request, reqs)? };
 //!     // Bind memory block to the buffer.
 //!     unsafe { device.bind_buffer_memory(block.memory(), block.range().start, &mut buf)? };
 //!     Ok((block, buf))
@@ -38,35 +38,94 @@
 #![deny(unused_imports)]
 #![deny(unused_must_use)]
 
+#[cfg(feature = "checks")]
+extern crate backtrace;
 extern crate gfx_hal;
 #[macro_use]
 extern crate failure;
 extern crate relevant;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(any(test, feature = "dump"))]
+extern crate serde_json;
 
 pub use arena::{ArenaAllocator, ArenaBlock};
 pub use block::{Block, RawBlock};
+pub use buddy::{BuddyAllocator, BuddyBlock};
+#[cfg(feature = "checks")]
+pub use checks::LiveBlock;
 pub use chunked::{ChunkedAllocator, ChunkedBlock};
-pub use combined::{CombinedAllocator, CombinedBlock, Type};
+pub use combined::{CombinedAllocator, CombinedBlock, ResourceKind, Type};
+pub use config::{
+    CombinedConfig, CombinedProfile, ConfigError, HeapProfile, MemoryTypeProfile, Profile,
+    SmartConfig,
+};
+pub use deferred::Deferred;
+pub use defrag::{DefragBudget, DefragMove};
+#[cfg(feature = "dump")]
+pub use dump::{
+    AllocatorDump, ArenaDump, BlockDump, BuddyDump, ChunkDump, ChunkedDump, CombinedDump, HeapDump,
+    MemoryTypeDump, SizeClassDump, TlsfDump, DUMP_VERSION,
+};
 pub use factory::{Factory, FactoryError, Item};
+pub use guard::{CorruptedBlock, GUARD_PATTERN};
+pub use handle::{BlockHandle, HandleAllocator};
+#[cfg(any(test, feature = "mock"))]
+pub use mock::{
+    LeakedMemory, MockBackend, MockBuffer, MockDevice, MockImage, MockMemory,
+    MOCK_BUFFER_ALIGNMENT, MOCK_IMAGE_ALIGNMENT,
+};
+pub use ring::{RingAllocator, RingBlock, RingFull};
 pub use root::RootAllocator;
-pub use smart::{SmartAllocator, SmartBlock};
+pub use smart::{
+    Budget, CandidateType, ErrorContext, MemoryUsage, Rejection, RequestedProperties,
+    SharedSmartAllocator, SmartAllocator, SmartBlock,
+};
+pub use stats::{
+    ArenaStats, BuddyStats, ChunkedStats, CombinedStats, HeapStats, MemoryTypeStats,
+    SizeClassStats, SmartStats, TlsfStats,
+};
+pub use tlsf::{TlsfAllocator, TlsfBlock};
+#[cfg(any(test, feature = "mock"))]
+pub use trace::replay;
+pub use trace::{
+    read_trace, ReplayFailure, ReplayReport, TraceAllocator, TraceError, TraceEvent, TraceRequest,
+    TracedBlock, TracingAllocator,
+};
 
 use std::cmp::PartialOrd;
 use std::fmt::Debug;
-use std::ops::{Add, BitOr, Sub};
+use std::ops::{Add, BitOr, Range, Sub};
 
 use gfx_hal::device::AllocationError;
 use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
 use gfx_hal::memory::Requirements;
 use gfx_hal::Backend;
 
 mod arena;
 mod block;
+mod buddy;
+#[cfg(feature = "checks")]
+mod checks;
 mod chunked;
 mod combined;
+mod config;
+mod deferred;
+mod defrag;
+#[cfg(feature = "dump")]
+mod dump;
 mod factory;
+mod guard;
+mod handle;
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod ring;
 mod root;
 mod smart;
+mod stats;
+mod tlsf;
+mod trace;
 
 /// Possible errors that may be returned from allocators.
 #[derive(Clone, Debug, Fail)]
@@ -82,6 +141,70 @@ pub enum MemoryError {
     /// Implementations might have a limit on number of allocations
     #[fail(display = "Can't allocate more objects")]
     TooManyObjects,
+
+    /// Compatible memory is available, but using it would exceed the heap budget.
+    #[fail(display = "Memory budget exceeded")]
+    BudgetExceeded,
+
+    /// Block is bigger than the sub-allocator supports, e.g. than `max_chunk_size` of the
+    /// `ChunkedAllocator`. The device memory itself may be available.
+    #[fail(display = "Block too large for the sub-allocator")]
+    TooLarge,
+
+    /// Allocation of `SmartAllocator` failed. Carries the request and the state of every memory
+    /// type considered for it along with the error.
+    #[fail(display = "{}", _0)]
+    Context(Box<ErrorContext>),
+}
+
+impl MemoryError {
+    /// Get the error without context, e.g. to match on the reason of the failure.
+    pub fn inner(&self) -> &MemoryError {
+        match *self {
+            MemoryError::Context(ref context) => context.error.inner(),
+            ref error => error,
+        }
+    }
+}
+
+/// Reasons why a block can't be freed by an allocator.
+///
+/// Returned by the `validate` methods of the allocators. When the `checks` feature is enabled,
+/// allocators validate each block they free and panic with the error before touching their state.
+#[derive(Clone, Debug, Fail, PartialEq, Eq)]
+pub enum BlockError {
+    /// Block was allocated by another allocator.
+    #[fail(display = "Block was not allocated by this allocator")]
+    ForeignBlock,
+
+    /// Block belongs to a chunk which was already returned to the underlying allocator, so the
+    /// block must have been freed before.
+    #[fail(display = "Block belongs to a chunk that was already released")]
+    ReleasedChunk,
+
+    /// Block is within memory of the allocator but doesn't match any allocation.
+    #[fail(display = "Block range doesn't match any allocation")]
+    InvalidRange,
+
+    /// Block was already freed.
+    #[fail(display = "Block was already freed")]
+    DoubleFree,
+}
+
+/// Panic with a description of the error if a block can't be freed.
+#[cfg(feature = "checks")]
+fn check_free<T>(block: &T, result: Result<(), BlockError>)
+where
+    T: Block,
+{
+    if let Err(error) = result {
+        panic!(
+            "Invalid free of block {:?} of memory {:p}: {}",
+            block.range(),
+            block.memory(),
+            error
+        );
+    }
 }
 
 impl From<OutOfMemory> for MemoryError {
@@ -225,6 +348,179 @@ pub trait MemorySubAllocator<B: Backend, O> {
         Self: Sized;
 }
 
+/// Trait for allocators that can map memory of the blocks they allocate into host address space.
+///
+/// Mappings are reference counted per device memory object, so blocks sharing the same memory
+/// object can be mapped at the same time.
+///
+/// Mapping hands out a raw pointer rather than a slice. A `&mut [u8]` would claim exclusive
+/// access that the allocator cannot guarantee: the device may read or write the memory at any
+/// time, and other blocks may map overlapping atoms of the same memory object. The caller
+/// upholds these rules and can build a slice of the mapped range with
+/// `std::slice::from_raw_parts_mut` when it knows no one else accesses the memory.
+pub trait MappingAllocator<B: Backend>: MemoryAllocator<B> {
+    /// Map a range of a block into host address space.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the block of memory
+    /// - `block`: block of memory to map, must be allocated from this allocator
+    /// - `range`: range of the block to map, relative to the start of the block
+    ///
+    /// ### Returns
+    ///
+    /// Returns a pointer to the start of `range`, which stays valid until the matching `unmap`.
+    ///
+    /// ### Safety
+    ///
+    /// The block must be allocated from this allocator using the same `device`. The pointer must
+    /// only be used to access `range`, and not after the matching `unmap`.
+    unsafe fn map(
+        &mut self,
+        device: &B::Device,
+        block: &Self::Block,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError>;
+
+    /// Release a mapping acquired with `map`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to map the block
+    /// - `block`: block of memory that was mapped
+    ///
+    /// ### Safety
+    ///
+    /// Each `unmap` must match an earlier `map` of the same block, and pointers returned by that
+    /// `map` must not be used afterwards.
+    unsafe fn unmap(&mut self, device: &B::Device, block: &Self::Block);
+
+    /// Flush host writes to a mapped range of a block, making them visible to the device.
+    ///
+    /// The range is expanded to the non-coherent atom size of the device, but never beyond the
+    /// memory object the block was allocated from.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to map the block
+    /// - `block`: mapped block of memory
+    /// - `range`: range of the block to flush, relative to the start of the block
+    ///
+    /// ### Safety
+    ///
+    /// The block must be mapped, and the device must not access `range` during the flush.
+    unsafe fn flush(
+        &mut self,
+        device: &B::Device,
+        block: &Self::Block,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory>;
+
+    /// Invalidate a mapped range of a block, making device writes visible to the host.
+    ///
+    /// The range is expanded to the non-coherent atom size of the device, but never beyond the
+    /// memory object the block was allocated from.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to map the block
+    /// - `block`: mapped block of memory
+    /// - `range`: range of the block to invalidate, relative to the start of the block
+    ///
+    /// ### Safety
+    ///
+    /// The block must be mapped, and the device must have finished writing `range`.
+    unsafe fn invalidate(
+        &mut self,
+        device: &B::Device,
+        block: &Self::Block,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory>;
+}
+
+/// Trait for sub-allocators that can map memory of the blocks they allocate into host address
+/// space using the allocator the bigger chunks came from.
+pub trait MappingSubAllocator<B: Backend, O>: MemorySubAllocator<B, O> {
+    /// Map a range of a block into host address space.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the inner memory blocks
+    /// - `device`: same device that was used to allocate the block of memory
+    /// - `block`: block of memory to map, must be allocated from this allocator
+    /// - `range`: range of the block to map, relative to the start of the block
+    ///
+    /// ### Returns
+    ///
+    /// Returns a pointer to the start of `range`, which stays valid until the matching `unmap`.
+    ///
+    /// ### Safety
+    ///
+    /// The block must be allocated from this allocator using the same `device`. The pointer must
+    /// only be used to access `range`, and not after the matching `unmap`.
+    unsafe fn map(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &Self::Block,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError>;
+
+    /// Release a mapping acquired with `map`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the inner memory blocks
+    /// - `device`: same device that was used to map the block
+    /// - `block`: block of memory that was mapped
+    ///
+    /// ### Safety
+    ///
+    /// Each `unmap` must match an earlier `map` of the same block, and pointers returned by that
+    /// `map` must not be used afterwards.
+    unsafe fn unmap(&mut self, owner: &mut O, device: &B::Device, block: &Self::Block);
+
+    /// Flush host writes to a mapped range of a block, making them visible to the device.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the inner memory blocks
+    /// - `device`: same device that was used to map the block
+    /// - `block`: mapped block of memory
+    /// - `range`: range of the block to flush, relative to the start of the block
+    ///
+    /// ### Safety
+    ///
+    /// The block must be mapped, and the device must not access `range` during the flush.
+    unsafe fn flush(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &Self::Block,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory>;
+
+    /// Invalidate a mapped range of a block, making device writes visible to the host.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the inner memory blocks
+    /// - `device`: same device that was used to map the block
+    /// - `block`: mapped block of memory
+    /// - `range`: range of the block to invalidate, relative to the start of the block
+    ///
+    /// ### Safety
+    ///
+    /// The block must be mapped, and the device must have finished writing `range`.
+    unsafe fn invalidate(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &Self::Block,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory>;
+}
+
 /// Calculate shift from specified offset required to satisfy alignment.
 pub fn alignment_shift<T>(alignment: T, offset: T) -> T
 where
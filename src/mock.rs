@@ -0,0 +1,1329 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use gfx_hal::command::{
+    self, AttachmentClear, BufferCopy, BufferImageCopy, ClearColorRaw, ClearDepthStencilRaw,
+    ClearValueRaw, CommandBufferFlags, CommandBufferInheritanceInfo, DescriptorSetOffset,
+    ImageBlit, ImageCopy, ImageResolve, SubpassContents,
+};
+use gfx_hal::device::{
+    AllocationError, BindError, DeviceLost, OomOrDeviceLost, OutOfMemory, ShaderError,
+};
+use gfx_hal::error::{DeviceCreationError, HostExecutionError};
+use gfx_hal::format::{self, Format};
+use gfx_hal::image::{self, Filter, Layout, SubresourceRange};
+use gfx_hal::memory::{Barrier, Dependencies, Properties, Requirements};
+use gfx_hal::pool::{CommandPoolCreateFlags, RawCommandPool};
+use gfx_hal::pso::{self, DescriptorPoolCreateFlags};
+use gfx_hal::queue::{QueueFamilyId, RawCommandQueue};
+use gfx_hal::range::RangeArg;
+use gfx_hal::window::{self, PresentError, Suboptimal};
+use gfx_hal::{
+    buffer, mapping, pass, query, AcquireError, Backend, DescriptorPool, Device, DrawCount,
+    Features, Gpu, IndexCount, InstanceCount, Limits, MemoryProperties, MemoryType, MemoryTypeId,
+    PhysicalDevice, PresentMode, QueueFamily, QueuePriority, QueueType, Submission, Surface,
+    SurfaceCapabilities, SwapImageIndex, Swapchain, SwapchainConfig, VertexCount, VertexOffset,
+    WorkGroupCount,
+};
+
+/// Alignment of memory requirements of buffers created by `MockDevice`.
+pub const MOCK_BUFFER_ALIGNMENT: u64 = 256;
+
+/// Alignment of memory requirements of images created by `MockDevice`.
+pub const MOCK_IMAGE_ALIGNMENT: u64 = 4096;
+
+/// Backend without a GPU for testing allocators.
+///
+/// Only memory management is implemented by `MockDevice`: memory objects, buffers and images.
+/// Other objects can't be created and their types are uninhabited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MockBackend {}
+
+impl Backend for MockBackend {
+    type PhysicalDevice = MockPhysicalDevice;
+    type Device = MockDevice;
+
+    type Surface = MockSurface;
+    type Swapchain = MockSwapchain;
+
+    type QueueFamily = MockQueueFamily;
+    type CommandQueue = MockCommandQueue;
+    type CommandBuffer = MockCommandBuffer;
+
+    type ShaderModule = Unsupported;
+    type RenderPass = Unsupported;
+    type Framebuffer = Unsupported;
+
+    type Memory = MockMemory;
+    type CommandPool = MockCommandPool;
+
+    type Buffer = MockBuffer;
+    type BufferView = Unsupported;
+    type Image = MockImage;
+    type ImageView = Unsupported;
+    type Sampler = Unsupported;
+
+    type ComputePipeline = Unsupported;
+    type GraphicsPipeline = Unsupported;
+    type PipelineCache = Unsupported;
+    type PipelineLayout = Unsupported;
+    type DescriptorPool = MockDescriptorPool;
+    type DescriptorSet = Unsupported;
+    type DescriptorSetLayout = Unsupported;
+
+    type Fence = Unsupported;
+    type Semaphore = Unsupported;
+    type QueryPool = Unsupported;
+}
+
+/// Device memory object allocated by `MockDevice`.
+#[derive(Debug)]
+pub struct MockMemory {
+    id: u64,
+    memory_type: MemoryTypeId,
+    size: u64,
+}
+
+impl MockMemory {
+    /// Get the unique identifier of the memory object.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get memory type of the memory object.
+    pub fn memory_type(&self) -> MemoryTypeId {
+        self.memory_type
+    }
+
+    /// Get size of the memory object.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Buffer created by `MockDevice`.
+#[derive(Debug)]
+pub struct MockBuffer {
+    requirements: Requirements,
+    binding: Option<(u64, u64)>,
+}
+
+impl MockBuffer {
+    /// Get identifier of the memory object and offset the buffer is bound to.
+    pub fn binding(&self) -> Option<(u64, u64)> {
+        self.binding
+    }
+}
+
+/// Image created by `MockDevice`.
+#[derive(Debug)]
+pub struct MockImage {
+    requirements: Requirements,
+    binding: Option<(u64, u64)>,
+}
+
+impl MockImage {
+    /// Get identifier of the memory object and offset the image is bound to.
+    pub fn binding(&self) -> Option<(u64, u64)> {
+        self.binding
+    }
+}
+
+/// Memory object that was not freed, as reported by `MockDevice::leaks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeakedMemory {
+    /// Identifier of the memory object.
+    pub id: u64,
+
+    /// Memory type of the memory object.
+    pub memory_type: MemoryTypeId,
+
+    /// Size of the memory object.
+    pub size: u64,
+}
+
+/// Fake device that allocates memory objects in host memory.
+///
+/// Tracks every memory object and the usage of every heap. Allocation fails with
+/// `OutOfDeviceMemory` when the heap is exhausted and with `TooManyObjects` when the object limit
+/// is reached. More failures can be injected with `inject_failure`.
+///
+/// Usage is validated the way a conforming driver would require: memory must be host visible to
+/// be mapped, can be mapped only once at a time, and flushed or invalidated ranges must be mapped
+/// and aligned to the non-coherent atom size. Violations panic.
+///
+/// Memory objects that are still allocated when the device is dropped are reported as leaks by
+/// panicking. Use `leaks` to inspect them beforehand.
+#[derive(Debug)]
+pub struct MockDevice {
+    memory_properties: MemoryProperties,
+    non_coherent_atom_size: u64,
+    state: Mutex<DeviceState>,
+}
+
+#[derive(Debug)]
+struct DeviceState {
+    next_id: u64,
+    objects: HashMap<u64, MemoryObject>,
+    heap_usage: Vec<u64>,
+    max_objects: Option<usize>,
+    failures: VecDeque<AllocationError>,
+    allocations: usize,
+}
+
+#[derive(Debug)]
+struct MemoryObject {
+    memory_type: MemoryTypeId,
+    size: u64,
+    data: Option<HostData>,
+    mapping: Option<Range<u64>>,
+}
+
+/// Host memory backing a mapped memory object.
+struct HostData(Box<[u8]>);
+
+impl fmt::Debug for HostData {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "HostData({} bytes)", self.0.len())
+    }
+}
+
+impl MockDevice {
+    /// Create a new mock device.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `memory_properties`: memory types and sizes of heaps of the device.
+    /// - `non_coherent_atom_size`: granularity of flush and invalidate operations on memory
+    ///   that is not `COHERENT`.
+    pub fn new(memory_properties: MemoryProperties, non_coherent_atom_size: u64) -> Self {
+        assert_ne!(non_coherent_atom_size, 0);
+        MockDevice {
+            state: Mutex::new(DeviceState {
+                next_id: 0,
+                objects: HashMap::new(),
+                heap_usage: vec![0; memory_properties.memory_heaps.len()],
+                max_objects: None,
+                failures: VecDeque::new(),
+                allocations: 0,
+            }),
+            memory_properties,
+            non_coherent_atom_size,
+        }
+    }
+
+    /// Get memory properties of the device.
+    pub fn memory_properties(&self) -> MemoryProperties {
+        self.memory_properties.clone()
+    }
+
+    /// Get the non-coherent atom size of the device.
+    pub fn non_coherent_atom_size(&self) -> u64 {
+        self.non_coherent_atom_size
+    }
+
+    /// Get limits of the device relevant to memory management.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            non_coherent_atom_size: self.non_coherent_atom_size as usize,
+            buffer_image_granularity: MOCK_IMAGE_ALIGNMENT,
+            ..Limits::default()
+        }
+    }
+
+    /// Limit the number of memory objects that can be allocated at the same time.
+    /// `None` removes the limit.
+    pub fn set_max_objects(&self, max_objects: Option<usize>) {
+        self.state().max_objects = max_objects;
+    }
+
+    /// Make one of the next allocations fail with `error`.
+    /// Injected failures are returned in order, one per allocation.
+    pub fn inject_failure(&self, error: AllocationError) {
+        self.state().failures.push_back(error);
+    }
+
+    /// Get the number of memory objects allocated and not yet freed.
+    pub fn object_count(&self) -> usize {
+        self.state().objects.len()
+    }
+
+    /// Get the number of successful allocations since the device was created.
+    pub fn allocation_count(&self) -> usize {
+        self.state().allocations
+    }
+
+    /// Get the total size of memory objects allocated from a heap.
+    pub fn heap_usage(&self, heap_index: usize) -> u64 {
+        self.state().heap_usage[heap_index]
+    }
+
+    /// Get the memory objects allocated and not yet freed, sorted by identifier.
+    pub fn leaks(&self) -> Vec<LeakedMemory> {
+        let mut leaks = self
+            .state()
+            .objects
+            .iter()
+            .map(|(&id, object)| LeakedMemory {
+                id,
+                memory_type: object.memory_type,
+                size: object.size,
+            })
+            .collect::<Vec<_>>();
+        leaks.sort_by_key(|leak| leak.id);
+        leaks
+    }
+
+    /// Check if a memory object is mapped.
+    pub fn is_mapped(&self, memory: &MockMemory) -> bool {
+        self.state().objects[&memory.id].mapping.is_some()
+    }
+
+    fn state(&self) -> MutexGuard<'_, DeviceState> {
+        self.state.lock().unwrap()
+    }
+
+    fn type_mask(&self) -> u64 {
+        (1 << self.memory_properties.memory_types.len()) - 1
+    }
+
+    fn bind(
+        &self,
+        memory: &MockMemory,
+        offset: u64,
+        reqs: Requirements,
+    ) -> Result<(u64, u64), BindError> {
+        assert!(
+            self.state().objects.contains_key(&memory.id),
+            "Memory {} is not allocated",
+            memory.id
+        );
+        assert_eq!(
+            offset % reqs.alignment,
+            0,
+            "Offset {} is not aligned to {}",
+            offset,
+            reqs.alignment
+        );
+        if (1 << memory.memory_type.0) & reqs.type_mask == 0 {
+            return Err(BindError::WrongMemory);
+        }
+        if offset > memory.size || memory.size - offset < reqs.size {
+            return Err(BindError::OutOfBounds);
+        }
+        Ok((memory.id, offset))
+    }
+
+    fn check_flush_ranges<'a, I, R>(&self, op: &str, ranges: I)
+    where
+        I: IntoIterator,
+        I::Item: Borrow<(&'a MockMemory, R)>,
+        R: RangeArg<u64>,
+    {
+        let state = self.state();
+        for range in ranges {
+            let (memory, ref range) = *range.borrow();
+            let object = &state.objects[&memory.id];
+            let mapping = object
+                .mapping
+                .as_ref()
+                .unwrap_or_else(|| panic!("{} of memory {} that is not mapped", op, memory.id));
+            let range = resolve_range(range, mapping.clone());
+            assert!(
+                mapping.start <= range.start
+                    && range.start <= range.end
+                    && range.end <= mapping.end,
+                "{} of range {:?} outside of mapped range {:?} of memory {}",
+                op,
+                range,
+                mapping,
+                memory.id,
+            );
+            let atom = self.non_coherent_atom_size;
+            assert!(
+                range.start % atom == 0 && (range.end % atom == 0 || range.end == object.size),
+                "{} of range {:?} of memory {} not aligned to non-coherent atom size {}",
+                op,
+                range,
+                memory.id,
+                atom,
+            );
+        }
+    }
+}
+
+impl Default for MockDevice {
+    /// Create a device with 1 GiB of device local memory and 256 MiB of host visible memory,
+    /// which is either coherent or cached. The non-coherent atom size is 256 bytes.
+    fn default() -> Self {
+        MockDevice::new(
+            MemoryProperties {
+                memory_types: vec![
+                    MemoryType {
+                        properties: Properties::DEVICE_LOCAL,
+                        heap_index: 0,
+                    },
+                    MemoryType {
+                        properties: Properties::CPU_VISIBLE | Properties::COHERENT,
+                        heap_index: 1,
+                    },
+                    MemoryType {
+                        properties: Properties::CPU_VISIBLE | Properties::CPU_CACHED,
+                        heap_index: 1,
+                    },
+                ],
+                memory_heaps: vec![1 << 30, 256 << 20],
+            },
+            256,
+        )
+    }
+}
+
+impl Drop for MockDevice {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            let leaks = self.leaks();
+            assert!(leaks.is_empty(), "Memory objects leaked: {:?}", leaks);
+        }
+    }
+}
+
+/// Resolve bounds of a range argument, defaulting to `whole`.
+fn resolve_range<R: RangeArg<u64>>(range: &R, whole: Range<u64>) -> Range<u64> {
+    range.start().cloned().unwrap_or(whole.start)..range.end().cloned().unwrap_or(whole.end)
+}
+
+fn unsupported() -> ! {
+    panic!("Only memory management is supported by `MockDevice`")
+}
+
+/// Size of an image in bytes, including all layers and mip levels.
+fn image_size(kind: image::Kind, mip_levels: image::Level, format: Format) -> u64 {
+    let texel_size = u64::from(format.surface_desc().bits / 8).max(1);
+    let layer_size = (0..mip_levels)
+        .map(|level| {
+            let extent = kind.level_extent(level);
+            u64::from(extent.width) * u64::from(extent.height) * u64::from(extent.depth)
+        })
+        .sum::<u64>();
+    layer_size * u64::from(kind.num_layers()) * texel_size
+}
+
+/// Object of the mock backend that can't be created.
+#[derive(Debug)]
+pub enum Unsupported {}
+
+/// Physical device of the mock backend. Can't be created, use `MockDevice::new`.
+#[derive(Debug)]
+pub enum MockPhysicalDevice {}
+
+/// Surface of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockSurface {}
+
+/// Swapchain of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockSwapchain {}
+
+/// Queue family of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockQueueFamily {}
+
+/// Command queue of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockCommandQueue {}
+
+/// Command buffer of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockCommandBuffer {}
+
+/// Command pool of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockCommandPool {}
+
+/// Descriptor pool of the mock backend. Can't be created.
+#[derive(Debug)]
+pub enum MockDescriptorPool {}
+
+impl Device<MockBackend> for MockDevice {
+    unsafe fn allocate_memory(
+        &self,
+        memory_type: MemoryTypeId,
+        size: u64,
+    ) -> Result<MockMemory, AllocationError> {
+        let heap_index = self.memory_properties.memory_types[memory_type.0].heap_index;
+        let mut state = self.state();
+        if let Some(error) = state.failures.pop_front() {
+            return Err(error);
+        }
+        if let Some(max_objects) = state.max_objects {
+            if state.objects.len() >= max_objects {
+                return Err(AllocationError::TooManyObjects);
+            }
+        }
+        if self.memory_properties.memory_heaps[heap_index] - state.heap_usage[heap_index] < size {
+            return Err(OutOfMemory::OutOfDeviceMemory.into());
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.allocations += 1;
+        state.heap_usage[heap_index] += size;
+        state.objects.insert(
+            id,
+            MemoryObject {
+                memory_type,
+                size,
+                data: None,
+                mapping: None,
+            },
+        );
+        Ok(MockMemory {
+            id,
+            memory_type,
+            size,
+        })
+    }
+
+    unsafe fn free_memory(&self, memory: MockMemory) {
+        let heap_index = self.memory_properties.memory_types[memory.memory_type.0].heap_index;
+        let mut state = self.state();
+        state
+            .objects
+            .remove(&memory.id)
+            .unwrap_or_else(|| panic!("Memory {} freed twice", memory.id));
+        state.heap_usage[heap_index] -= memory.size;
+    }
+
+    unsafe fn map_memory<R>(&self, memory: &MockMemory, range: R) -> Result<*mut u8, mapping::Error>
+    where
+        R: RangeArg<u64>,
+    {
+        let properties = self.memory_properties.memory_types[memory.memory_type.0].properties;
+        if !properties.contains(Properties::CPU_VISIBLE) {
+            return Err(mapping::Error::InvalidAccess);
+        }
+        let mut state = self.state();
+        let object = state.objects.get_mut(&memory.id).unwrap();
+        let range = resolve_range(&range, 0..object.size);
+        if range.start > range.end || range.end > object.size {
+            return Err(mapping::Error::OutOfBounds);
+        }
+        assert!(
+            object.mapping.is_none(),
+            "Memory {} is already mapped",
+            memory.id
+        );
+        let size = object.size as usize;
+        let data = object
+            .data
+            .get_or_insert_with(|| HostData(vec![0; size].into_boxed_slice()));
+        object.mapping = Some(range.clone());
+        Ok(data.0.as_mut_ptr().add(range.start as usize))
+    }
+
+    unsafe fn flush_mapped_memory_ranges<'a, I, R>(&self, ranges: I) -> Result<(), OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<(&'a MockMemory, R)>,
+        R: RangeArg<u64>,
+    {
+        self.check_flush_ranges("Flush", ranges);
+        Ok(())
+    }
+
+    unsafe fn invalidate_mapped_memory_ranges<'a, I, R>(&self, ranges: I) -> Result<(), OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<(&'a MockMemory, R)>,
+        R: RangeArg<u64>,
+    {
+        self.check_flush_ranges("Invalidation", ranges);
+        Ok(())
+    }
+
+    unsafe fn unmap_memory(&self, memory: &MockMemory) {
+        let mut state = self.state();
+        let object = state.objects.get_mut(&memory.id).unwrap();
+        assert!(
+            object.mapping.take().is_some(),
+            "Memory {} is not mapped",
+            memory.id
+        );
+    }
+
+    unsafe fn create_buffer(
+        &self,
+        size: u64,
+        _: buffer::Usage,
+    ) -> Result<MockBuffer, buffer::CreationError> {
+        Ok(MockBuffer {
+            requirements: Requirements {
+                size,
+                alignment: MOCK_BUFFER_ALIGNMENT,
+                type_mask: self.type_mask(),
+            },
+            binding: None,
+        })
+    }
+
+    unsafe fn get_buffer_requirements(&self, buf: &MockBuffer) -> Requirements {
+        buf.requirements
+    }
+
+    unsafe fn bind_buffer_memory(
+        &self,
+        memory: &MockMemory,
+        offset: u64,
+        buf: &mut MockBuffer,
+    ) -> Result<(), BindError> {
+        buf.binding = Some(self.bind(memory, offset, buf.requirements)?);
+        Ok(())
+    }
+
+    unsafe fn destroy_buffer(&self, _: MockBuffer) {}
+
+    unsafe fn create_image(
+        &self,
+        kind: image::Kind,
+        mip_levels: image::Level,
+        format: Format,
+        _: image::Tiling,
+        _: image::Usage,
+        _: image::ViewCapabilities,
+    ) -> Result<MockImage, image::CreationError> {
+        Ok(MockImage {
+            requirements: Requirements {
+                size: image_size(kind, mip_levels, format),
+                alignment: MOCK_IMAGE_ALIGNMENT,
+                type_mask: self.type_mask(),
+            },
+            binding: None,
+        })
+    }
+
+    unsafe fn get_image_requirements(&self, image: &MockImage) -> Requirements {
+        image.requirements
+    }
+
+    unsafe fn bind_image_memory(
+        &self,
+        memory: &MockMemory,
+        offset: u64,
+        image: &mut MockImage,
+    ) -> Result<(), BindError> {
+        image.binding = Some(self.bind(memory, offset, image.requirements)?);
+        Ok(())
+    }
+
+    unsafe fn destroy_image(&self, _: MockImage) {}
+
+    fn wait_idle(&self) -> Result<(), HostExecutionError> {
+        Ok(())
+    }
+
+    unsafe fn create_command_pool(
+        &self,
+        _: QueueFamilyId,
+        _: CommandPoolCreateFlags,
+    ) -> Result<MockCommandPool, OutOfMemory> {
+        unsupported()
+    }
+    unsafe fn destroy_command_pool(&self, _: MockCommandPool) {
+        unsupported()
+    }
+    unsafe fn create_render_pass<'a, IA, IS, ID>(
+        &self,
+        _: IA,
+        _: IS,
+        _: ID,
+    ) -> Result<Unsupported, OutOfMemory>
+    where
+        IA: IntoIterator,
+        IA::Item: Borrow<pass::Attachment>,
+        IS: IntoIterator,
+        IS::Item: Borrow<pass::SubpassDesc<'a>>,
+        ID: IntoIterator,
+        ID::Item: Borrow<pass::SubpassDependency>,
+    {
+        unsupported()
+    }
+    unsafe fn destroy_render_pass(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_pipeline_layout<IS, IR>(
+        &self,
+        _: IS,
+        _: IR,
+    ) -> Result<Unsupported, OutOfMemory>
+    where
+        IS: IntoIterator,
+        IS::Item: Borrow<Unsupported>,
+        IR: IntoIterator,
+        IR::Item: Borrow<(pso::ShaderStageFlags, Range<u32>)>,
+    {
+        unsupported()
+    }
+    unsafe fn destroy_pipeline_layout(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_pipeline_cache(&self, _: Option<&[u8]>) -> Result<Unsupported, OutOfMemory> {
+        unsupported()
+    }
+    unsafe fn get_pipeline_cache_data(&self, _: &Unsupported) -> Result<Vec<u8>, OutOfMemory> {
+        unsupported()
+    }
+    unsafe fn merge_pipeline_caches<I>(&self, _: &Unsupported, _: I) -> Result<(), OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Unsupported>,
+    {
+        unsupported()
+    }
+    unsafe fn destroy_pipeline_cache(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn destroy_graphics_pipeline(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn destroy_compute_pipeline(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_framebuffer<I>(
+        &self,
+        _: &Unsupported,
+        _: I,
+        _: image::Extent,
+    ) -> Result<Unsupported, OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Unsupported>,
+    {
+        unsupported()
+    }
+    unsafe fn destroy_framebuffer(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_shader_module(&self, _: &[u8]) -> Result<Unsupported, ShaderError> {
+        unsupported()
+    }
+    unsafe fn destroy_shader_module(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_buffer_view<R: RangeArg<u64>>(
+        &self,
+        _: &MockBuffer,
+        _: Option<format::Format>,
+        _: R,
+    ) -> Result<Unsupported, buffer::ViewCreationError> {
+        unsupported()
+    }
+    unsafe fn destroy_buffer_view(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn get_image_subresource_footprint(
+        &self,
+        _: &MockImage,
+        _: image::Subresource,
+    ) -> image::SubresourceFootprint {
+        unsupported()
+    }
+    unsafe fn create_image_view(
+        &self,
+        _: &MockImage,
+        _: image::ViewKind,
+        _: format::Format,
+        _: format::Swizzle,
+        _: image::SubresourceRange,
+    ) -> Result<Unsupported, image::ViewError> {
+        unsupported()
+    }
+    unsafe fn destroy_image_view(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_sampler(&self, _: image::SamplerInfo) -> Result<Unsupported, AllocationError> {
+        unsupported()
+    }
+    unsafe fn destroy_sampler(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_descriptor_pool<I>(
+        &self,
+        _: usize,
+        _: I,
+        _: DescriptorPoolCreateFlags,
+    ) -> Result<MockDescriptorPool, OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<pso::DescriptorRangeDesc>,
+    {
+        unsupported()
+    }
+    unsafe fn destroy_descriptor_pool(&self, _: MockDescriptorPool) {
+        unsupported()
+    }
+    unsafe fn create_descriptor_set_layout<I, J>(
+        &self,
+        _: I,
+        _: J,
+    ) -> Result<Unsupported, OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<pso::DescriptorSetLayoutBinding>,
+        J: IntoIterator,
+        J::Item: Borrow<Unsupported>,
+    {
+        unsupported()
+    }
+    unsafe fn destroy_descriptor_set_layout(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn write_descriptor_sets<'a, I, J>(&self, _: I)
+    where
+        I: IntoIterator<Item = pso::DescriptorSetWrite<'a, MockBackend, J>>,
+        J: IntoIterator,
+        J::Item: Borrow<pso::Descriptor<'a, MockBackend>>,
+    {
+        unsupported()
+    }
+    unsafe fn copy_descriptor_sets<'a, I>(&self, _: I)
+    where
+        I: IntoIterator,
+        I::Item: Borrow<pso::DescriptorSetCopy<'a, MockBackend>>,
+    {
+        unsupported()
+    }
+    fn create_semaphore(&self) -> Result<Unsupported, OutOfMemory> {
+        unsupported()
+    }
+    unsafe fn destroy_semaphore(&self, _: Unsupported) {
+        unsupported()
+    }
+    fn create_fence(&self, _: bool) -> Result<Unsupported, OutOfMemory> {
+        unsupported()
+    }
+    unsafe fn get_fence_status(&self, _: &Unsupported) -> Result<bool, DeviceLost> {
+        unsupported()
+    }
+    unsafe fn destroy_fence(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn create_query_pool(
+        &self,
+        _: query::Type,
+        _: query::Id,
+    ) -> Result<Unsupported, query::CreationError> {
+        unsupported()
+    }
+    unsafe fn destroy_query_pool(&self, _: Unsupported) {
+        unsupported()
+    }
+    unsafe fn get_query_pool_results(
+        &self,
+        _: &Unsupported,
+        _: Range<query::Id>,
+        _: &mut [u8],
+        _: buffer::Offset,
+        _: query::ResultFlags,
+    ) -> Result<bool, OomOrDeviceLost> {
+        unsupported()
+    }
+    unsafe fn create_swapchain(
+        &self,
+        _: &mut MockSurface,
+        _: SwapchainConfig,
+        _: Option<MockSwapchain>,
+    ) -> Result<(MockSwapchain, Vec<MockImage>), window::CreationError> {
+        unsupported()
+    }
+    unsafe fn destroy_swapchain(&self, _: MockSwapchain) {
+        unsupported()
+    }
+}
+
+impl PhysicalDevice<MockBackend> for MockPhysicalDevice {
+    unsafe fn open(
+        &self,
+        _: &[(&MockQueueFamily, &[QueuePriority])],
+        _: Features,
+    ) -> Result<Gpu<MockBackend>, DeviceCreationError> {
+        match *self {}
+    }
+    fn format_properties(&self, _: Option<format::Format>) -> format::Properties {
+        match *self {}
+    }
+    fn image_format_properties(
+        &self,
+        _: format::Format,
+        _: u8,
+        _: image::Tiling,
+        _: image::Usage,
+        _: image::ViewCapabilities,
+    ) -> Option<image::FormatProperties> {
+        match *self {}
+    }
+    fn memory_properties(&self) -> MemoryProperties {
+        match *self {}
+    }
+    fn features(&self) -> Features {
+        match *self {}
+    }
+    fn limits(&self) -> Limits {
+        match *self {}
+    }
+}
+
+impl Surface<MockBackend> for MockSurface {
+    fn kind(&self) -> image::Kind {
+        match *self {}
+    }
+    fn supports_queue_family(&self, _: &MockQueueFamily) -> bool {
+        match *self {}
+    }
+    fn compatibility(
+        &self,
+        _: &MockPhysicalDevice,
+    ) -> (SurfaceCapabilities, Option<Vec<Format>>, Vec<PresentMode>) {
+        match *self {}
+    }
+}
+
+impl Swapchain<MockBackend> for MockSwapchain {
+    unsafe fn acquire_image(
+        &mut self,
+        _: u64,
+        _: Option<&Unsupported>,
+        _: Option<&Unsupported>,
+    ) -> Result<(SwapImageIndex, Option<Suboptimal>), AcquireError> {
+        match *self {}
+    }
+}
+
+impl QueueFamily for MockQueueFamily {
+    fn queue_type(&self) -> QueueType {
+        match *self {}
+    }
+    fn max_queues(&self) -> usize {
+        match *self {}
+    }
+    fn id(&self) -> QueueFamilyId {
+        match *self {}
+    }
+}
+
+impl RawCommandQueue<MockBackend> for MockCommandQueue {
+    unsafe fn submit<'a, T, Ic, S, Iw, Is>(
+        &mut self,
+        _: Submission<Ic, Iw, Is>,
+        _: Option<&Unsupported>,
+    ) where
+        T: 'a + Borrow<MockCommandBuffer>,
+        Ic: IntoIterator<Item = &'a T>,
+        S: 'a + Borrow<Unsupported>,
+        Iw: IntoIterator<Item = (&'a S, pso::PipelineStage)>,
+        Is: IntoIterator<Item = &'a S>,
+    {
+        match *self {}
+    }
+    unsafe fn present<'a, W, Is, S, Iw>(
+        &mut self,
+        _: Is,
+        _: Iw,
+    ) -> Result<Option<Suboptimal>, PresentError>
+    where
+        Self: Sized,
+        W: 'a + Borrow<MockSwapchain>,
+        Is: IntoIterator<Item = (&'a W, SwapImageIndex)>,
+        S: 'a + Borrow<Unsupported>,
+        Iw: IntoIterator<Item = &'a S>,
+    {
+        match *self {}
+    }
+    fn wait_idle(&self) -> Result<(), HostExecutionError> {
+        match *self {}
+    }
+}
+
+impl command::RawCommandBuffer<MockBackend> for MockCommandBuffer {
+    unsafe fn begin(
+        &mut self,
+        _: CommandBufferFlags,
+        _: CommandBufferInheritanceInfo<MockBackend>,
+    ) {
+        match *self {}
+    }
+    unsafe fn finish(&mut self) {
+        match *self {}
+    }
+    unsafe fn reset(&mut self, _: bool) {
+        match *self {}
+    }
+    unsafe fn pipeline_barrier<'a, T>(
+        &mut self,
+        _: Range<pso::PipelineStage>,
+        _: Dependencies,
+        _: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<Barrier<'a, MockBackend>>,
+    {
+        match *self {}
+    }
+    unsafe fn fill_buffer<R>(&mut self, _: &MockBuffer, _: R, _: u32)
+    where
+        R: RangeArg<buffer::Offset>,
+    {
+        match *self {}
+    }
+    unsafe fn update_buffer(&mut self, _: &MockBuffer, _: buffer::Offset, _: &[u8]) {
+        match *self {}
+    }
+    unsafe fn clear_image<T>(
+        &mut self,
+        _: &MockImage,
+        _: Layout,
+        _: ClearColorRaw,
+        _: ClearDepthStencilRaw,
+        _: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<SubresourceRange>,
+    {
+        match *self {}
+    }
+    unsafe fn clear_attachments<T, U>(&mut self, _: T, _: U)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<AttachmentClear>,
+        U: IntoIterator,
+        U::Item: Borrow<pso::ClearRect>,
+    {
+        match *self {}
+    }
+    unsafe fn resolve_image<T>(&mut self, _: &MockImage, _: Layout, _: &MockImage, _: Layout, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<ImageResolve>,
+    {
+        match *self {}
+    }
+    unsafe fn blit_image<T>(
+        &mut self,
+        _: &MockImage,
+        _: Layout,
+        _: &MockImage,
+        _: Layout,
+        _: Filter,
+        _: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<ImageBlit>,
+    {
+        match *self {}
+    }
+    unsafe fn bind_index_buffer(&mut self, _: buffer::IndexBufferView<MockBackend>) {
+        match *self {}
+    }
+    unsafe fn bind_vertex_buffers<I, T>(&mut self, _: pso::BufferIndex, _: I)
+    where
+        I: IntoIterator<Item = (T, buffer::Offset)>,
+        T: Borrow<MockBuffer>,
+    {
+        match *self {}
+    }
+    unsafe fn set_viewports<T>(&mut self, _: u32, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<pso::Viewport>,
+    {
+        match *self {}
+    }
+    unsafe fn set_scissors<T>(&mut self, _: u32, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<pso::Rect>,
+    {
+        match *self {}
+    }
+    unsafe fn set_stencil_reference(&mut self, _: pso::Face, _: pso::StencilValue) {
+        match *self {}
+    }
+    unsafe fn set_stencil_read_mask(&mut self, _: pso::Face, _: pso::StencilValue) {
+        match *self {}
+    }
+    unsafe fn set_stencil_write_mask(&mut self, _: pso::Face, _: pso::StencilValue) {
+        match *self {}
+    }
+    unsafe fn set_blend_constants(&mut self, _: pso::ColorValue) {
+        match *self {}
+    }
+    unsafe fn set_depth_bounds(&mut self, _: Range<f32>) {
+        match *self {}
+    }
+    unsafe fn set_line_width(&mut self, _: f32) {
+        match *self {}
+    }
+    unsafe fn set_depth_bias(&mut self, _: pso::DepthBias) {
+        match *self {}
+    }
+    unsafe fn begin_render_pass<T>(
+        &mut self,
+        _: &Unsupported,
+        _: &Unsupported,
+        _: pso::Rect,
+        _: T,
+        _: SubpassContents,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<ClearValueRaw>,
+    {
+        match *self {}
+    }
+    unsafe fn next_subpass(&mut self, _: SubpassContents) {
+        match *self {}
+    }
+    unsafe fn end_render_pass(&mut self) {
+        match *self {}
+    }
+    unsafe fn bind_graphics_pipeline(&mut self, _: &Unsupported) {
+        match *self {}
+    }
+    unsafe fn bind_graphics_descriptor_sets<I, J>(&mut self, _: &Unsupported, _: usize, _: I, _: J)
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Unsupported>,
+        J: IntoIterator,
+        J::Item: Borrow<DescriptorSetOffset>,
+    {
+        match *self {}
+    }
+    unsafe fn bind_compute_pipeline(&mut self, _: &Unsupported) {
+        match *self {}
+    }
+    unsafe fn bind_compute_descriptor_sets<I, J>(&mut self, _: &Unsupported, _: usize, _: I, _: J)
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Unsupported>,
+        J: IntoIterator,
+        J::Item: Borrow<DescriptorSetOffset>,
+    {
+        match *self {}
+    }
+    unsafe fn dispatch(&mut self, _: WorkGroupCount) {
+        match *self {}
+    }
+    unsafe fn dispatch_indirect(&mut self, _: &MockBuffer, _: buffer::Offset) {
+        match *self {}
+    }
+    unsafe fn copy_buffer<T>(&mut self, _: &MockBuffer, _: &MockBuffer, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<BufferCopy>,
+    {
+        match *self {}
+    }
+    unsafe fn copy_image<T>(&mut self, _: &MockImage, _: Layout, _: &MockImage, _: Layout, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<ImageCopy>,
+    {
+        match *self {}
+    }
+    unsafe fn copy_buffer_to_image<T>(&mut self, _: &MockBuffer, _: &MockImage, _: Layout, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<BufferImageCopy>,
+    {
+        match *self {}
+    }
+    unsafe fn copy_image_to_buffer<T>(&mut self, _: &MockImage, _: Layout, _: &MockBuffer, _: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<BufferImageCopy>,
+    {
+        match *self {}
+    }
+    unsafe fn draw(&mut self, _: Range<VertexCount>, _: Range<InstanceCount>) {
+        match *self {}
+    }
+    unsafe fn draw_indexed(
+        &mut self,
+        _: Range<IndexCount>,
+        _: VertexOffset,
+        _: Range<InstanceCount>,
+    ) {
+        match *self {}
+    }
+    unsafe fn draw_indirect(&mut self, _: &MockBuffer, _: buffer::Offset, _: DrawCount, _: u32) {
+        match *self {}
+    }
+    unsafe fn draw_indexed_indirect(
+        &mut self,
+        _: &MockBuffer,
+        _: buffer::Offset,
+        _: DrawCount,
+        _: u32,
+    ) {
+        match *self {}
+    }
+    unsafe fn begin_query(&mut self, _: query::Query<MockBackend>, _: query::ControlFlags) {
+        match *self {}
+    }
+    unsafe fn end_query(&mut self, _: query::Query<MockBackend>) {
+        match *self {}
+    }
+    unsafe fn reset_query_pool(&mut self, _: &Unsupported, _: Range<query::Id>) {
+        match *self {}
+    }
+    unsafe fn copy_query_pool_results(
+        &mut self,
+        _: &Unsupported,
+        _: Range<query::Id>,
+        _: &MockBuffer,
+        _: buffer::Offset,
+        _: buffer::Offset,
+        _: query::ResultFlags,
+    ) {
+        match *self {}
+    }
+    unsafe fn write_timestamp(&mut self, _: pso::PipelineStage, _: query::Query<MockBackend>) {
+        match *self {}
+    }
+    unsafe fn push_graphics_constants(
+        &mut self,
+        _: &Unsupported,
+        _: pso::ShaderStageFlags,
+        _: u32,
+        _: &[u32],
+    ) {
+        match *self {}
+    }
+    unsafe fn push_compute_constants(&mut self, _: &Unsupported, _: u32, _: &[u32]) {
+        match *self {}
+    }
+    unsafe fn execute_commands<'a, T, I>(&mut self, _: I)
+    where
+        T: 'a + Borrow<MockCommandBuffer>,
+        I: IntoIterator<Item = &'a T>,
+    {
+        match *self {}
+    }
+}
+
+impl RawCommandPool<MockBackend> for MockCommandPool {
+    unsafe fn reset(&mut self) {
+        match *self {}
+    }
+    unsafe fn free<I>(&mut self, _: I)
+    where
+        I: IntoIterator<Item = MockCommandBuffer>,
+    {
+        match *self {}
+    }
+}
+
+impl DescriptorPool<MockBackend> for MockDescriptorPool {
+    unsafe fn free_sets<I>(&mut self, _: I)
+    where
+        I: IntoIterator<Item = Unsupported>,
+    {
+        match *self {}
+    }
+    unsafe fn reset(&mut self) {
+        match *self {}
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn test_send_sync() {
+    fn foo<T: Send + Sync>() {}
+    fn bar() {
+        foo::<MockDevice>()
+    }
+}
+
+#[test]
+fn test_leak_detection() {
+    let device = MockDevice::default();
+    unsafe {
+        let memory = device.allocate_memory(MemoryTypeId(0), 1024).unwrap();
+        assert_eq!(
+            device.leaks(),
+            vec![LeakedMemory {
+                id: memory.id(),
+                memory_type: MemoryTypeId(0),
+                size: 1024,
+            }]
+        );
+        assert_eq!(device.heap_usage(0), 1024);
+        device.free_memory(memory);
+    }
+    assert!(device.leaks().is_empty());
+    assert_eq!(device.heap_usage(0), 0);
+}
+
+#[test]
+fn test_failure_injection() {
+    let device = MockDevice::default();
+    unsafe {
+        device.inject_failure(AllocationError::OutOfMemory(OutOfMemory::OutOfHostMemory));
+        match device.allocate_memory(MemoryTypeId(0), 1024) {
+            Err(AllocationError::OutOfMemory(OutOfMemory::OutOfHostMemory)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        device.set_max_objects(Some(1));
+        let memory = device.allocate_memory(MemoryTypeId(0), 1024).unwrap();
+        match device.allocate_memory(MemoryTypeId(0), 1024) {
+            Err(AllocationError::TooManyObjects) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        device.set_max_objects(None);
+
+        match device.allocate_memory(MemoryTypeId(1), 512 << 20) {
+            Err(AllocationError::OutOfMemory(OutOfMemory::OutOfDeviceMemory)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        device.free_memory(memory);
+    }
+    assert_eq!(device.allocation_count(), 1);
+}
+
+#[test]
+fn test_mapping() {
+    let device = MockDevice::default();
+    unsafe {
+        let memory = device.allocate_memory(MemoryTypeId(2), 1024).unwrap();
+        let ptr = device.map_memory(&memory, 256..768).unwrap();
+        *ptr = 42;
+        device
+            .flush_mapped_memory_ranges(Some((&memory, 256..512)))
+            .unwrap();
+        device.unmap_memory(&memory);
+        assert_eq!(*device.map_memory(&memory, ..).unwrap().add(256), 42);
+        device.unmap_memory(&memory);
+
+        let local = device.allocate_memory(MemoryTypeId(0), 1024).unwrap();
+        match device.map_memory(&local, ..) {
+            Err(mapping::Error::InvalidAccess) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        device.free_memory(local);
+        device.free_memory(memory);
+    }
+}
+
+#[test]
+#[should_panic(expected = "not aligned to non-coherent atom size")]
+fn test_unaligned_flush() {
+    let device = MockDevice::default();
+    unsafe {
+        let memory = device.allocate_memory(MemoryTypeId(2), 1024).unwrap();
+        device.map_memory(&memory, ..).unwrap();
+        let _ = device.flush_mapped_memory_ranges(Some((&memory, 100..200)));
+    }
+}
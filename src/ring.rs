@@ -0,0 +1,599 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::ops::Range;
+
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
+use gfx_hal::memory::Requirements;
+use gfx_hal::{Backend, MemoryTypeId};
+
+use block::{Block, RawBlock};
+#[cfg(feature = "checks")]
+use check_free;
+use {
+    alignment_shift, BlockError, MappingAllocator, MappingSubAllocator, MemoryAllocator,
+    MemoryError, MemorySubAllocator,
+};
+
+/// Controls what `RingAllocator` does when no chunk has room for an allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RingFull {
+    /// Fail the allocation with `MemoryError::OutOfMemory`.
+    Error,
+
+    /// Allocate another chunk from the underlying allocator.
+    Grow,
+}
+
+/// Memory of a chunk allocated during one epoch.
+#[derive(Debug)]
+struct RingFrame {
+    epoch: u64,
+    /// Offset of the first block of the frame, relative to the chunk
+    start: u64,
+}
+
+/// Chunk allocated from super-allocator
+#[derive(Debug)]
+struct RingChunk<T> {
+    /// Block from super-allocator
+    block: T,
+    /// Offset where the next block is allocated, relative to the chunk
+    head: u64,
+    /// Frames with memory in the chunk, from the oldest.
+    frames: VecDeque<RingFrame>,
+    /// Number of blocks of the chunk not freed yet, retired or not
+    blocks: usize,
+}
+
+impl<T> RingChunk<T>
+where
+    T: Block,
+{
+    /// Offset of the oldest memory still in use.
+    fn tail(&self) -> Option<u64> {
+        self.frames.front().map(|frame| frame.start)
+    }
+
+    /// Size of memory between tail and head, including padding.
+    fn used(&self) -> u64 {
+        match self.tail() {
+            None => 0,
+            Some(tail) if self.head > tail => self.head - tail,
+            Some(tail) => self.block.size() - tail + self.head,
+        }
+    }
+
+    /// Find offset of a block satisfying `reqs`, wrapping around to the start of the chunk if
+    /// there is no room before its end.
+    fn fit(&self, reqs: Requirements) -> Option<u64> {
+        let start = self.block.range().start;
+        let size = self.block.size();
+        let fits = |offset: u64, end: u64| {
+            let offset = offset + alignment_shift(reqs.alignment, start + offset);
+            if offset + reqs.size <= end {
+                Some(offset)
+            } else {
+                None
+            }
+        };
+        match self.tail() {
+            None => fits(0, size),
+            // Free memory is after the head and before the tail
+            Some(tail) if self.head > tail => fits(self.head, size).or_else(|| fits(0, tail)),
+            // The ring has wrapped, free memory is between the head and the tail
+            Some(tail) => fits(self.head, tail),
+        }
+    }
+}
+
+/// Sub-allocator that can be used for transient data of a frame, such as uniforms and staging
+/// data uploaded each frame.
+///
+/// This allocator allocates blocks linearly from fixed size chunks, wrapping around to the start
+/// of the chunk when its end is reached. Memory is not reclaimed when blocks are freed, but when
+/// the epoch (e.g. frame index) the blocks were allocated during is retired, once the device is
+/// done with it. This way the same chunks are reused frame after frame.
+///
+/// The first chunk is allocated on the first allocation. When no chunk has room for a block, the
+/// allocator either fails or allocates another chunk, depending on `RingFull` policy. Chunks are
+/// kept until `trim` is called, and until all their blocks are freed.
+///
+/// ### Type parameters:
+///
+/// - `T`: type of blocks this allocator sub-allocates from.
+#[derive(Debug)]
+pub struct RingAllocator<T> {
+    id: MemoryTypeId,
+    chunk_size: u64,
+    full: RingFull,
+    epoch: u64,
+    blocks: usize,
+    /// Chunk blocks are allocated from
+    current: usize,
+    /// Slots of released chunks are reused.
+    chunks: Vec<Option<RingChunk<T>>>,
+}
+
+impl<T> RingAllocator<T> {
+    /// Create a new ring allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `id`: ID of the memory type this allocator allocates from.
+    /// - `chunk_size`: The minimum size of the chunks allocated from the underlying allocator
+    ///   in bytes. Bigger chunks are allocated for blocks that don't fit.
+    /// - `full`: what to do when all chunks are full.
+    pub fn new(id: MemoryTypeId, chunk_size: u64, full: RingFull) -> Self {
+        assert_ne!(chunk_size, 0);
+        RingAllocator {
+            id,
+            chunk_size,
+            full,
+            epoch: 0,
+            blocks: 0,
+            current: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Check if any of the blocks allocated by this allocator are still in use.
+    /// If this function returns `false`, the allocator can be `dispose`d.
+    pub fn is_used(&self) -> bool {
+        self.blocks != 0
+    }
+
+    /// Get memory type of the allocator
+    pub fn memory_type(&self) -> MemoryTypeId {
+        self.id
+    }
+
+    /// Get the minimum size of each chunk in bytes
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /// Get the policy used when all chunks are full.
+    pub fn full(&self) -> RingFull {
+        self.full
+    }
+
+    /// Get the epoch blocks are currently allocated during.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Start allocating blocks during the next epoch.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `epoch`: the new epoch, usually the index of the frame being recorded.
+    ///   Must not be less than the current one.
+    pub fn begin_epoch(&mut self, epoch: u64) {
+        assert!(epoch >= self.epoch, "Epochs must not decrease");
+        self.epoch = epoch;
+    }
+
+    /// Reclaim memory of blocks allocated during epochs up to `completed`.
+    /// The blocks must not be used by the device anymore, but don't need to be freed yet.
+    pub fn retire(&mut self, completed: u64) {
+        for chunk in self.chunks.iter_mut().flatten() {
+            while chunk
+                .frames
+                .front()
+                .map(|frame| frame.epoch <= completed)
+                .unwrap_or(false)
+            {
+                chunk.frames.pop_front();
+            }
+            if chunk.frames.is_empty() {
+                // Restart from the beginning to avoid wrapping
+                chunk.head = 0;
+            }
+        }
+    }
+
+    /// Retrieves the block backing an allocation.
+    pub fn underlying_block<M>(&self, block: &RingBlock<M>) -> &T {
+        &self.chunks[block.1]
+            .as_ref()
+            .expect("Chunk was released")
+            .block
+    }
+
+    /// Check that the block can be freed by this allocator.
+    /// Freeing a block twice can't be detected, as memory is reclaimed by epochs rather than
+    /// per block.
+    pub fn validate<M>(&self, block: &RingBlock<M>) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        match self.chunks.get(block.1) {
+            Some(Some(chunk)) if chunk.block.contains(block) => Ok(()),
+            Some(None) => Err(BlockError::ReleasedChunk),
+            _ => Err(BlockError::ForeignBlock),
+        }
+    }
+
+    /// Get the total size of memory not retired yet, including padding.
+    pub fn used(&self) -> u64
+    where
+        T: Block,
+    {
+        self.chunks.iter().flatten().map(RingChunk::used).sum()
+    }
+
+    /// Get the total size of all chunks allocated by this allocator.
+    pub fn allocated(&self) -> u64
+    where
+        T: Block,
+    {
+        self.chunks
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.block.size())
+            .sum()
+    }
+
+    /// Return all chunks without memory in use to the underlying allocator.
+    /// Chunks with blocks not freed yet are kept even if their memory is retired, as is the
+    /// chunk blocks are currently allocated from.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the chunks
+    /// - `device`: same device that was used to allocate the chunks
+    ///
+    /// ### Safety
+    ///
+    /// `owner` and `device` must be the ones the chunks were allocated with.
+    pub unsafe fn trim<B, O>(&mut self, owner: &mut O, device: &B::Device)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        for (index, slot) in self.chunks.iter_mut().enumerate() {
+            let empty = slot
+                .as_ref()
+                .map(|chunk| chunk.frames.is_empty() && chunk.blocks == 0)
+                .unwrap_or(false);
+            if index != self.current && empty {
+                owner.free(device, slot.take().unwrap().block);
+            }
+        }
+    }
+
+    unsafe fn grow<B, O>(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        request: O::Request,
+        reqs: Requirements,
+    ) -> Result<usize, MemoryError>
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        let size = ((reqs.size - 1) / self.chunk_size + 1) * self.chunk_size;
+        let chunk_reqs = Requirements {
+            type_mask: 1 << self.id.0,
+            size,
+            alignment: reqs.alignment,
+        };
+        let block = owner.alloc(device, request, chunk_reqs)?;
+        assert!(block.size() >= size);
+        let chunk = RingChunk {
+            block,
+            head: 0,
+            frames: VecDeque::new(),
+            blocks: 0,
+        };
+
+        // Reuse slot of a released chunk or append a new one
+        match self.chunks.iter().position(Option::is_none) {
+            Some(index) => {
+                self.chunks[index] = Some(chunk);
+                Ok(index)
+            }
+            None => {
+                self.chunks.push(Some(chunk));
+                Ok(self.chunks.len() - 1)
+            }
+        }
+    }
+}
+
+impl<B, O, T> MemorySubAllocator<B, O> for RingAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MemoryAllocator<B, Block = T>,
+{
+    type Request = O::Request;
+    type Block = RingBlock<B::Memory>;
+
+    unsafe fn alloc(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        request: O::Request,
+        reqs: Requirements,
+    ) -> Result<RingBlock<B::Memory>, MemoryError> {
+        if (1 << self.id.0) & reqs.type_mask == 0 {
+            return Err(MemoryError::NoCompatibleMemoryType);
+        }
+        assert_ne!(reqs.size, 0);
+
+        // Try the current chunk first, then any other chunk with room
+        let current = self.current;
+        let fit = |index: usize, chunk: &Option<RingChunk<T>>| {
+            chunk
+                .as_ref()
+                .and_then(|chunk| chunk.fit(reqs))
+                .map(|offset| (index, offset))
+        };
+        let found = self
+            .chunks
+            .get(current)
+            .and_then(|chunk| fit(current, chunk))
+            .or_else(|| {
+                self.chunks
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, chunk)| fit(index, chunk))
+                    .next()
+            });
+        let (index, offset) = match found {
+            Some(found) => found,
+            // The first chunk is allocated regardless of the policy
+            None if self.full == RingFull::Error && self.chunks.iter().any(Option::is_some) => {
+                return Err(MemoryError::OutOfMemory);
+            }
+            None => {
+                let index = self.grow(owner, device, request, reqs)?;
+                fit(index, &self.chunks[index]).expect("New chunk fits the block")
+            }
+        };
+        self.current = index;
+
+        let epoch = self.epoch;
+        let chunk = self.chunks[index].as_mut().unwrap();
+        if chunk.frames.back().map(|frame| frame.epoch) != Some(epoch) {
+            chunk.frames.push_back(RingFrame {
+                epoch,
+                start: offset,
+            });
+        }
+        chunk.head = offset + reqs.size;
+        chunk.blocks += 1;
+        self.blocks += 1;
+
+        let start = chunk.block.range().start + offset;
+        Ok(RingBlock(
+            RawBlock::new(chunk.block.memory(), start..start + reqs.size),
+            index,
+        ))
+    }
+
+    unsafe fn free(&mut self, _owner: &mut O, _device: &B::Device, block: RingBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
+        {
+            let chunk = self.chunks[block.1]
+                .as_mut()
+                .expect("Block of released chunk");
+            assert!(chunk.block.contains(&block));
+            chunk.blocks -= 1;
+        }
+        block.0.dispose();
+        self.blocks -= 1;
+    }
+
+    unsafe fn dispose(mut self, owner: &mut O, device: &B::Device) -> Result<(), Self> {
+        if self.is_used() {
+            Err(self)
+        } else {
+            for chunk in self.chunks.drain(..).flatten() {
+                owner.free(device, chunk.block);
+            }
+            Ok(())
+        }
+    }
+}
+
+impl<B, O, T> MappingSubAllocator<B, O> for RingAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MappingAllocator<B, Block = T>,
+{
+    unsafe fn map(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &RingBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.map(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn unmap(&mut self, owner: &mut O, device: &B::Device, block: &RingBlock<B::Memory>) {
+        owner.unmap(device, self.underlying_block(block))
+    }
+
+    unsafe fn flush(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &RingBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.flush(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &RingBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.invalidate(device, chunk, offset + range.start..offset + range.end)
+    }
+}
+
+/// `Block` type returned by `RingAllocator`.
+#[derive(Debug)]
+pub struct RingBlock<M>(pub(crate) RawBlock<M>, pub(crate) usize);
+
+impl<M> Block for RingBlock<M>
+where
+    M: Debug + Any,
+{
+    type Memory = M;
+
+    #[inline(always)]
+    fn memory(&self) -> &M {
+        self.0.memory()
+    }
+
+    #[inline(always)]
+    fn range(&self) -> Range<u64> {
+        self.0.range()
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn test_send_sync() {
+    fn foo<T: Send + Sync>() {}
+    fn bar<M: Send + Sync>() {
+        foo::<RingAllocator<M>>()
+    }
+}
+
+#[test]
+fn test_wraparound() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut ring = RingAllocator::new(MemoryTypeId(0), 1024, RingFull::Error);
+    let reqs = |size| Requirements {
+        size,
+        alignment: 256,
+        type_mask: 1,
+    };
+    unsafe {
+        // Frame 0 takes the first half, frame 1 the rest.
+        let a = ring.alloc(&mut root, &device, (), reqs(500)).unwrap();
+        ring.begin_epoch(1);
+        let b = ring.alloc(&mut root, &device, (), reqs(400)).unwrap();
+        assert_eq!(b.range().start - a.range().start, 512);
+        match ring.alloc(&mut root, &device, (), reqs(200)) {
+            Err(MemoryError::OutOfMemory) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // Retiring frame 0 makes room at the start of the ring.
+        ring.retire(0);
+        ring.begin_epoch(2);
+        let c = ring.alloc(&mut root, &device, (), reqs(200)).unwrap();
+        assert_eq!(c.range().start, a.range().start);
+        assert_eq!(ring.used(), 1024 - 512 + 200);
+
+        ring.free(&mut root, &device, a);
+        ring.free(&mut root, &device, b);
+        ring.free(&mut root, &device, c);
+        ring.retire(2);
+        assert_eq!(ring.used(), 0);
+        assert_eq!(device.object_count(), 1);
+        ring.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_grow() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut ring = RingAllocator::new(MemoryTypeId(0), 1024, RingFull::Grow);
+    let reqs = |size| Requirements {
+        size,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        let a = ring.alloc(&mut root, &device, (), reqs(1000)).unwrap();
+        let b = ring.alloc(&mut root, &device, (), reqs(1000)).unwrap();
+        let c = ring.alloc(&mut root, &device, (), reqs(3000)).unwrap();
+        assert_eq!(device.object_count(), 3);
+        assert_eq!(ring.allocated(), 1024 + 1024 + 3072);
+        ring.free(&mut root, &device, a);
+        ring.free(&mut root, &device, b);
+        ring.free(&mut root, &device, c);
+
+        // Retired chunks are reused until trimmed.
+        ring.retire(0);
+        ring.begin_epoch(1);
+        let d = ring.alloc(&mut root, &device, (), reqs(1000)).unwrap();
+        assert_eq!(device.object_count(), 3);
+        ring.free(&mut root, &device, d);
+        ring.retire(1);
+        ring.trim(&mut root, &device);
+        assert_eq!(device.object_count(), 1);
+        ring.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_trim_keeps_unfreed_blocks() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut ring = RingAllocator::new(MemoryTypeId(0), 1024, RingFull::Grow);
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        let a = ring.alloc(&mut root, &device, (), reqs).unwrap();
+        let b = ring.alloc(&mut root, &device, (), reqs).unwrap();
+        assert_eq!(device.object_count(), 2);
+
+        // The chunk of `a` is retired and not current, but `a` is not freed yet.
+        ring.retire(0);
+        ring.trim(&mut root, &device);
+        assert_eq!(device.object_count(), 2);
+        ring.free(&mut root, &device, a);
+        ring.free(&mut root, &device, b);
+
+        ring.trim(&mut root, &device);
+        assert_eq!(device.object_count(), 1);
+        ring.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
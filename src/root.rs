@@ -1,14 +1,30 @@
+use std::cmp::min;
+use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
 
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
 use gfx_hal::memory::Requirements;
 use gfx_hal::{Backend, Device, MemoryTypeId};
 
 use block::{Block, RawBlock};
+#[cfg(feature = "checks")]
+use check_free;
 use relevant::Relevant;
-use {MemoryAllocator, MemoryError};
+use smart::HeapUsage;
+use {shift_for_alignment, BlockError, MappingAllocator, MemoryAllocator, MemoryError};
 
 /// Allocator that allocates memory directly from device.
 ///
+/// Keeps track of host mappings of the memory objects it allocates. Each memory object is mapped
+/// once as a whole and stays mapped while any block of it is mapped.
+///
+/// The size of memory objects can also be added to a counter shared with other allocators,
+/// which is how `SmartAllocator` keeps track of heap usage. Allocations that would exceed the
+/// budget of the heap then fail with `MemoryError::BudgetExceeded`.
+///
 /// ### Type parameters:
 ///
 /// - `B`: hal `Backend`
@@ -16,21 +32,60 @@ use {MemoryAllocator, MemoryError};
 pub struct RootAllocator<B> {
     relevant: Relevant,
     id: MemoryTypeId,
+    non_coherent_atom_size: u64,
     used: u64,
+    heap_usage: Option<Arc<HeapUsage>>,
+    #[cfg(feature = "dump")]
+    next_id: u64,
+    objects: HashMap<usize, MemoryObject>,
     pd: PhantomData<fn() -> B>,
 }
 
+/// Memory object allocated from the device.
+#[derive(Debug)]
+struct MemoryObject {
+    /// Number of the object in allocation order.
+    #[cfg(feature = "dump")]
+    id: u64,
+    size: u64,
+    mapping: Option<Mapping>,
+}
+
+/// Host mapping of a whole memory object.
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    count: usize,
+}
+
+unsafe impl Send for Mapping {}
+
+unsafe impl Sync for Mapping {}
+
+/// Key used to identify memory objects.
+pub(crate) fn memory_key<M>(memory: &M) -> usize {
+    memory as *const M as usize
+}
+
 impl<B> RootAllocator<B> {
     /// Create new allocator that will allocate memory of specified type.
     ///
     /// ### Parameters:
     ///
     /// - `id`: ID of the memory type this allocator allocates from.
-    pub fn new(id: MemoryTypeId) -> Self {
+    /// - `non_coherent_atom_size`: granularity of flush and invalidate operations on the memory,
+    ///   as reported by device `Limits`.
+    pub fn new(id: MemoryTypeId, non_coherent_atom_size: u64) -> Self {
+        assert_ne!(non_coherent_atom_size, 0);
         RootAllocator {
             relevant: Relevant,
             id,
+            non_coherent_atom_size,
             used: 0,
+            heap_usage: None,
+            #[cfg(feature = "dump")]
+            next_id: 0,
+            objects: HashMap::new(),
             pd: PhantomData,
         }
     }
@@ -40,10 +95,74 @@ impl<B> RootAllocator<B> {
         self.id
     }
 
+    /// Get granularity of flush and invalidate operations.
+    pub fn non_coherent_atom_size(&self) -> u64 {
+        self.non_coherent_atom_size
+    }
+
     /// Get the total size of all blocks allocated by this allocator.
     pub fn used(&self) -> u64 {
         self.used
     }
+
+    /// Add the size of memory objects allocated by this allocator to `usage`
+    /// until they are freed, including the ones allocated already.
+    /// New memory objects are only allocated if they fit the budget of `usage`.
+    pub(crate) fn set_heap_usage(&mut self, usage: Arc<HeapUsage>) {
+        usage.add(self.used);
+        if let Some(old) = self.heap_usage.replace(usage) {
+            old.release(self.used);
+        }
+    }
+
+    /// Get the number of memory objects allocated by this allocator.
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Iterate over keys, numbers in allocation order and sizes of all memory objects.
+    #[cfg(feature = "dump")]
+    pub(crate) fn objects(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        self.objects
+            .iter()
+            .map(|(&key, object)| (key as u64, object.id, object.size))
+    }
+
+    /// Check if the memory object of the block is currently mapped.
+    pub fn is_mapped<T: Block>(&self, block: &T) -> bool {
+        self.objects
+            .get(&memory_key(block.memory()))
+            .map(|object| object.mapping.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Check that the block can be freed by this allocator.
+    /// Blocks that were already freed are reported as foreign, as their memory objects are gone.
+    pub fn validate<T: Block>(&self, block: &T) -> Result<(), BlockError> {
+        let object = self
+            .objects
+            .get(&memory_key(block.memory()))
+            .ok_or(BlockError::ForeignBlock)?;
+        if block.range() != (0..object.size) {
+            return Err(BlockError::InvalidRange);
+        }
+        Ok(())
+    }
+
+    /// Expand range of the block to `non_coherent_atom_size` boundaries.
+    /// Resulting range is absolute and never exceeds the memory object.
+    fn atom_range<T: Block>(&self, block: &T, range: Range<u64>) -> Range<u64> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let object = &self.objects[&memory_key(block.memory())];
+        debug_assert!(
+            object.mapping.is_some(),
+            "Memory of the block is not mapped"
+        );
+        let atom = self.non_coherent_atom_size;
+        let start = block.range().start + range.start;
+        let end = block.range().start + range.end;
+        (start - start % atom)..min(shift_for_alignment(atom, end), object.size)
+    }
 }
 
 impl<B> MemoryAllocator<B> for RootAllocator<B>
@@ -59,18 +178,54 @@ where
         _: (),
         reqs: Requirements,
     ) -> Result<RawBlock<B::Memory>, MemoryError> {
-        let memory = device.allocate_memory(self.id, reqs.size)?;
+        if let Some(ref usage) = self.heap_usage {
+            usage.reserve(reqs.size)?;
+        }
+        let memory = match device.allocate_memory(self.id, reqs.size) {
+            Ok(memory) => memory,
+            Err(error) => {
+                if let Some(ref usage) = self.heap_usage {
+                    usage.release(reqs.size);
+                }
+                return Err(error.into());
+            }
+        };
         let memory = Box::into_raw(Box::new(memory)); // Suboptimal
+        self.objects.insert(
+            memory_key(&*memory),
+            MemoryObject {
+                #[cfg(feature = "dump")]
+                id: self.next_id,
+                size: reqs.size,
+                mapping: None,
+            },
+        );
+        #[cfg(feature = "dump")]
+        {
+            self.next_id += 1;
+        }
         self.used += reqs.size;
         Ok(RawBlock::new(memory, 0..reqs.size))
     }
 
     unsafe fn free(&mut self, device: &B::Device, block: RawBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
         let size = block.size();
         assert_eq!(block.range().start, 0);
+        let object = self
+            .objects
+            .remove(&memory_key(block.memory()))
+            .expect("Block was not allocated by this allocator");
+        if object.mapping.is_some() {
+            device.unmap_memory(block.memory());
+        }
         device.free_memory(*Box::from_raw(block.memory() as *const _ as *mut _));
         block.dispose();
         self.used -= size;
+        if let Some(ref usage) = self.heap_usage {
+            usage.release(size);
+        }
     }
 
     fn is_used(&self) -> bool {
@@ -87,6 +242,147 @@ where
     }
 }
 
+impl<B> MappingAllocator<B> for RootAllocator<B>
+where
+    B: Backend,
+{
+    unsafe fn map(
+        &mut self,
+        device: &B::Device,
+        block: &RawBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let object = self
+            .objects
+            .get_mut(&memory_key(block.memory()))
+            .expect("Block was not allocated by this allocator");
+        if object.mapping.is_none() {
+            // Map the whole memory object so that other blocks can share the mapping
+            let ptr = device.map_memory(block.memory(), ..)?;
+            object.mapping = Some(Mapping { ptr, count: 0 });
+        }
+        let mapping = object.mapping.as_mut().unwrap();
+        mapping.count += 1;
+        Ok(mapping
+            .ptr
+            .offset((block.range().start + range.start) as isize))
+    }
+
+    unsafe fn unmap(&mut self, device: &B::Device, block: &RawBlock<B::Memory>) {
+        let object = self
+            .objects
+            .get_mut(&memory_key(block.memory()))
+            .expect("Block was not allocated by this allocator");
+        let unused = {
+            let mapping = object
+                .mapping
+                .as_mut()
+                .expect("Memory of the block is not mapped");
+            mapping.count -= 1;
+            mapping.count == 0
+        };
+        if unused {
+            object.mapping = None;
+            device.unmap_memory(block.memory());
+        }
+    }
+
+    unsafe fn flush(
+        &mut self,
+        device: &B::Device,
+        block: &RawBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        let range = self.atom_range(block, range);
+        device.flush_mapped_memory_ranges(Some((block.memory(), range)))
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        device: &B::Device,
+        block: &RawBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        let range = self.atom_range(block, range);
+        device.invalidate_mapped_memory_ranges(Some((block.memory(), range)))
+    }
+}
+
+/// `RootAllocator` behind a lock, used as the owner of sub-allocators that are locked
+/// separately. The root is locked only for the duration of each call, so sub-allocators
+/// serving blocks from their existing chunks never contend on it.
+#[derive(Debug)]
+pub(crate) struct LockedRoot<'a, B: 'a>(pub(crate) &'a Mutex<RootAllocator<B>>);
+
+impl<'a, B> MemoryAllocator<B> for LockedRoot<'a, B>
+where
+    B: Backend,
+{
+    type Request = ();
+    type Block = RawBlock<B::Memory>;
+
+    unsafe fn alloc(
+        &mut self,
+        device: &B::Device,
+        request: (),
+        reqs: Requirements,
+    ) -> Result<RawBlock<B::Memory>, MemoryError> {
+        self.0.lock().unwrap().alloc(device, request, reqs)
+    }
+
+    unsafe fn free(&mut self, device: &B::Device, block: RawBlock<B::Memory>) {
+        self.0.lock().unwrap().free(device, block)
+    }
+
+    fn is_used(&self) -> bool {
+        self.0.lock().unwrap().is_used()
+    }
+
+    unsafe fn dispose(self, _: &B::Device) -> Result<(), Self> {
+        // The root allocator itself is disposed by the owner of the lock
+        Err(self)
+    }
+}
+
+impl<'a, B> MappingAllocator<B> for LockedRoot<'a, B>
+where
+    B: Backend,
+{
+    unsafe fn map(
+        &mut self,
+        device: &B::Device,
+        block: &RawBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        self.0.lock().unwrap().map(device, block, range)
+    }
+
+    unsafe fn unmap(&mut self, device: &B::Device, block: &RawBlock<B::Memory>) {
+        self.0.lock().unwrap().unmap(device, block)
+    }
+
+    unsafe fn flush(
+        &mut self,
+        device: &B::Device,
+        block: &RawBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        self.0.lock().unwrap().flush(device, block, range)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        device: &B::Device,
+        block: &RawBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        self.0.lock().unwrap().invalidate(device, block, range)
+    }
+}
+
 #[test]
 #[allow(dead_code)]
 fn test_send_sync() {
@@ -95,3 +391,34 @@ fn test_send_sync() {
         foo::<RootAllocator<B>>()
     }
 }
+
+#[test]
+fn test_mapping() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let mut allocator =
+        RootAllocator::<MockBackend>::new(MemoryTypeId(2), device.non_coherent_atom_size());
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 1,
+        type_mask: !0,
+    };
+    unsafe {
+        let block = allocator.alloc(&device, (), reqs).unwrap();
+        let ptr = allocator.map(&device, &block, 100..200).unwrap();
+        *ptr = 42;
+        // Expanded to atoms and clamped to the memory object.
+        allocator.flush(&device, &block, 100..1000).unwrap();
+        assert_eq!(
+            *allocator.map(&device, &block, 0..1000).unwrap().add(100),
+            42
+        );
+        allocator.unmap(&device, &block);
+        allocator.unmap(&device, &block);
+        assert!(!allocator.is_mapped(&block));
+        allocator.free(&device, block);
+        allocator.dispose(&device).unwrap();
+    }
+    assert!(device.leaks().is_empty());
+}
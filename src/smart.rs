@@ -1,29 +1,66 @@
 use std::any::Any;
-use std::fmt::Debug;
+use std::cmp::{min, Ordering as CmpOrdering};
+use std::fmt::{self, Debug};
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
 use gfx_hal::memory::{Properties, Requirements};
 use gfx_hal::{Backend, MemoryProperties, MemoryType, MemoryTypeId};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use block::Block;
-use combined::{CombinedAllocator, CombinedBlock, Type};
-use {MemoryAllocator, MemoryError};
+#[cfg(feature = "checks")]
+use check_free;
+#[cfg(feature = "checks")]
+use checks::LiveBlock;
+use combined::{CombinedAllocator, CombinedBlock, ResourceKind, Type};
+use config::{CombinedConfig, ConfigError, SmartConfig};
+use defrag::{DefragBudget, DefragMove};
+#[cfg(feature = "dump")]
+use dump::{property_names, AllocatorDump, MemoryTypeDump};
+use guard::CorruptedBlock;
+use stats::{MemoryTypeStats, SmartStats};
+use {BlockError, MappingAllocator, MemoryAllocator, MemoryError};
 
 /// Allocator that can choose memory type based on requirements, and keeps track of allocators
 /// for all given memory types.
 ///
-/// Allocates memory blocks from the least used memory type from those which satisfy requirements.
+/// Allocations are requested with `RequestedProperties`, usually made from a `MemoryUsage`.
+/// Memory types which satisfy requirements are ranked by how well they suit the request, and
+/// blocks are allocated from the best one with enough memory available, or the least used one
+/// among equally suited types. If the allocation fails, the next memory types are tried in order.
+/// Heaps can be given a `Budget` and usage reported by other allocators or processes, which
+/// are both respected when choosing memory type. Heap usage of the allocator is the size of the
+/// memory objects it allocated from the device, including unused space in chunks.
+/// Allocations bigger than the dedicated threshold are always allocated as dedicated memory
+/// objects, regardless of requested `Type`.
+///
+/// Besides the `&mut self` methods of `MemoryAllocator` and `MappingAllocator`, blocks can be
+/// allocated, freed and mapped through `&self`, so the allocator can be shared between threads
+/// without an outer lock. Only the sub-allocator of the chosen memory type that serves the block
+/// is locked, see `CombinedAllocator`, and heap usage is updated atomically.
 #[derive(Debug)]
 pub struct SmartAllocator<B: Backend> {
     allocators: Vec<(MemoryType, CombinedAllocator<B>)>,
     heaps: Vec<Heap>,
+    dedicated_threshold: Option<u64>,
+    spill: bool,
 }
 
+/// `SmartAllocator` shared between threads. Allocation and freeing take `&self` and lock only
+/// the sub-allocator that serves the block.
+pub type SharedSmartAllocator<B> = SmartAllocator<B>;
+
 impl<B> SmartAllocator<B>
 where
     B: Backend,
 {
-    /// Create a new smart allocator from `MemoryProperties` given by a device.
+    /// Create a new smart allocator from `MemoryProperties` given by a device, using the same
+    /// sizes for all memory types. See `SmartConfig::uniform`.
     ///
     /// ### Parameters:
     ///
@@ -32,14 +69,81 @@ where
     /// - `blocks_per_chunk`: see `ChunkedAllocator`
     /// - `min_block_size`: see `ChunkedAllocator`
     /// - `max_chunk_size`: see `ChunkedAllocator`
+    /// - `non_coherent_atom_size`: `non_coherent_atom_size` from device `Limits`. Only applied to
+    ///   memory types that are `CPU_VISIBLE` and not `COHERENT`.
+    /// - `buffer_image_granularity`: `buffer_image_granularity` from device `Limits`
     pub fn new(
         memory_properties: MemoryProperties,
         arena_chunk_size: u64,
         blocks_per_chunk: usize,
         min_block_size: u64,
         max_chunk_size: u64,
+        non_coherent_atom_size: u64,
+        buffer_image_granularity: u64,
     ) -> Self {
-        SmartAllocator {
+        let config = SmartConfig::uniform(
+            &memory_properties,
+            CombinedConfig::new(
+                arena_chunk_size,
+                blocks_per_chunk,
+                min_block_size,
+                max_chunk_size,
+            ),
+        );
+        SmartAllocator::with_config(
+            memory_properties,
+            &config,
+            non_coherent_atom_size,
+            buffer_image_granularity,
+        )
+    }
+
+    /// Create a new smart allocator from `MemoryProperties` given by a device and a
+    /// configuration of each memory type and heap.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `memory_properties`: memory properties describing the memory available on a device
+    /// - `config`: configuration created for the same `memory_properties`
+    /// - `non_coherent_atom_size`: `non_coherent_atom_size` from device `Limits`. Only applied to
+    ///   memory types that are `CPU_VISIBLE` and not `COHERENT`.
+    /// - `buffer_image_granularity`: `buffer_image_granularity` from device `Limits`
+    ///
+    /// ### Panics
+    ///
+    /// Panics if `config` is invalid, see `SmartConfig::validate`. Use `try_with_config` to
+    /// handle invalid configurations, e.g. ones loaded from a `Profile`.
+    pub fn with_config(
+        memory_properties: MemoryProperties,
+        config: &SmartConfig,
+        non_coherent_atom_size: u64,
+        buffer_image_granularity: u64,
+    ) -> Self {
+        SmartAllocator::try_with_config(
+            memory_properties,
+            config,
+            non_coherent_atom_size,
+            buffer_image_granularity,
+        )
+        .unwrap_or_else(|error| panic!("Invalid allocator configuration: {}", error))
+    }
+
+    /// Create a new smart allocator from `MemoryProperties` given by a device and a
+    /// configuration of each memory type and heap, failing if the configuration is invalid.
+    ///
+    /// See `with_config` for parameters.
+    ///
+    /// ### Returns
+    ///
+    /// The error found by `SmartConfig::validate` if `config` is invalid.
+    pub fn try_with_config(
+        memory_properties: MemoryProperties,
+        config: &SmartConfig,
+        non_coherent_atom_size: u64,
+        buffer_image_granularity: u64,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let mut allocator = SmartAllocator {
             allocators: memory_properties
                 .memory_types
                 .into_iter()
@@ -47,12 +151,11 @@ where
                 .map(|(index, memory_type)| {
                     (
                         memory_type,
-                        CombinedAllocator::new(
+                        CombinedAllocator::with_config(
                             MemoryTypeId(index),
-                            arena_chunk_size,
-                            blocks_per_chunk,
-                            min_block_size,
-                            max_chunk_size,
+                            *config.memory_type(index),
+                            atom_size(memory_type.properties, non_coherent_atom_size),
+                            buffer_image_granularity,
                         ),
                     )
                 })
@@ -60,9 +163,118 @@ where
             heaps: memory_properties
                 .memory_heaps
                 .into_iter()
-                .map(|size| Heap { size, used: 0 })
+                .map(Heap::new)
                 .collect(),
+            dedicated_threshold: config.dedicated_threshold(),
+            spill: config.spill(),
+        };
+        for (heap_index, heap) in allocator.heaps.iter_mut().enumerate() {
+            heap.set_budget(config.heap_budget(heap_index));
+        }
+        // Heap usage counts memory objects allocated from the device, not blocks
+        let heaps = &allocator.heaps;
+        for (memory_type, combined) in &mut allocator.allocators {
+            combined.set_heap_usage(heaps[memory_type.heap_index].counter());
+        }
+        Ok(allocator)
+    }
+
+    /// Set the budget of a heap. `None` allows using the whole heap.
+    ///
+    /// The budget limits memory objects allocated from the device, so a block that needs a new
+    /// chunk fails with `MemoryError::BudgetExceeded` if the chunk would go over, even if the
+    /// block alone would not.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `heap_index`: index of the heap in `MemoryProperties::memory_heaps`
+    /// - `budget`: how much memory of the heap this allocator may use
+    ///
+    /// ### Returns
+    ///
+    /// `ConfigError::InvalidBudget` if a `Budget::Fraction` is not between `0.0` and `1.0`.
+    pub fn set_heap_budget(
+        &self,
+        heap_index: usize,
+        budget: Option<Budget>,
+    ) -> Result<(), ConfigError> {
+        if let Some(budget) = budget {
+            budget.validate(heap_index)?;
         }
+        self.heaps[heap_index].set_budget(budget);
+        Ok(())
+    }
+
+    /// Set the amount of heap memory used outside of this allocator, by other allocators or
+    /// processes. Allocations fail with `MemoryError::BudgetExceeded` rather than going over.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `heap_index`: index of the heap in `MemoryProperties::memory_heaps`
+    /// - `usage`: memory used outside of this allocator in bytes
+    pub fn set_external_usage(&self, heap_index: usize, usage: u64) {
+        self.heaps[heap_index].set_external(usage);
+    }
+
+    /// Get the budget of a heap in bytes.
+    pub fn heap_budget(&self, heap_index: usize) -> u64 {
+        self.heaps[heap_index].limit()
+    }
+
+    /// Get the memory of a heap used by this allocator and externally in bytes.
+    pub fn heap_usage(&self, heap_index: usize) -> u64 {
+        self.heaps[heap_index].usage()
+    }
+
+    /// Get size above which allocations are promoted to dedicated memory objects.
+    pub fn dedicated_threshold(&self) -> Option<u64> {
+        self.dedicated_threshold
+    }
+
+    /// Set size above which allocations are promoted to dedicated memory objects.
+    /// `None` disables promotion.
+    pub fn set_dedicated_threshold(&mut self, threshold: Option<u64>) {
+        self.dedicated_threshold = threshold;
+    }
+
+    /// Set the minimal size of guard bands around sub-allocated blocks of all host-visible
+    /// memory types. Zero disables them. See `CombinedAllocator::set_guard_size`.
+    pub fn set_guard_size(&mut self, size: u64) {
+        for (memory_type, allocator) in &mut self.allocators {
+            if memory_type.properties.contains(Properties::CPU_VISIBLE) {
+                allocator.set_guard_size(size);
+            }
+        }
+    }
+
+    /// Check guard bands of all blocks in use, returning the blocks whose bands were overwritten.
+    /// See `CombinedAllocator::check_corruption`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the blocks
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the blocks were allocated with.
+    pub unsafe fn check_corruption(&self, device: &B::Device) -> Vec<CorruptedBlock> {
+        self.allocators
+            .iter()
+            .flat_map(|alloc| alloc.1.check_corruption(device))
+            .collect()
+    }
+
+    /// Check if allocations requiring device-local memory spill into other memory types once all
+    /// device-local memory types fail.
+    pub fn spill(&self) -> bool {
+        self.spill
+    }
+
+    /// Allow allocations requiring device-local memory to spill into other memory types, usually
+    /// host-visible system memory, once all device-local memory types fail. Disabled by default.
+    /// Spilled blocks are slower to access by the device, check `properties` of blocks to find them.
+    pub fn set_spill(&mut self, spill: bool) {
+        self.spill = spill;
     }
 
     /// Get properties of the block
@@ -82,72 +294,316 @@ where
             .map(|alloc| alloc.1.allocated())
             .sum()
     }
+
+    /// Set the number of empty chunks kept per block size for all memory types.
+    pub fn set_spare_chunks(&mut self, spare_chunks: usize) {
+        for &mut (_, ref mut allocator) in &mut self.allocators {
+            allocator.set_spare_chunks(spare_chunks);
+        }
+    }
+
+    /// Return all empty chunks of the sub-allocators to the device.
+    /// Useful when big amount of memory was freed, at level transitions for example.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the chunks
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the chunks were allocated with.
+    pub unsafe fn trim(&self, device: &B::Device) {
+        for (_, allocator) in &self.allocators {
+            allocator.trim(device);
+        }
+    }
+
+    /// Plan moves of blocks that compact the chunks of all memory types.
+    /// Blocks never move to another memory type. See `CombinedAllocator::plan_defrag`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `blocks`: blocks that may be moved
+    /// - `budget`: limits of the work to plan, reduced by the planned moves
+    pub fn plan_defrag(
+        &self,
+        blocks: &[&SmartBlock<B::Memory>],
+        budget: &mut DefragBudget,
+    ) -> Vec<DefragMove<SmartBlock<B::Memory>>> {
+        let mut moves = Vec::new();
+        for (type_index, (_, allocator)) in self.allocators.iter().enumerate() {
+            let (indices, blocks): (Vec<_>, Vec<_>) = blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, block)| block.1 == type_index)
+                .map(|(index, block)| (index, &block.0))
+                .unzip();
+            if blocks.is_empty() {
+                continue;
+            }
+            moves.extend(allocator.plan_defrag(&blocks, budget).into_iter().map(
+                |DefragMove { index, block }| DefragMove {
+                    index: indices[index],
+                    block: SmartBlock(block, type_index),
+                },
+            ));
+        }
+        moves
+    }
+
+    /// Free the old blocks of the planned moves once their contents are copied, returning the
+    /// evacuated chunks to the device. See `ChunkedAllocator::commit_defrag`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the blocks
+    /// - `blocks`: old blocks of the planned moves
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the blocks were allocated with. Blocks must not be used by the
+    /// device anymore.
+    pub unsafe fn commit_defrag<I>(&self, device: &B::Device, blocks: I)
+    where
+        I: IntoIterator<Item = SmartBlock<B::Memory>>,
+    {
+        for (type_index, blocks) in self.split_blocks(blocks).into_iter().enumerate() {
+            self.allocators[type_index].1.commit_defrag(device, blocks);
+        }
+    }
+
+    /// Abandon the planned moves, freeing their new blocks.
+    /// See `ChunkedAllocator::cancel_defrag`.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `device`: same device that was used to allocate the blocks
+    /// - `blocks`: new blocks of the planned moves
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one the blocks were allocated with. Blocks must not be used by the
+    /// device anymore.
+    pub unsafe fn cancel_defrag<I>(&self, device: &B::Device, blocks: I)
+    where
+        I: IntoIterator<Item = SmartBlock<B::Memory>>,
+    {
+        for (type_index, blocks) in self.split_blocks(blocks).into_iter().enumerate() {
+            self.allocators[type_index].1.cancel_defrag(device, blocks);
+        }
+    }
+
+    /// Group blocks about to be freed by memory type.
+    fn split_blocks<I>(&self, blocks: I) -> Vec<Vec<CombinedBlock<B::Memory>>>
+    where
+        I: IntoIterator<Item = SmartBlock<B::Memory>>,
+    {
+        let mut split: Vec<_> = self.allocators.iter().map(|_| Vec::new()).collect();
+        for SmartBlock(block, type_index) in blocks {
+            split[type_index].push(block);
+        }
+        split
+    }
+
+    /// Collect detailed statistics of all memory types and heaps.
+    pub fn stats(&self) -> SmartStats {
+        SmartStats::new(
+            self.allocators
+                .iter()
+                .map(|&(memory_type, ref allocator)| MemoryTypeStats {
+                    properties: memory_type.properties,
+                    heap_index: memory_type.heap_index,
+                    allocator: allocator.stats(),
+                })
+                .collect(),
+            &self.heaps,
+        )
+    }
+
+    /// Dump state of all memory types and heaps, including every chunk and block in use.
+    #[cfg(feature = "dump")]
+    pub fn dump(&self) -> AllocatorDump {
+        AllocatorDump::new(
+            self.allocators
+                .iter()
+                .enumerate()
+                .map(|(index, &(memory_type, ref allocator))| MemoryTypeDump {
+                    index,
+                    properties: property_names(memory_type.properties),
+                    heap_index: memory_type.heap_index,
+                    allocator: allocator.dump(),
+                })
+                .collect(),
+            &self.heaps,
+        )
+    }
+
+    /// Collect blocks in use of all memory types with backtraces of their allocations.
+    /// See `CombinedAllocator::live_blocks`.
+    #[cfg(feature = "checks")]
+    pub fn live_blocks(&self) -> Vec<LiveBlock> {
+        self.allocators
+            .iter()
+            .flat_map(|alloc| alloc.1.live_blocks())
+            .collect()
+    }
+
+    /// Label a block in use, to be reported by `live_blocks`.
+    #[cfg(feature = "checks")]
+    pub fn set_label<L>(&self, block: &SmartBlock<B::Memory>, label: L)
+    where
+        L: Into<String>,
+    {
+        self.allocators[block.1].1.set_label(&block.0, label);
+    }
+
+    /// Check that the block can be freed by this allocator.
+    pub fn validate(&self, block: &SmartBlock<B::Memory>) -> Result<(), BlockError> {
+        self.allocators
+            .get(block.1)
+            .ok_or(BlockError::ForeignBlock)?
+            .1
+            .validate(&block.0)
+    }
+
+    /// Get the total size of all blocks allocated as dedicated memory objects.
+    pub fn dedicated(&self) -> u64 {
+        self.allocators
+            .iter()
+            .map(|alloc| alloc.1.dedicated())
+            .sum()
+    }
+
+    /// Get the total size of all blocks sub-allocated from bigger chunks.
+    pub fn sub_allocated(&self) -> u64 {
+        self.allocators
+            .iter()
+            .map(|alloc| alloc.1.sub_allocated())
+            .sum()
+    }
+
+    /// Allocate a block of memory. See `MemoryAllocator::alloc`.
+    ///
+    /// ### Safety
+    ///
+    /// `device` must be the one used for all other calls to this allocator.
+    pub unsafe fn alloc(
+        &self,
+        device: &B::Device,
+        (ty, props, kind): (Type, RequestedProperties, ResourceKind),
+        reqs: Requirements,
+    ) -> Result<SmartBlock<B::Memory>, MemoryError> {
+        let ty = promote(self.dedicated_threshold, ty, reqs);
+        let memory_types: Vec<_> = self
+            .allocators
+            .iter()
+            .map(|&(memory_type, _)| memory_type)
+            .collect();
+        let (block, chosen) = alloc_ranked(
+            &memory_types,
+            &self.heaps,
+            (ty, props),
+            reqs,
+            self.spill,
+            |index| self.allocators[index].1.alloc(device, (ty, kind), reqs),
+        )?;
+        Ok(SmartBlock(block, chosen))
+    }
+
+    /// Free a block of memory. See `MemoryAllocator::free`.
+    ///
+    /// ### Safety
+    ///
+    /// The block must be allocated by this allocator using the same `device`, and must not be
+    /// used by the device anymore.
+    pub unsafe fn free(&self, device: &B::Device, block: SmartBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
+        let SmartBlock(block, index) = block;
+        self.allocators[index].1.free(device, block);
+    }
+
+    /// Map a range of a block into host address space. See `MappingAllocator::map`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::map`.
+    pub unsafe fn map(
+        &self,
+        device: &B::Device,
+        block: &SmartBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if !self.properties(block).contains(Properties::CPU_VISIBLE) {
+            return Err(MappingError::InvalidAccess);
+        }
+        self.allocators[block.1].1.map(device, &block.0, range)
+    }
+
+    /// Release a mapping acquired with `map`. See `MappingAllocator::unmap`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::unmap`.
+    pub unsafe fn unmap(&self, device: &B::Device, block: &SmartBlock<B::Memory>) {
+        self.allocators[block.1].1.unmap(device, &block.0)
+    }
+
+    /// Flush a mapped range of a block. See `MappingAllocator::flush`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::flush`.
+    pub unsafe fn flush(
+        &self,
+        device: &B::Device,
+        block: &SmartBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        if self.properties(block).contains(Properties::COHERENT) {
+            return Ok(());
+        }
+        self.allocators[block.1].1.flush(device, &block.0, range)
+    }
+
+    /// Invalidate a mapped range of a block. See `MappingAllocator::invalidate`.
+    ///
+    /// ### Safety
+    ///
+    /// See `MappingAllocator::invalidate`.
+    pub unsafe fn invalidate(
+        &self,
+        device: &B::Device,
+        block: &SmartBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        if self.properties(block).contains(Properties::COHERENT) {
+            return Ok(());
+        }
+        self.allocators[block.1]
+            .1
+            .invalidate(device, &block.0, range)
+    }
 }
 
 impl<B> MemoryAllocator<B> for SmartAllocator<B>
 where
     B: Backend,
 {
-    type Request = (Type, Properties);
+    type Request = (Type, RequestedProperties, ResourceKind);
     type Block = SmartBlock<B::Memory>;
 
     unsafe fn alloc(
         &mut self,
         device: &B::Device,
-        (ty, prop): (Type, Properties),
+        request: (Type, RequestedProperties, ResourceKind),
         reqs: Requirements,
     ) -> Result<SmartBlock<B::Memory>, MemoryError> {
-        let mut compatible = false;
-        let mut candidate = None;
-
-        // Find compatible memory type with least used heap with enough available memory
-        for index in 0..self.allocators.len() {
-            let memory_type = self.allocators[index].0;
-            // filter out non-compatible
-            if ((1 << index) & reqs.type_mask) != (1 << index)
-                || !memory_type.properties.contains(prop)
-            {
-                continue;
-            }
-            compatible = true;
-            // filter out if heap has not enough memory available
-            if self.heaps[memory_type.heap_index].available() < (reqs.size + reqs.alignment) {
-                continue;
-            }
-            // Compare with candidate. Replace if this one is less used.
-            let this_usage = self.heaps[memory_type.heap_index].usage();
-            match candidate {
-                Some((ref mut candidate, ref mut usage)) if *usage > this_usage => {
-                    *candidate = index;
-                    *usage = this_usage;
-                }
-                ref mut candidate @ None => *candidate = Some((index, this_usage)),
-                _ => {}
-            }
-        }
-
-        match candidate {
-            Some((chosen, _)) => {
-                // Allocate from final candidate
-                let block = self.allocators[chosen].1.alloc(device, ty, reqs)?;
-                self.heaps[self.allocators[chosen].0.heap_index].alloc(block.size());
-                Ok(SmartBlock(block, chosen))
-            }
-            None => {
-                // No candidates
-                Err(if !compatible {
-                    MemoryError::NoCompatibleMemoryType
-                } else {
-                    MemoryError::OutOfMemory
-                })
-            }
-        }
+        SmartAllocator::alloc(self, device, request, reqs)
     }
 
     unsafe fn free(&mut self, device: &B::Device, block: SmartBlock<B::Memory>) {
-        let SmartBlock(block, index) = block;
-        self.heaps[self.allocators[index].0.heap_index].free(block.size());
-        self.allocators[index].1.free(device, block);
+        SmartAllocator::free(self, device, block)
     }
 
     fn is_used(&self) -> bool {
@@ -168,33 +624,587 @@ where
     }
 }
 
+impl<B> MappingAllocator<B> for SmartAllocator<B>
+where
+    B: Backend,
+{
+    unsafe fn map(
+        &mut self,
+        device: &B::Device,
+        block: &SmartBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        SmartAllocator::map(self, device, block, range)
+    }
+
+    unsafe fn unmap(&mut self, device: &B::Device, block: &SmartBlock<B::Memory>) {
+        SmartAllocator::unmap(self, device, block)
+    }
+
+    unsafe fn flush(
+        &mut self,
+        device: &B::Device,
+        block: &SmartBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        SmartAllocator::flush(self, device, block, range)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        device: &B::Device,
+        block: &SmartBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        SmartAllocator::invalidate(self, device, block, range)
+    }
+}
+
+/// Limit of memory usage of a heap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum Budget {
+    /// Absolute limit in bytes.
+    Bytes(u64),
+
+    /// Limit as a fraction of heap size, from `0.0` to `1.0`.
+    Fraction(f32),
+}
+
+impl Budget {
+    /// Check that the budget is valid for the heap with index `heap`.
+    pub(crate) fn validate(&self, heap: usize) -> Result<(), ConfigError> {
+        match *self {
+            Budget::Fraction(fraction) if !(0.0..=1.0).contains(&fraction) => {
+                Err(ConfigError::InvalidBudget { heap, fraction })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the limit in bytes for a heap of the given size.
+    ///
+    /// ### Panics
+    ///
+    /// Panics if a `Budget::Fraction` is not between `0.0` and `1.0`.
+    pub fn bytes(&self, heap_size: u64) -> u64 {
+        match *self {
+            Budget::Bytes(bytes) => min(bytes, heap_size),
+            Budget::Fraction(fraction) => {
+                assert!((0.0..=1.0).contains(&fraction));
+                (heap_size as f64 * fraction as f64) as u64
+            }
+        }
+    }
+}
+
+/// Usage and budget of a heap, shared with the `RootAllocator`s of the memory types of the heap.
+/// They count the memory objects they allocate from the device and refuse to go over the budget.
 #[derive(Debug)]
-struct Heap {
+pub(crate) struct HeapUsage {
+    /// Total size of memory objects allocated by the allocator
+    used: AtomicU64,
+    /// Memory used outside of the allocator
+    external: AtomicU64,
+    /// Budget in bytes, `!0` if there is none
+    budget: AtomicU64,
+}
+
+impl HeapUsage {
+    /// Count a new memory object of `size` bytes, unless it would exceed the budget.
+    pub(crate) fn reserve(&self, size: u64) -> Result<(), MemoryError> {
+        let budget = self.budget.load(Ordering::Relaxed);
+        let external = self.external.load(Ordering::Relaxed);
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            if used.saturating_add(external).saturating_add(size) > budget {
+                return Err(MemoryError::BudgetExceeded);
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used + size,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    /// Count memory objects regardless of the budget.
+    pub(crate) fn add(&self, size: u64) {
+        self.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Stop counting freed memory objects.
+    pub(crate) fn release(&self, size: u64) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+/// Memory usage of a heap.
+/// Counters are atomic so that it can be shared between threads.
+#[derive(Debug)]
+pub(crate) struct Heap {
     size: u64,
-    used: u64,
+    usage: Arc<HeapUsage>,
 }
 
 impl Heap {
+    fn new(size: u64) -> Self {
+        Heap {
+            size,
+            usage: Arc::new(HeapUsage {
+                used: AtomicU64::new(0),
+                external: AtomicU64::new(0),
+                budget: AtomicU64::new(!0),
+            }),
+        }
+    }
+
+    pub(crate) fn set_budget(&self, budget: Option<Budget>) {
+        let bytes = budget.map_or(!0, |budget| budget.bytes(self.size));
+        self.usage.budget.store(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_external(&self, usage: u64) {
+        self.usage.external.store(usage, Ordering::Relaxed);
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn external(&self) -> u64 {
+        self.usage.external.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn limit(&self) -> u64 {
+        match self.usage.budget.load(Ordering::Relaxed) {
+            budget if budget == !0 => self.size,
+            budget => budget,
+        }
+    }
+
+    pub(crate) fn used(&self) -> u64 {
+        self.usage.used.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn usage(&self) -> u64 {
+        self.used() + self.external()
+    }
+
     fn available(&self) -> u64 {
-        self.size - self.used
+        self.limit().saturating_sub(self.usage())
+    }
+
+    fn unbudgeted(&self) -> u64 {
+        self.size.saturating_sub(self.usage())
+    }
+
+    fn counter(&self) -> Arc<HeapUsage> {
+        self.usage.clone()
     }
 
-    fn alloc(&mut self, size: u64) {
-        self.used += size;
+    fn load(&self) -> f32 {
+        self.usage() as f32 / self.limit() as f32
     }
+}
 
-    fn free(&mut self, size: u64) {
-        self.used -= size;
+/// Flush and invalidate granularity to use for a memory type.
+/// Only host-visible memory that is not `COHERENT` needs padding to `non_coherent_atom_size`.
+pub(crate) fn atom_size(properties: Properties, non_coherent_atom_size: u64) -> u64 {
+    if properties.contains(Properties::CPU_VISIBLE) && !properties.contains(Properties::COHERENT) {
+        non_coherent_atom_size
+    } else {
+        1
     }
+}
 
-    fn usage(&self) -> f32 {
-        self.used as f32 / self.size as f32
+/// Replace requested `Type` with `Type::Dedicated` if allocation is bigger than `threshold`.
+fn promote(threshold: Option<u64>, ty: Type, reqs: Requirements) -> Type {
+    match threshold {
+        Some(threshold) if reqs.size > threshold => Type::Dedicated,
+        _ => ty,
     }
 }
 
+/// Common ways memory is used, mapped to required and preferred properties by
+/// `RequestedProperties`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryUsage {
+    /// Used only by the device, such as render targets and static meshes.
+    /// Prefers device-local memory, but accepts any.
+    GpuOnly,
+
+    /// Written by the host once and read by the device, such as staging buffers.
+    /// Requires host-visible memory and prefers coherent memory.
+    Upload,
+
+    /// Written by the device and read back by the host.
+    /// Requires host-visible memory and prefers cached memory.
+    Readback,
+
+    /// Written by the host frequently and read by the device, such as uniform buffers.
+    /// Requires host-visible memory and prefers device-local coherent memory.
+    Dynamic,
+}
+
+/// Properties requested for an allocation of `SmartAllocator`.
+///
+/// Memory types lacking `required` properties are never used. Compatible memory types are ranked
+/// by their `preferred` properties, and against properties that are neither, e.g. cached memory
+/// is avoided for uploads. Ties are broken by choosing the least used heap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestedProperties {
+    /// Properties the memory type must have.
+    pub required: Properties,
+
+    /// Properties the memory type should have.
+    pub preferred: Properties,
+}
+
+impl RequestedProperties {
+    /// Score how well a memory type with `properties` suits the usage, higher is better.
+    /// Returns `None` if some of the required properties are missing.
+    pub fn score(&self, properties: Properties) -> Option<i32> {
+        if !properties.contains(self.required) {
+            return None;
+        }
+        let preferred = (properties & self.preferred).bits().count_ones() as i32;
+        let unwanted = (properties - self.required - self.preferred)
+            .bits()
+            .count_ones() as i32;
+        // A preferred property outweighs an unwanted one
+        Some(2 * preferred - unwanted)
+    }
+}
+
+impl From<Properties> for RequestedProperties {
+    /// Require the properties, without preferring any other.
+    fn from(required: Properties) -> Self {
+        RequestedProperties {
+            required,
+            preferred: Properties::empty(),
+        }
+    }
+}
+
+impl From<MemoryUsage> for RequestedProperties {
+    fn from(usage: MemoryUsage) -> Self {
+        match usage {
+            MemoryUsage::GpuOnly => RequestedProperties {
+                required: Properties::empty(),
+                preferred: Properties::DEVICE_LOCAL,
+            },
+            MemoryUsage::Upload => RequestedProperties {
+                required: Properties::CPU_VISIBLE,
+                preferred: Properties::COHERENT,
+            },
+            MemoryUsage::Readback => RequestedProperties {
+                required: Properties::CPU_VISIBLE,
+                preferred: Properties::CPU_CACHED,
+            },
+            MemoryUsage::Dynamic => RequestedProperties {
+                required: Properties::CPU_VISIBLE,
+                preferred: Properties::DEVICE_LOCAL | Properties::COHERENT,
+            },
+        }
+    }
+}
+
+/// Reason why a memory type wasn't used for an allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Memory type is not in the type mask of the requirements.
+    TypeMask,
+
+    /// Memory type lacks some of the required properties.
+    Properties,
+
+    /// Heap of the memory type doesn't have enough memory left.
+    HeapFull,
+
+    /// Heap of the memory type has memory left, but using it would exceed the heap budget.
+    BudgetExceeded,
+
+    /// Memory type is compatible, but wasn't tried.
+    NotChosen,
+
+    /// Allocating from the memory type was tried, but failed.
+    AllocationFailed,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Rejection::TypeMask => "not in type mask",
+            Rejection::Properties => "missing properties",
+            Rejection::HeapFull => "heap full",
+            Rejection::BudgetExceeded => "budget exceeded",
+            Rejection::NotChosen => "not chosen",
+            Rejection::AllocationFailed => "allocation failed",
+        })
+    }
+}
+
+/// Memory type considered for a failed allocation.
+#[derive(Clone, Debug)]
+pub struct CandidateType {
+    /// Index of the memory type.
+    pub memory_type: MemoryTypeId,
+
+    /// Properties of the memory type.
+    pub properties: Properties,
+
+    /// Index of the heap of the memory type.
+    pub heap_index: usize,
+
+    /// Memory of the heap used by the allocator and externally at the time of failure.
+    pub heap_usage: u64,
+
+    /// Budget of the heap, or its size if it has no budget.
+    pub heap_budget: u64,
+
+    /// Why the memory type wasn't used.
+    pub rejection: Rejection,
+}
+
+/// Context of a failed allocation of `SmartAllocator`, carried by `MemoryError::Context`.
+#[derive(Clone, Debug)]
+pub struct ErrorContext {
+    /// Error of the allocation.
+    /// `MemoryError::OutOfMemory` and `MemoryError::TooManyObjects` come from the device,
+    /// `MemoryError::BudgetExceeded` from a heap budget, `MemoryError::TooLarge` from a
+    /// sub-allocator.
+    pub error: MemoryError,
+
+    /// Kind of allocation requested, after promotion to dedicated memory.
+    pub request: Type,
+
+    /// Requested properties.
+    pub properties: RequestedProperties,
+
+    /// Requirements of the allocation.
+    pub reqs: Requirements,
+
+    /// All memory types of the allocator.
+    pub memory_types: Vec<CandidateType>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} allocating {} bytes aligned to {} ({:?}, {:?}, type mask {:#x})",
+            self.error,
+            self.reqs.size,
+            self.reqs.alignment,
+            self.request,
+            self.properties,
+            self.reqs.type_mask,
+        )?;
+        for candidate in &self.memory_types {
+            write!(
+                f,
+                "; type {}: {} (heap {}: {} of {} bytes used)",
+                candidate.memory_type.0,
+                candidate.rejection,
+                candidate.heap_index,
+                candidate.heap_usage,
+                candidate.heap_budget,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Check if the memory type can be used for an allocation.
+fn check_memory_type(
+    index: usize,
+    memory_type: MemoryType,
+    heaps: &[Heap],
+    props: RequestedProperties,
+    reqs: Requirements,
+) -> Result<(), Rejection> {
+    if ((1 << index) & reqs.type_mask) != (1 << index) {
+        return Err(Rejection::TypeMask);
+    }
+    if props.score(memory_type.properties).is_none() {
+        return Err(Rejection::Properties);
+    }
+    // filter out if heap has not enough memory available
+    let heap = &heaps[memory_type.heap_index];
+    if heap.available() < (reqs.size + reqs.alignment) {
+        return Err(if heap.unbudgeted() >= (reqs.size + reqs.alignment) {
+            Rejection::BudgetExceeded
+        } else {
+            Rejection::HeapFull
+        });
+    }
+    Ok(())
+}
+
+/// Rank compatible memory types with enough available memory, best suited first.
+/// Ties are broken by putting the least used heap first.
+fn rank_memory_types(
+    memory_types: &[MemoryType],
+    heaps: &[Heap],
+    props: RequestedProperties,
+    reqs: Requirements,
+) -> Result<Vec<usize>, MemoryError> {
+    let mut compatible = false;
+    let mut over_budget = false;
+    let mut ranked = Vec::new();
+
+    for (index, &memory_type) in memory_types.iter().enumerate() {
+        match check_memory_type(index, memory_type, heaps, props, reqs) {
+            Ok(()) => {}
+            Err(Rejection::TypeMask) | Err(Rejection::Properties) => continue,
+            Err(rejection) => {
+                compatible = true;
+                over_budget |= rejection == Rejection::BudgetExceeded;
+                continue;
+            }
+        }
+        compatible = true;
+        let score = props.score(memory_type.properties).unwrap();
+        let load = heaps[memory_type.heap_index].load();
+        ranked.push((index, score, load));
+    }
+
+    if ranked.is_empty() {
+        // No candidates
+        return Err(if !compatible {
+            MemoryError::NoCompatibleMemoryType
+        } else if over_budget {
+            MemoryError::BudgetExceeded
+        } else {
+            MemoryError::OutOfMemory
+        });
+    }
+    ranked.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then(a.2.partial_cmp(&b.2).unwrap_or(CmpOrdering::Equal))
+    });
+    Ok(ranked.into_iter().map(|(index, _, _)| index).collect())
+}
+
+/// Move requirement of device-local memory to preferences, so the allocation can spill into
+/// other memory.
+fn spill(props: RequestedProperties) -> Option<RequestedProperties> {
+    if props.required.contains(Properties::DEVICE_LOCAL) {
+        Some(RequestedProperties {
+            required: props.required - Properties::DEVICE_LOCAL,
+            preferred: props.preferred | Properties::DEVICE_LOCAL,
+        })
+    } else {
+        None
+    }
+}
+
+/// Allocate from the best suited memory type, trying the next ones in ranked order if the
+/// allocation fails.
+///
+/// ### Parameters:
+///
+/// - `memory_types`: all memory types of the allocator
+/// - `heaps`: all heaps of the allocator
+/// - `spill_enabled`: whether allocations requiring device-local memory may spill into other
+///   memory once device-local memory types are exhausted
+/// - `alloc`: allocate from a memory type by index
+fn alloc_ranked<T, F>(
+    memory_types: &[MemoryType],
+    heaps: &[Heap],
+    (ty, props): (Type, RequestedProperties),
+    reqs: Requirements,
+    spill_enabled: bool,
+    mut alloc: F,
+) -> Result<(T, usize), MemoryError>
+where
+    F: FnMut(usize) -> Result<T, MemoryError>,
+{
+    let spilled = if spill_enabled { spill(props) } else { None };
+    let mut tried = Vec::new();
+    let mut rank_error = None;
+    let mut alloc_error = None;
+    for props in Some(props).into_iter().chain(spilled) {
+        let ranked = match rank_memory_types(memory_types, heaps, props, reqs) {
+            Ok(ranked) => ranked,
+            Err(error) => {
+                rank_error.get_or_insert(error);
+                continue;
+            }
+        };
+        for index in ranked {
+            if tried.contains(&index) {
+                continue;
+            }
+            match alloc(index) {
+                Ok(block) => return Ok((block, index)),
+                Err(error) => {
+                    tried.push(index);
+                    alloc_error = Some(error);
+                }
+            }
+        }
+    }
+    let error = alloc_error.or(rank_error).unwrap();
+    Err(error_context(
+        memory_types,
+        heaps,
+        (ty, props),
+        reqs,
+        &tried,
+        error,
+    ))
+}
+
+/// Wrap the error of a failed allocation with the state of all memory types.
+///
+/// ### Parameters:
+///
+/// - `tried`: memory types the allocation failed in
+fn error_context(
+    memory_types: &[MemoryType],
+    heaps: &[Heap],
+    (ty, props): (Type, RequestedProperties),
+    reqs: Requirements,
+    tried: &[usize],
+    error: MemoryError,
+) -> MemoryError {
+    let memory_types = memory_types
+        .iter()
+        .enumerate()
+        .map(|(index, &memory_type)| {
+            let heap = &heaps[memory_type.heap_index];
+            CandidateType {
+                memory_type: MemoryTypeId(index),
+                properties: memory_type.properties,
+                heap_index: memory_type.heap_index,
+                heap_usage: heap.usage(),
+                heap_budget: heap.limit(),
+                rejection: match check_memory_type(index, memory_type, heaps, props, reqs) {
+                    _ if tried.contains(&index) => Rejection::AllocationFailed,
+                    Ok(()) => Rejection::NotChosen,
+                    Err(rejection) => rejection,
+                },
+            }
+        })
+        .collect();
+    MemoryError::Context(Box::new(ErrorContext {
+        error,
+        request: ty,
+        properties: props,
+        reqs,
+        memory_types,
+    }))
+}
+
 /// `Block` type returned by `SmartAllocator`.
 #[derive(Debug)]
-pub struct SmartBlock<M>(CombinedBlock<M>, usize);
+pub struct SmartBlock<M>(pub(crate) CombinedBlock<M>, pub(crate) usize);
 
 impl<M> Block for SmartBlock<M>
 where
@@ -221,3 +1231,502 @@ fn test_send_sync() {
         foo::<SmartAllocator<B>>()
     }
 }
+
+#[test]
+fn test_non_coherent_atom_size() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let atoms = allocator
+        .allocators
+        .iter()
+        .map(|(_, allocator)| allocator.non_coherent_atom_size())
+        .collect::<Vec<_>>();
+    // DEVICE_LOCAL, CPU_VISIBLE | COHERENT, CPU_VISIBLE | CPU_CACHED
+    assert_eq!(atoms, vec![1, 1, device.non_coherent_atom_size()]);
+    unsafe {
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_alloc_free() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = |size| Requirements {
+        size,
+        alignment: 256,
+        type_mask: !0,
+    };
+    unsafe {
+        let general = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(1000),
+            )
+            .unwrap();
+        let short_lived = allocator
+            .alloc(
+                &device,
+                (
+                    Type::ShortLived,
+                    Properties::CPU_VISIBLE.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(1000),
+            )
+            .unwrap();
+        assert_eq!(allocator.properties(&general), Properties::DEVICE_LOCAL);
+        assert!(allocator
+            .properties(&short_lived)
+            .contains(Properties::CPU_VISIBLE));
+
+        let ptr = allocator.map(&device, &short_lived, 0..1000).unwrap();
+        *ptr = 42;
+        allocator.flush(&device, &short_lived, 0..1000).unwrap();
+        allocator.unmap(&device, &short_lived);
+
+        allocator
+            .set_heap_budget(0, Some(Budget::Bytes(1 << 20)))
+            .unwrap();
+        match allocator.alloc(
+            &device,
+            (
+                Type::General,
+                Properties::DEVICE_LOCAL.into(),
+                ResourceKind::Linear,
+            ),
+            reqs(2 << 20),
+        ) {
+            Err(ref error) if matches!(*error.inner(), MemoryError::BudgetExceeded) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        allocator.free(&device, general);
+        allocator.free(&device, short_lived);
+        allocator.dispose(&device).unwrap();
+    }
+    assert!(device.leaks().is_empty());
+}
+
+#[test]
+fn test_buddy() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let memory_properties = device.memory_properties();
+    let config = SmartConfig::new(&memory_properties).with_memory_type(
+        0,
+        CombinedConfig::new(1 << 16, 64, 256, 1 << 20).with_buddy(true),
+    );
+    let allocator = SmartAllocator::<MockBackend>::with_config(
+        memory_properties,
+        &config,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = |size| Requirements {
+        size,
+        alignment: 256,
+        type_mask: 1,
+    };
+    let request = (
+        Type::General,
+        Properties::DEVICE_LOCAL.into(),
+        ResourceKind::Linear,
+    );
+    unsafe {
+        let big = allocator.alloc(&device, request, reqs(33 << 10)).unwrap();
+        let small = allocator.alloc(&device, request, reqs(1000)).unwrap();
+        // Blocks of both sizes share one chunk of the buddy allocator.
+        let stats = allocator.stats().memory_types[0].allocator.clone();
+        assert_eq!(stats.buddy.chunks, 1);
+        assert_eq!(stats.buddy.blocks, 2);
+        assert_eq!(stats.buddy.used, (64 << 10) + 1024);
+        assert_eq!(stats.chunked.chunks, 0);
+        assert_eq!(device.object_count(), 1);
+
+        allocator.free(&device, big);
+        allocator.free(&device, small);
+        assert_eq!(allocator.stats().memory_types[0].allocator.buddy.blocks, 0);
+        allocator.dispose(&device).unwrap();
+    }
+    assert!(device.leaks().is_empty());
+}
+
+#[test]
+fn test_heap_usage() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 256,
+        type_mask: !0,
+    };
+    assert_eq!(
+        allocator.set_heap_budget(0, Some(Budget::Fraction(1.5))),
+        Err(ConfigError::InvalidBudget {
+            heap: 0,
+            fraction: 1.5,
+        })
+    );
+    unsafe {
+        let block = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        // Whole chunk allocated from the device is used, not just the block.
+        assert!(allocator.heap_usage(0) > block.size());
+        assert_eq!(allocator.heap_usage(0), device.heap_usage(0));
+
+        // The block fits the headroom, but the new chunk it needs doesn't.
+        let used = allocator.heap_usage(0);
+        allocator
+            .set_heap_budget(0, Some(Budget::Bytes(used + 2048)))
+            .unwrap();
+        match allocator.alloc(
+            &device,
+            (
+                Type::General,
+                Properties::DEVICE_LOCAL.into(),
+                ResourceKind::Linear,
+            ),
+            Requirements { size: 1500, ..reqs },
+        ) {
+            Err(ref error) if matches!(*error.inner(), MemoryError::BudgetExceeded) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(allocator.heap_usage(0), used);
+        assert_eq!(device.heap_usage(0), used);
+        allocator.set_heap_budget(0, None).unwrap();
+        allocator.free(&device, block);
+        allocator.trim(&device);
+        assert_eq!(allocator.heap_usage(0), 0);
+        assert_eq!(device.heap_usage(0), 0);
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_error_context() {
+    use gfx_hal::device::{AllocationError, OutOfMemory};
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = Requirements {
+        size: 1 << 20,
+        alignment: 256,
+        type_mask: 0b101,
+    };
+    unsafe {
+        device.inject_failure(AllocationError::OutOfMemory(OutOfMemory::OutOfDeviceMemory));
+        let error = allocator
+            .alloc(
+                &device,
+                (
+                    Type::Dedicated,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap_err();
+        match error {
+            MemoryError::Context(ref context) => {
+                let rejections: Vec<_> = context
+                    .memory_types
+                    .iter()
+                    .map(|candidate| candidate.rejection)
+                    .collect();
+                assert_eq!(
+                    rejections,
+                    [
+                        Rejection::AllocationFailed,
+                        Rejection::TypeMask,
+                        Rejection::Properties,
+                    ]
+                );
+                assert_eq!(context.reqs.size, 1 << 20);
+            }
+            ref other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(matches!(*error.inner(), MemoryError::OutOfMemory));
+        assert!(error.to_string().contains("type 0: allocation failed"));
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_memory_usage() {
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = Requirements {
+        size: 1000,
+        alignment: 256,
+        type_mask: !0,
+    };
+    unsafe {
+        let mut blocks = Vec::new();
+        for &(usage, memory_type) in &[
+            (MemoryUsage::GpuOnly, 0),
+            (MemoryUsage::Upload, 1),
+            (MemoryUsage::Readback, 2),
+            (MemoryUsage::Dynamic, 1),
+        ] {
+            let block = allocator
+                .alloc(
+                    &device,
+                    (Type::General, usage.into(), ResourceKind::Linear),
+                    reqs,
+                )
+                .unwrap();
+            assert_eq!(block.1, memory_type, "{:?}", usage);
+            blocks.push(block);
+        }
+
+        // Device-local memory is preferred but not required.
+        allocator
+            .set_heap_budget(0, Some(Budget::Bytes(1 << 10)))
+            .unwrap();
+        let block = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    MemoryUsage::GpuOnly.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        assert!(allocator
+            .properties(&block)
+            .contains(Properties::CPU_VISIBLE));
+        blocks.push(block);
+
+        for block in blocks {
+            allocator.free(&device, block);
+        }
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_fallback() {
+    use gfx_hal::device::{AllocationError, OutOfMemory};
+    use mock::{MockBackend, MockDevice};
+
+    let device = MockDevice::default();
+    let mut allocator = SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    );
+    let reqs = Requirements {
+        size: 1 << 20,
+        alignment: 256,
+        type_mask: !0,
+    };
+    let oom = || AllocationError::OutOfMemory(OutOfMemory::OutOfDeviceMemory);
+    unsafe {
+        // Coherent memory is preferred, cached memory is the next candidate.
+        device.inject_failure(oom());
+        let upload = allocator
+            .alloc(
+                &device,
+                (
+                    Type::Dedicated,
+                    MemoryUsage::Upload.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        assert_eq!(upload.1, 2);
+
+        device.inject_failure(oom());
+        allocator
+            .alloc(
+                &device,
+                (
+                    Type::Dedicated,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap_err();
+
+        allocator.set_spill(true);
+        device.inject_failure(oom());
+        let spilled = allocator
+            .alloc(
+                &device,
+                (
+                    Type::Dedicated,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs,
+            )
+            .unwrap();
+        assert!(allocator
+            .properties(&spilled)
+            .contains(Properties::CPU_VISIBLE));
+
+        // Every memory type is tried before failing.
+        for _ in 0..3 {
+            device.inject_failure(oom());
+        }
+        match allocator.alloc(
+            &device,
+            (
+                Type::Dedicated,
+                Properties::DEVICE_LOCAL.into(),
+                ResourceKind::Linear,
+            ),
+            reqs,
+        ) {
+            Err(MemoryError::Context(ref context)) => assert!(context
+                .memory_types
+                .iter()
+                .all(|candidate| candidate.rejection == Rejection::AllocationFailed)),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        allocator.free(&device, upload);
+        allocator.free(&device, spilled);
+        allocator.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_threads() {
+    use mock::{MockBackend, MockDevice};
+    use std::sync::Arc;
+    use std::thread;
+
+    let device = Arc::new(MockDevice::default());
+    let allocator = Arc::new(SmartAllocator::<MockBackend>::new(
+        device.memory_properties(),
+        1 << 16,
+        64,
+        256,
+        1 << 20,
+        device.non_coherent_atom_size(),
+        device.limits().buffer_image_granularity,
+    ));
+    let threads: Vec<_> = [
+        Type::ShortLived,
+        Type::General,
+        Type::MediumLived,
+        Type::General,
+    ]
+    .iter()
+    .enumerate()
+    .map(|(index, &ty)| {
+        let device = device.clone();
+        let allocator = allocator.clone();
+        thread::spawn(move || unsafe {
+            let props = Properties::CPU_VISIBLE.into();
+            let mut blocks = Vec::new();
+            for size in 1..200 {
+                let reqs = Requirements {
+                    size: size * (index as u64 + 1) * 64,
+                    alignment: 64,
+                    type_mask: !0,
+                };
+                let block = allocator
+                    .alloc(&*device, (ty, props, ResourceKind::Linear), reqs)
+                    .unwrap();
+                let ptr = allocator.map(&*device, &block, 0..1).unwrap();
+                *ptr = index as u8;
+                allocator.unmap(&*device, &block);
+                blocks.push(block);
+                if size % 3 == 0 {
+                    allocator.free(&*device, blocks.swap_remove(0));
+                }
+            }
+            for block in blocks {
+                let ptr = allocator.map(&*device, &block, 0..1).unwrap();
+                assert_eq!(*ptr, index as u8);
+                allocator.unmap(&*device, &block);
+                allocator.free(&*device, block);
+            }
+        })
+    })
+    .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let allocator = Arc::try_unwrap(allocator).unwrap();
+    assert!(!allocator.is_used());
+    unsafe {
+        allocator.dispose(&*device).unwrap();
+    }
+    assert!(device.leaks().is_empty());
+}
@@ -0,0 +1,235 @@
+use gfx_hal::memory::Properties;
+use gfx_hal::MemoryTypeId;
+
+use smart::Heap;
+
+/// Statistics of one size class of a `ChunkedAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeClassStats {
+    /// Size of blocks of the size class.
+    pub block_size: u64,
+
+    /// Size of chunks allocated for the size class.
+    pub chunk_size: u64,
+
+    /// Number of chunks allocated for the size class.
+    pub chunks: usize,
+
+    /// Number of blocks in all chunks.
+    pub blocks: usize,
+
+    /// Number of blocks in use.
+    pub used_blocks: usize,
+}
+
+/// Statistics of a `ChunkedAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkedStats {
+    /// Number of chunks allocated from the underlying allocator.
+    pub chunks: usize,
+
+    /// Number of blocks in use.
+    pub blocks: usize,
+
+    /// Total size of blocks in use.
+    pub used: u64,
+
+    /// Total size of chunks.
+    pub allocated: u64,
+
+    /// Size of the biggest block that can be allocated without allocating a new chunk.
+    pub largest_free: u64,
+
+    /// Occupancy of each size class, from the smallest to the biggest.
+    pub size_classes: Vec<SizeClassStats>,
+}
+
+/// Statistics of an `ArenaAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArenaStats {
+    /// Number of arena nodes, each owning one chunk from the underlying allocator.
+    pub nodes: usize,
+
+    /// Number of blocks in use.
+    pub blocks: usize,
+
+    /// Total size of blocks in use.
+    pub used: u64,
+
+    /// Total size of chunks.
+    pub allocated: u64,
+
+    /// Memory of chunks that is neither in use nor available for allocation until the whole
+    /// chunk is freed.
+    pub wasted: u64,
+
+    /// Size of the biggest block that can be allocated without allocating a new chunk.
+    pub largest_free: u64,
+}
+
+/// Statistics of a `TlsfAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsfStats {
+    /// Number of chunks allocated from the underlying allocator.
+    pub chunks: usize,
+
+    /// Number of blocks in use.
+    pub blocks: usize,
+
+    /// Total size of blocks in use.
+    pub used: u64,
+
+    /// Total size of chunks.
+    pub allocated: u64,
+
+    /// Number of free ranges between blocks, a measure of fragmentation.
+    pub free_ranges: usize,
+
+    /// Size of the biggest free range.
+    pub largest_free: u64,
+}
+
+/// Statistics of a `BuddyAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuddyStats {
+    /// Number of chunks allocated from the underlying allocator.
+    pub chunks: usize,
+
+    /// Number of blocks in use.
+    pub blocks: usize,
+
+    /// Total size of blocks in use.
+    pub used: u64,
+
+    /// Total size of chunks.
+    pub allocated: u64,
+
+    /// Number of free blocks that could not be coalesced with their buddies.
+    pub free_blocks: usize,
+
+    /// Size of the biggest free block.
+    pub largest_free: u64,
+}
+
+/// Statistics of a `CombinedAllocator`, which covers one memory type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CombinedStats {
+    /// Memory type of the allocator.
+    pub memory_type: MemoryTypeId,
+
+    /// Number of device memory objects allocated.
+    pub device_objects: usize,
+
+    /// Number of blocks in use.
+    pub blocks: usize,
+
+    /// Total size of blocks in use.
+    pub used: u64,
+
+    /// Total size of device memory objects.
+    pub allocated: u64,
+
+    /// Total size of dedicated allocations.
+    pub dedicated: u64,
+
+    /// Size of the biggest block that can be sub-allocated without allocating a new chunk.
+    pub largest_free: u64,
+
+    /// Statistics of the arena sub-allocator.
+    pub arena: ArenaStats,
+
+    /// Statistics of the chunked sub-allocator.
+    pub chunked: ChunkedStats,
+
+    /// Statistics of the TLSF sub-allocator.
+    pub tlsf: TlsfStats,
+
+    /// Statistics of the buddy sub-allocator.
+    pub buddy: BuddyStats,
+}
+
+/// Statistics of memory type of a `SmartAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryTypeStats {
+    /// Properties of the memory type.
+    pub properties: Properties,
+
+    /// Index of the heap the memory type allocates from.
+    pub heap_index: usize,
+
+    /// Statistics of the allocator of the memory type.
+    pub allocator: CombinedStats,
+}
+
+/// Statistics of a memory heap of a `SmartAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeapStats {
+    /// Size of the heap.
+    pub size: u64,
+
+    /// Budget of the heap.
+    pub budget: u64,
+
+    /// Memory of the heap used outside of the allocator.
+    pub external: u64,
+
+    /// Number of device memory objects allocated from the heap.
+    pub device_objects: usize,
+
+    /// Number of blocks in use.
+    pub blocks: usize,
+
+    /// Total size of blocks in use.
+    pub used: u64,
+
+    /// Total size of device memory objects.
+    pub allocated: u64,
+}
+
+/// Statistics of a `SmartAllocator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmartStats {
+    /// Statistics of each memory type.
+    pub memory_types: Vec<MemoryTypeStats>,
+
+    /// Statistics of each heap.
+    pub heaps: Vec<HeapStats>,
+}
+
+impl SmartStats {
+    /// Collect statistics of the heaps from statistics of the memory types.
+    pub(crate) fn new(memory_types: Vec<MemoryTypeStats>, heaps: &[Heap]) -> Self {
+        let heaps = heaps
+            .iter()
+            .enumerate()
+            .map(|(index, heap)| {
+                let types = memory_types
+                    .iter()
+                    .filter(|stats| stats.heap_index == index)
+                    .map(|stats| &stats.allocator);
+                types.fold(
+                    HeapStats {
+                        size: heap.size(),
+                        budget: heap.limit(),
+                        external: heap.external(),
+                        device_objects: 0,
+                        blocks: 0,
+                        used: 0,
+                        allocated: 0,
+                    },
+                    |heap, stats| HeapStats {
+                        device_objects: heap.device_objects + stats.device_objects,
+                        blocks: heap.blocks + stats.blocks,
+                        used: heap.used + stats.used,
+                        allocated: heap.allocated + stats.allocated,
+                        ..heap
+                    },
+                )
+            })
+            .collect();
+        SmartStats {
+            memory_types,
+            heaps,
+        }
+    }
+}
@@ -0,0 +1,813 @@
+use std::any::Any;
+use std::fmt::Debug;
+use std::ops::Range;
+
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
+use gfx_hal::memory::Requirements;
+use gfx_hal::{Backend, MemoryTypeId};
+
+use block::{Block, RawBlock};
+#[cfg(feature = "checks")]
+use check_free;
+#[cfg(feature = "dump")]
+use dump::{BlockDump, ChunkDump, TlsfDump};
+#[cfg(feature = "dump")]
+use root::memory_key;
+use stats::TlsfStats;
+use {
+    alignment_shift, BlockError, MappingAllocator, MappingSubAllocator, MemoryAllocator,
+    MemoryError, MemorySubAllocator,
+};
+
+/// Log2 of the number of second level lists per first level.
+const SL_BITS: u32 = 4;
+
+/// Number of second level lists per first level.
+const SL_COUNT: usize = 1 << SL_BITS;
+
+/// Number of first levels, enough for any `u64` size.
+const FL_COUNT: usize = 64 - SL_BITS as usize + 1;
+
+/// Get the list of free segments that a segment of `size` belongs to.
+fn mapping_insert(size: u64) -> (usize, usize) {
+    if size < SL_COUNT as u64 {
+        // Small sizes get a list each
+        (0, size as usize)
+    } else {
+        let log2 = 63 - size.leading_zeros();
+        let fl = (log2 - SL_BITS + 1) as usize;
+        let sl = (size >> (log2 - SL_BITS)) as usize ^ SL_COUNT;
+        (fl, sl)
+    }
+}
+
+/// Get the first list whose free segments are all at least `size` bytes.
+fn mapping_search(size: u64) -> Option<(usize, usize)> {
+    if size < SL_COUNT as u64 {
+        Some(mapping_insert(size))
+    } else {
+        let log2 = 63 - size.leading_zeros();
+        let round = (1 << (log2 - SL_BITS)) - 1;
+        size.checked_add(round).map(mapping_insert)
+    }
+}
+
+/// Range of a chunk, either in use or free.
+#[derive(Debug)]
+struct Segment {
+    chunk_index: usize,
+    /// Offset relative to the chunk
+    offset: u64,
+    size: u64,
+    /// In a list of free segments, or merged into another segment
+    free: bool,
+    /// Physically adjacent segments of the same chunk
+    prev: Option<usize>,
+    next: Option<usize>,
+    /// Neighbours in the list of free segments
+    prev_free: Option<usize>,
+    next_free: Option<usize>,
+}
+
+/// Chunk allocated from super-allocator
+#[derive(Debug)]
+struct TlsfChunk<T> {
+    /// Block from super-allocator
+    block: T,
+    /// Total size of blocks in use
+    used: u64,
+    /// Segment at the start of the chunk. Merged segments keep the lower index, so it never
+    /// changes and spans the whole chunk when the chunk is empty.
+    first: usize,
+}
+
+/// Sub-allocator that can be used for mid-sized objects with medium lifetime.
+///
+/// This allocator implements two-level segregated fit: free ranges of the chunks are kept in
+/// lists segregated by size, found through two levels of bitmaps. Blocks have exactly the
+/// requested size and alignment, and freed blocks are coalesced with adjacent free ranges.
+///
+/// Allocation and freeing take constant time as long as no chunk is allocated or returned.
+/// Allocating a chunk searches for a released chunk slot to reuse, and a chunk becoming empty
+/// searches all chunks for empty ones to return, both linear in the number of chunks.
+///
+/// Chunks are allocated in increments of `chunk_size` bytes. Chunks that become empty are
+/// returned to the underlying allocator, except for `spare_chunks` of them which are kept to avoid
+/// reallocating on every spike. `trim` returns all empty chunks.
+///
+/// ### Type parameters:
+///
+/// - `T`: type of bigger blocks this allocator sub-allocates from.
+#[derive(Debug)]
+pub struct TlsfAllocator<T> {
+    id: MemoryTypeId,
+    chunk_size: u64,
+    spare_chunks: usize,
+    blocks: usize,
+    /// Slots of released chunks are reused.
+    chunks: Vec<Option<TlsfChunk<T>>>,
+    segments: Vec<Segment>,
+    /// Slots of merged segments, to be reused.
+    unused_segments: Vec<usize>,
+    /// Heads of the lists of free segments, `SL_COUNT` per first level.
+    heads: Vec<Option<usize>>,
+    /// Bit per first level that has free segments.
+    fl_bitmap: u64,
+    /// Bit per second level list that has free segments, for each first level.
+    sl_bitmaps: Vec<u32>,
+}
+
+impl<T> TlsfAllocator<T> {
+    /// Create a new TLSF allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `id`: ID of the memory type this allocator allocates from.
+    /// - `chunk_size`: The minimum size of the chunks allocated from the underlying allocator
+    ///   in bytes. All memory is allocated in increments of `chunk_size`.
+    pub fn new(id: MemoryTypeId, chunk_size: u64) -> Self {
+        assert_ne!(chunk_size, 0);
+        TlsfAllocator {
+            id,
+            chunk_size,
+            spare_chunks: 1,
+            blocks: 0,
+            chunks: Vec::new(),
+            segments: Vec::new(),
+            unused_segments: Vec::new(),
+            heads: vec![None; FL_COUNT * SL_COUNT],
+            fl_bitmap: 0,
+            sl_bitmaps: vec![0; FL_COUNT],
+        }
+    }
+
+    /// Check if any of the blocks allocated by this allocator are still in use.
+    /// If this function returns `false`, the allocator can be `dispose`d.
+    pub fn is_used(&self) -> bool {
+        self.blocks != 0
+    }
+
+    /// Get memory type of the allocator
+    pub fn memory_type(&self) -> MemoryTypeId {
+        self.id
+    }
+
+    /// Get the minimum size of each chunk in bytes
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /// Get the number of empty chunks kept.
+    pub fn spare_chunks(&self) -> usize {
+        self.spare_chunks
+    }
+
+    /// Set the number of empty chunks kept instead of returning them to the underlying
+    /// allocator. Takes effect the next time a chunk becomes empty.
+    pub fn set_spare_chunks(&mut self, spare_chunks: usize) {
+        self.spare_chunks = spare_chunks;
+    }
+
+    /// Retrieves the block backing an allocation.
+    pub fn underlying_block<M>(&self, block: &TlsfBlock<M>) -> &T {
+        let chunk_index = self.segments[block.1].chunk_index;
+        &self.chunks[chunk_index]
+            .as_ref()
+            .expect("Chunk was released")
+            .block
+    }
+
+    /// Check that the block can be freed by this allocator.
+    pub fn validate<M>(&self, block: &TlsfBlock<M>) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        self.validate_raw(&block.0, block.1)
+    }
+
+    pub(crate) fn validate_raw<M>(
+        &self,
+        block: &RawBlock<M>,
+        index: usize,
+    ) -> Result<(), BlockError>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        let segment = self.segments.get(index).ok_or(BlockError::ForeignBlock)?;
+        let chunk = match self.chunks.get(segment.chunk_index) {
+            Some(Some(chunk)) => chunk,
+            _ => return Err(BlockError::ReleasedChunk),
+        };
+        if !chunk.block.contains(block) {
+            return Err(BlockError::ForeignBlock);
+        }
+        // Segments of freed blocks are either free or merged into their neighbours,
+        // which marks them free as well
+        if segment.free {
+            return Err(BlockError::DoubleFree);
+        }
+        let offset = block.range().start - chunk.block.range().start;
+        if offset != segment.offset || block.size() != segment.size {
+            return Err(BlockError::InvalidRange);
+        }
+        Ok(())
+    }
+
+    /// Get the total size of all blocks allocated by this allocator.
+    pub fn used(&self) -> u64 {
+        self.chunks.iter().flatten().map(|chunk| chunk.used).sum()
+    }
+
+    /// Get the total size of all chunks allocated by this allocator.
+    pub fn allocated(&self) -> u64
+    where
+        T: Block,
+    {
+        self.chunks
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.block.size())
+            .sum()
+    }
+
+    /// Collect detailed statistics of the allocator.
+    pub fn stats(&self) -> TlsfStats
+    where
+        T: Block,
+    {
+        let free = self.chunks.iter().flatten().flat_map(|chunk| {
+            self.chunk_segments(chunk)
+                .filter(|segment| segment.free)
+                .map(|segment| segment.size)
+        });
+        let (free_ranges, largest_free) = free.fold((0, 0), |(count, largest), size| {
+            (count + 1, largest.max(size))
+        });
+        TlsfStats {
+            chunks: self.chunks.iter().flatten().count(),
+            blocks: self.blocks,
+            used: self.used(),
+            allocated: self.allocated(),
+            free_ranges,
+            largest_free,
+        }
+    }
+
+    /// Dump state of the allocator, including every block in use.
+    #[cfg(feature = "dump")]
+    pub fn dump(&self) -> TlsfDump
+    where
+        T: Block,
+    {
+        TlsfDump {
+            chunk_size: self.chunk_size,
+            chunks: self
+                .chunks
+                .iter()
+                .flatten()
+                .map(|chunk| {
+                    let start = chunk.block.range().start;
+                    ChunkDump {
+                        memory: memory_key(chunk.block.memory()) as u64,
+                        offset: start,
+                        size: chunk.block.size(),
+                        blocks: self
+                            .chunk_segments(chunk)
+                            .filter(|segment| !segment.free)
+                            .map(|segment| BlockDump {
+                                offset: start + segment.offset,
+                                size: segment.size,
+                            })
+                            .collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Return all empty chunks to the underlying allocator.
+    ///
+    /// ### Parameters:
+    ///
+    /// - `owner`: allocator that was used to allocate the chunks
+    /// - `device`: same device that was used to allocate the chunks
+    ///
+    /// ### Safety
+    ///
+    /// `owner` and `device` must be the ones the chunks were allocated with.
+    pub unsafe fn trim<B, O>(&mut self, owner: &mut O, device: &B::Device)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        self.release_empty(owner, device, 0);
+    }
+
+    /// Iterate over segments of the chunk in order of their offsets.
+    fn chunk_segments<'a>(&'a self, chunk: &TlsfChunk<T>) -> impl Iterator<Item = &'a Segment> {
+        let mut next = Some(chunk.first);
+        ::std::iter::from_fn(move || {
+            let segment = &self.segments[next?];
+            next = segment.next;
+            Some(segment)
+        })
+    }
+
+    fn add_segment(&mut self, segment: Segment) -> usize {
+        match self.unused_segments.pop() {
+            Some(index) => {
+                self.segments[index] = segment;
+                index
+            }
+            None => {
+                self.segments.push(segment);
+                self.segments.len() - 1
+            }
+        }
+    }
+
+    /// Make the slot of the segment available for reuse. The segment is marked free, so blocks
+    /// that referred to it are recognized as freed until the slot is reused.
+    fn remove_segment(&mut self, index: usize) {
+        self.segments[index].free = true;
+        self.unused_segments.push(index);
+    }
+
+    /// Put the segment into the list of free segments of its size.
+    fn insert_free(&mut self, index: usize) {
+        let (fl, sl) = mapping_insert(self.segments[index].size);
+        let head = self.heads[fl * SL_COUNT + sl];
+        {
+            let segment = &mut self.segments[index];
+            segment.free = true;
+            segment.prev_free = None;
+            segment.next_free = head;
+        }
+        if let Some(head) = head {
+            self.segments[head].prev_free = Some(index);
+        }
+        self.heads[fl * SL_COUNT + sl] = Some(index);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmaps[fl] |= 1 << sl;
+    }
+
+    /// Take the segment out of the list of free segments.
+    fn remove_free(&mut self, index: usize) {
+        let (fl, sl) = mapping_insert(self.segments[index].size);
+        let (prev_free, next_free) = {
+            let segment = &mut self.segments[index];
+            debug_assert!(segment.free);
+            segment.free = false;
+            (segment.prev_free.take(), segment.next_free.take())
+        };
+        if let Some(next_free) = next_free {
+            self.segments[next_free].prev_free = prev_free;
+        }
+        match prev_free {
+            Some(prev_free) => self.segments[prev_free].next_free = next_free,
+            None => {
+                self.heads[fl * SL_COUNT + sl] = next_free;
+                if next_free.is_none() {
+                    self.sl_bitmaps[fl] &= !(1 << sl);
+                    if self.sl_bitmaps[fl] == 0 {
+                        self.fl_bitmap &= !(1 << fl);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find and take a free segment of at least `size` bytes.
+    fn find_free(&mut self, size: u64) -> Option<usize> {
+        let (fl, sl) = mapping_search(size)?;
+        let sl_map = self.sl_bitmaps[fl] & (!0 << sl);
+        let (fl, sl_map) = if sl_map != 0 {
+            (fl, sl_map)
+        } else {
+            let fl_map = self.fl_bitmap & (!0u64).checked_shl(fl as u32 + 1).unwrap_or(0);
+            if fl_map == 0 {
+                return None;
+            }
+            let fl = fl_map.trailing_zeros() as usize;
+            (fl, self.sl_bitmaps[fl])
+        };
+        let sl = sl_map.trailing_zeros() as usize;
+        let index = self.heads[fl * SL_COUNT + sl].expect("Bitmap is in sync");
+        self.remove_free(index);
+        Some(index)
+    }
+
+    /// Split the segment at `size` bytes, returning the index of the second part.
+    fn split(&mut self, index: usize, size: u64) -> usize {
+        let (chunk_index, offset, rest, next) = {
+            let segment = &self.segments[index];
+            debug_assert!(size < segment.size);
+            (
+                segment.chunk_index,
+                segment.offset + size,
+                segment.size - size,
+                segment.next,
+            )
+        };
+        let split = self.add_segment(Segment {
+            chunk_index,
+            offset,
+            size: rest,
+            free: false,
+            prev: Some(index),
+            next,
+            prev_free: None,
+            next_free: None,
+        });
+        if let Some(next) = next {
+            self.segments[next].prev = Some(split);
+        }
+        let segment = &mut self.segments[index];
+        segment.size = size;
+        segment.next = Some(split);
+        split
+    }
+
+    /// Merge the next segment into this one. Neither may be in the lists of free segments.
+    fn merge_next(&mut self, index: usize) {
+        let next = self.segments[index].next.unwrap();
+        let (size, after) = (self.segments[next].size, self.segments[next].next);
+        if let Some(after) = after {
+            self.segments[after].prev = Some(index);
+        }
+        {
+            let segment = &mut self.segments[index];
+            segment.size += size;
+            segment.next = after;
+        }
+        self.remove_segment(next);
+    }
+
+    unsafe fn grow<B, O>(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        request: O::Request,
+        reqs: Requirements,
+    ) -> Result<usize, MemoryError>
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        let size = ((reqs.size - 1) / self.chunk_size + 1) * self.chunk_size;
+        let chunk_reqs = Requirements {
+            type_mask: 1 << self.id.0,
+            size,
+            alignment: reqs.alignment,
+        };
+        let block = owner.alloc(device, request, chunk_reqs)?;
+        assert!(block.size() >= size);
+
+        // Reuse slot of a released chunk or append a new one
+        let chunk_index = match self.chunks.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.chunks.push(None);
+                self.chunks.len() - 1
+            }
+        };
+        let first = self.add_segment(Segment {
+            chunk_index,
+            offset: 0,
+            size: block.size(),
+            free: false,
+            prev: None,
+            next: None,
+            prev_free: None,
+            next_free: None,
+        });
+        self.chunks[chunk_index] = Some(TlsfChunk {
+            block,
+            used: 0,
+            first,
+        });
+        Ok(first)
+    }
+
+    /// Cut a block satisfying `reqs` out of the free segment, returning the rest to the lists.
+    fn carve<M>(&mut self, index: usize, reqs: Requirements) -> TlsfBlock<M>
+    where
+        M: Debug + Any,
+        T: Block<Memory = M>,
+    {
+        let chunk_index = self.segments[index].chunk_index;
+        let chunk_start = self.chunks[chunk_index]
+            .as_ref()
+            .unwrap()
+            .block
+            .range()
+            .start;
+
+        let mut index = index;
+        let shift = alignment_shift(reqs.alignment, chunk_start + self.segments[index].offset);
+        if shift != 0 {
+            let aligned = self.split(index, shift);
+            self.insert_free(index);
+            index = aligned;
+        }
+        if self.segments[index].size > reqs.size {
+            let rest = self.split(index, reqs.size);
+            self.insert_free(rest);
+        }
+
+        self.blocks += 1;
+        let chunk = self.chunks[chunk_index].as_mut().unwrap();
+        chunk.used += reqs.size;
+        let start = chunk_start + self.segments[index].offset;
+        TlsfBlock(
+            RawBlock::new(chunk.block.memory(), start..start + reqs.size),
+            index,
+        )
+    }
+
+    /// Return empty chunks to super-allocator, keeping at most `keep` of them.
+    unsafe fn release_empty<B, O>(&mut self, owner: &mut O, device: &B::Device, keep: usize)
+    where
+        B: Backend,
+        T: Block<Memory = B::Memory>,
+        O: MemoryAllocator<B, Block = T>,
+    {
+        let mut kept = 0;
+        for chunk_index in 0..self.chunks.len() {
+            match self.chunks[chunk_index] {
+                Some(TlsfChunk { used: 0, .. }) if kept < keep => kept += 1,
+                Some(TlsfChunk { used: 0, first, .. }) => {
+                    self.remove_free(first);
+                    self.remove_segment(first);
+                    let chunk = self.chunks[chunk_index].take().unwrap();
+                    owner.free(device, chunk.block);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<B, O, T> MemorySubAllocator<B, O> for TlsfAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MemoryAllocator<B, Block = T>,
+{
+    type Request = O::Request;
+    type Block = TlsfBlock<B::Memory>;
+
+    unsafe fn alloc(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        request: O::Request,
+        reqs: Requirements,
+    ) -> Result<TlsfBlock<B::Memory>, MemoryError> {
+        if (1 << self.id.0) & reqs.type_mask == 0 {
+            return Err(MemoryError::NoCompatibleMemoryType);
+        }
+        assert_ne!(reqs.size, 0);
+        // Any segment this big fits the block after alignment
+        let index = match reqs
+            .size
+            .checked_add(reqs.alignment - 1)
+            .and_then(|size| self.find_free(size))
+        {
+            Some(index) => index,
+            None => self.grow(owner, device, request, reqs)?,
+        };
+        let block = self.carve(index, reqs);
+        assert_eq!(block.range().start & (reqs.alignment - 1), 0);
+        Ok(block)
+    }
+
+    unsafe fn free(&mut self, owner: &mut O, device: &B::Device, block: TlsfBlock<B::Memory>) {
+        #[cfg(feature = "checks")]
+        check_free(&block, self.validate(&block));
+        let TlsfBlock(block, index) = block;
+        let chunk_index = {
+            let segment = &self.segments[index];
+            assert!(!segment.free);
+            segment.chunk_index
+        };
+        {
+            let chunk = self.chunks[chunk_index]
+                .as_mut()
+                .expect("Block of released chunk");
+            assert!(chunk.block.contains(&block));
+            assert_eq!(
+                block.range().start - chunk.block.range().start,
+                self.segments[index].offset
+            );
+            chunk.used -= block.size();
+        }
+        block.dispose();
+        self.blocks -= 1;
+
+        // Coalesce with free neighbours
+        let mut index = index;
+        if let Some(next) = self.segments[index].next {
+            if self.segments[next].free {
+                self.remove_free(next);
+                self.merge_next(index);
+            }
+        }
+        if let Some(prev) = self.segments[index].prev {
+            if self.segments[prev].free {
+                self.remove_free(prev);
+                self.merge_next(prev);
+                index = prev;
+            }
+        }
+        self.insert_free(index);
+
+        if self.chunks[chunk_index].as_ref().unwrap().used == 0 {
+            let keep = self.spare_chunks;
+            self.release_empty(owner, device, keep);
+        }
+    }
+
+    unsafe fn dispose(mut self, owner: &mut O, device: &B::Device) -> Result<(), Self> {
+        if self.is_used() {
+            Err(self)
+        } else {
+            for chunk in self.chunks.drain(..).flatten() {
+                owner.free(device, chunk.block);
+            }
+            Ok(())
+        }
+    }
+}
+
+impl<B, O, T> MappingSubAllocator<B, O> for TlsfAllocator<T>
+where
+    B: Backend,
+    T: Block<Memory = B::Memory>,
+    O: MappingAllocator<B, Block = T>,
+{
+    unsafe fn map(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &TlsfBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        if range.start > range.end || range.end > block.size() {
+            return Err(MappingError::OutOfBounds);
+        }
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.map(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn unmap(&mut self, owner: &mut O, device: &B::Device, block: &TlsfBlock<B::Memory>) {
+        owner.unmap(device, self.underlying_block(block))
+    }
+
+    unsafe fn flush(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &TlsfBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.flush(device, chunk, offset + range.start..offset + range.end)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        owner: &mut O,
+        device: &B::Device,
+        block: &TlsfBlock<B::Memory>,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        assert!(range.start <= range.end && range.end <= block.size());
+        let chunk = self.underlying_block(block);
+        let offset = block.range().start - chunk.range().start;
+        owner.invalidate(device, chunk, offset + range.start..offset + range.end)
+    }
+}
+
+/// `Block` type returned by `TlsfAllocator`.
+#[derive(Debug)]
+pub struct TlsfBlock<M>(pub(crate) RawBlock<M>, pub(crate) usize);
+
+impl<M> Block for TlsfBlock<M>
+where
+    M: Debug + Any,
+{
+    type Memory = M;
+
+    #[inline(always)]
+    fn memory(&self) -> &M {
+        self.0.memory()
+    }
+
+    #[inline(always)]
+    fn range(&self) -> Range<u64> {
+        self.0.range()
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn test_send_sync() {
+    fn foo<T: Send + Sync>() {}
+    fn bar<M: Send + Sync>() {
+        foo::<TlsfAllocator<M>>()
+    }
+}
+
+#[test]
+fn test_alloc_and_coalesce() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut tlsf = TlsfAllocator::new(MemoryTypeId(0), 4096);
+    let reqs = |size, alignment| Requirements {
+        size,
+        alignment,
+        type_mask: 1,
+    };
+    unsafe {
+        let first = tlsf.alloc(&mut root, &device, (), reqs(100, 1)).unwrap();
+        let second = tlsf.alloc(&mut root, &device, (), reqs(1000, 512)).unwrap();
+        let third = tlsf.alloc(&mut root, &device, (), reqs(300, 1)).unwrap();
+        // Blocks have exactly the requested sizes and share one chunk.
+        assert_eq!(device.object_count(), 1);
+        assert_eq!(first.size(), 100);
+        assert_eq!(second.size(), 1000);
+        assert_eq!(second.range().start % 512, 0);
+        assert_eq!(tlsf.used(), 1400);
+
+        // Chunks grow to fit big blocks.
+        let big = tlsf.alloc(&mut root, &device, (), reqs(5000, 1)).unwrap();
+        assert_eq!(device.object_count(), 2);
+        assert_eq!(tlsf.allocated(), 4096 + 8192);
+        tlsf.free(&mut root, &device, big);
+
+        tlsf.free(&mut root, &device, first);
+        tlsf.free(&mut root, &device, third);
+        assert_eq!(tlsf.stats().free_ranges, 3);
+        tlsf.free(&mut root, &device, second);
+        assert!(!tlsf.is_used());
+
+        // Freed ranges are coalesced back into the whole chunk.
+        let stats = tlsf.stats();
+        assert_eq!(stats.free_ranges, 1);
+        assert_eq!(stats.largest_free, 4096);
+        let whole = tlsf.alloc(&mut root, &device, (), reqs(4096, 1)).unwrap();
+        assert_eq!(device.object_count(), 1);
+        tlsf.free(&mut root, &device, whole);
+
+        tlsf.trim(&mut root, &device);
+        assert_eq!(device.object_count(), 0);
+        tlsf.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
+
+#[test]
+fn test_validate_merged() {
+    use mock::{MockBackend, MockDevice};
+    use root::RootAllocator;
+
+    let device = MockDevice::default();
+    let mut root = RootAllocator::<MockBackend>::new(MemoryTypeId(0), 1);
+    let mut tlsf = TlsfAllocator::new(MemoryTypeId(0), 4096);
+    let reqs = Requirements {
+        size: 256,
+        alignment: 1,
+        type_mask: 1,
+    };
+    unsafe {
+        let first = tlsf.alloc(&mut root, &device, (), reqs).unwrap();
+        let second = tlsf.alloc(&mut root, &device, (), reqs).unwrap();
+        let third = tlsf.alloc(&mut root, &device, (), reqs).unwrap();
+        assert_eq!(tlsf.validate(&second), Ok(()));
+
+        // Copy of the block as left behind by a cloned pointer.
+        let copy = TlsfBlock(RawBlock::new(second.memory(), second.range()), second.1);
+        tlsf.free(&mut root, &device, first);
+        // The segment of the second block is merged into the free first one.
+        tlsf.free(&mut root, &device, second);
+        assert_eq!(tlsf.validate(&copy), Err(BlockError::DoubleFree));
+        copy.0.dispose();
+
+        tlsf.free(&mut root, &device, third);
+        tlsf.trim(&mut root, &device);
+        tlsf.dispose(&mut root, &device).unwrap();
+        root.dispose(&device).unwrap();
+    }
+}
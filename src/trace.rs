@@ -0,0 +1,751 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::marker::PhantomData;
+use std::ops::Range;
+
+use gfx_hal::device::OutOfMemory;
+use gfx_hal::mapping::Error as MappingError;
+use gfx_hal::memory::{Properties, Requirements};
+use gfx_hal::{Backend, MemoryTypeId};
+
+use block::Block;
+use combined::{CombinedAllocator, ResourceKind, Type};
+#[cfg(any(test, feature = "mock"))]
+use mock::{MockBackend, MockDevice};
+use root::{memory_key, RootAllocator};
+use smart::{RequestedProperties, SmartAllocator};
+use {MappingAllocator, MemoryAllocator, MemoryError};
+
+/// Request type of an allocator that can be written to a trace and parsed back.
+/// The written form must not contain whitespace.
+pub trait TraceRequest: Clone + Sized {
+    /// Write the request to a trace.
+    fn write_trace(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+
+    /// Parse a request written by `write_trace`.
+    fn parse_trace(s: &str) -> Option<Self>;
+}
+
+impl TraceRequest for () {
+    fn write_trace(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-")
+    }
+
+    fn parse_trace(s: &str) -> Option<()> {
+        if s == "-" {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl TraceRequest for Type {
+    fn write_trace(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+
+    fn parse_trace(s: &str) -> Option<Type> {
+        match s {
+            "ShortLived" => Some(Type::ShortLived),
+            "General" => Some(Type::General),
+            "MediumLived" => Some(Type::MediumLived),
+            "Dedicated" => Some(Type::Dedicated),
+            _ => None,
+        }
+    }
+}
+
+impl TraceRequest for ResourceKind {
+    fn write_trace(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+
+    fn parse_trace(s: &str) -> Option<ResourceKind> {
+        match s {
+            "Linear" => Some(ResourceKind::Linear),
+            "Optimal" => Some(ResourceKind::Optimal),
+            "Unknown" => Some(ResourceKind::Unknown),
+            _ => None,
+        }
+    }
+}
+
+impl TraceRequest for (Type, ResourceKind) {
+    fn write_trace(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.write_trace(f)?;
+        write!(f, ":")?;
+        self.1.write_trace(f)
+    }
+
+    fn parse_trace(s: &str) -> Option<(Type, ResourceKind)> {
+        let mut parts = s.split(':');
+        let ty = Type::parse_trace(parts.next()?)?;
+        let kind = ResourceKind::parse_trace(parts.next()?)?;
+        match parts.next() {
+            None => Some((ty, kind)),
+            Some(_) => None,
+        }
+    }
+}
+
+impl TraceRequest for (Type, RequestedProperties, ResourceKind) {
+    fn write_trace(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.write_trace(f)?;
+        write!(
+            f,
+            ":{:#x}:{:#x}:",
+            self.1.required.bits(),
+            self.1.preferred.bits()
+        )?;
+        self.2.write_trace(f)
+    }
+
+    fn parse_trace(s: &str) -> Option<(Type, RequestedProperties, ResourceKind)> {
+        let mut parts = s.split(':');
+        let ty = Type::parse_trace(parts.next()?)?;
+        let mut properties = || {
+            let bits = u16::from_str_radix(parts.next()?.strip_prefix("0x")?, 16).ok()?;
+            Properties::from_bits(bits)
+        };
+        let usage = RequestedProperties {
+            required: properties()?,
+            preferred: properties()?,
+        };
+        let kind = ResourceKind::parse_trace(parts.next()?)?;
+        match parts.next() {
+            None => Some((ty, usage, kind)),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Allocator that can tell the memory type of its blocks, so its allocations can be traced.
+pub trait TraceAllocator<B: Backend>: MemoryAllocator<B> {
+    /// Get the memory type a block was allocated from.
+    fn block_memory_type(&self, block: &Self::Block) -> MemoryTypeId;
+}
+
+impl<B> TraceAllocator<B> for RootAllocator<B>
+where
+    B: Backend,
+{
+    fn block_memory_type(&self, _block: &Self::Block) -> MemoryTypeId {
+        self.memory_type()
+    }
+}
+
+impl<B> TraceAllocator<B> for CombinedAllocator<B>
+where
+    B: Backend,
+{
+    fn block_memory_type(&self, _block: &Self::Block) -> MemoryTypeId {
+        self.memory_type()
+    }
+}
+
+impl<B> TraceAllocator<B> for SmartAllocator<B>
+where
+    B: Backend,
+{
+    fn block_memory_type(&self, block: &Self::Block) -> MemoryTypeId {
+        MemoryTypeId(block.1)
+    }
+}
+
+/// Block allocated while tracing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TracedBlock {
+    /// Memory type the block was allocated from.
+    pub memory_type: MemoryTypeId,
+
+    /// Identifier of the device memory object the block belongs to.
+    pub memory: u64,
+
+    /// Offset of the block in the memory object.
+    pub offset: u64,
+
+    /// Size of the block.
+    pub size: u64,
+}
+
+/// Line of a trace written by `TracingAllocator`.
+///
+/// Traces are line-based text. Allocations are written as
+/// `alloc <id> <request> <size> <alignment> <type mask> -> <memory type> <memory> <offset> <size>`,
+/// or with `failed: <error>` in place of the arrow and block when the allocation failed.
+/// Frees are written as `free <id>`. Lines starting with `#` are comments.
+#[derive(Clone, Debug)]
+pub enum TraceEvent<R> {
+    /// Allocation of a block.
+    Alloc {
+        /// Identifier of the allocation, referred to when the block is freed.
+        id: u64,
+
+        /// Request passed to the allocator.
+        request: R,
+
+        /// Requirements passed to the allocator.
+        reqs: Requirements,
+
+        /// Allocated block, `None` if the allocation failed.
+        block: Option<TracedBlock>,
+    },
+
+    /// Freeing of a block.
+    Free {
+        /// Identifier of the allocation of the block.
+        id: u64,
+    },
+}
+
+impl<R> TraceEvent<R>
+where
+    R: TraceRequest,
+{
+    /// Parse a line of a trace. Returns `None` for malformed lines.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let event = match words.next()? {
+            "alloc" => {
+                let id = words.next()?.parse().ok()?;
+                let request = R::parse_trace(words.next()?)?;
+                let reqs = Requirements {
+                    size: words.next()?.parse().ok()?,
+                    alignment: words.next()?.parse().ok()?,
+                    type_mask: parse_hex(words.next()?)?,
+                };
+                let block = match words.next()? {
+                    "->" => Some(TracedBlock {
+                        memory_type: MemoryTypeId(words.next()?.parse().ok()?),
+                        memory: parse_hex(words.next()?)?,
+                        offset: words.next()?.parse().ok()?,
+                        size: words.next()?.parse().ok()?,
+                    }),
+                    "failed:" => {
+                        return Some(TraceEvent::Alloc {
+                            id,
+                            request,
+                            reqs,
+                            block: None,
+                        })
+                    }
+                    _ => return None,
+                };
+                TraceEvent::Alloc {
+                    id,
+                    request,
+                    reqs,
+                    block,
+                }
+            }
+            "free" => TraceEvent::Free {
+                id: words.next()?.parse().ok()?,
+            },
+            _ => return None,
+        };
+        match words.next() {
+            None => Some(event),
+            Some(_) => None,
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+/// Line of a trace with the error of a failed allocation.
+struct TraceLine<'a, R: 'a>(&'a TraceEvent<R>, Option<&'a MemoryError>);
+
+impl<'a, R> fmt::Display for TraceLine<'a, R>
+where
+    R: TraceRequest,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self.0 {
+            TraceEvent::Alloc {
+                id,
+                ref request,
+                reqs,
+                block,
+            } => {
+                write!(f, "alloc {} ", id)?;
+                request.write_trace(f)?;
+                write!(f, " {} {} {:#x}", reqs.size, reqs.alignment, reqs.type_mask)?;
+                match (block, self.1) {
+                    (Some(block), _) => write!(
+                        f,
+                        " -> {} {:#x} {} {}",
+                        block.memory_type.0, block.memory, block.offset, block.size
+                    ),
+                    (None, Some(error)) => write!(f, " failed: {}", error),
+                    (None, None) => write!(f, " failed:"),
+                }
+            }
+            TraceEvent::Free { id } => write!(f, "free {}", id),
+        }
+    }
+}
+
+impl<R> fmt::Display for TraceEvent<R>
+where
+    R: TraceRequest,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        TraceLine(self, None).fmt(f)
+    }
+}
+
+/// Error of reading a trace.
+#[derive(Debug, Fail)]
+pub enum TraceError {
+    /// Reading the trace failed.
+    #[fail(display = "Failed to read trace: {}", _0)]
+    Io(#[cause] io::Error),
+
+    /// A line of the trace is malformed.
+    #[fail(display = "Malformed line {} of trace", _0)]
+    Malformed(usize),
+}
+
+impl From<io::Error> for TraceError {
+    fn from(error: io::Error) -> Self {
+        TraceError::Io(error)
+    }
+}
+
+/// Read a trace written by `TracingAllocator`, skipping empty lines and comments.
+pub fn read_trace<R, T>(reader: T) -> Result<Vec<TraceEvent<R>>, TraceError>
+where
+    R: TraceRequest,
+    T: BufRead,
+{
+    let mut events = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        events.push(TraceEvent::parse(line).ok_or(TraceError::Malformed(index + 1))?);
+    }
+    Ok(events)
+}
+
+/// Layer over a `MemoryAllocator` that writes every allocation and free to a trace.
+///
+/// The trace can be read with `read_trace` and replayed against a mock device with `replay`
+/// to reproduce out of memory errors and fragmentation. See `TraceEvent` for the format.
+///
+/// Errors of writing the trace don't fail allocations. The first one is kept and can be taken
+/// with `take_error`.
+///
+/// ### Type parameters:
+///
+/// - `B`: hal `Backend`
+/// - `A`: inner allocator
+/// - `W`: writer of the trace
+#[derive(Debug)]
+pub struct TracingAllocator<B, A, W>
+where
+    B: Backend,
+    A: TraceAllocator<B>,
+{
+    allocator: A,
+    out: W,
+    ids: HashMap<(usize, u64), u64>,
+    next_id: u64,
+    error: Option<io::Error>,
+    pd: PhantomData<fn() -> B>,
+}
+
+impl<B, A, W> TracingAllocator<B, A, W>
+where
+    B: Backend,
+    A: TraceAllocator<B>,
+    A::Request: TraceRequest,
+    W: Write,
+{
+    /// Wrap an allocator, writing the trace to `out`.
+    pub fn new(allocator: A, out: W) -> Self {
+        TracingAllocator {
+            allocator,
+            out,
+            ids: HashMap::new(),
+            next_id: 0,
+            error: None,
+            pd: PhantomData,
+        }
+    }
+
+    /// Get the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.allocator
+    }
+
+    /// Get the inner allocator mutably. Allocations made directly with it are not traced.
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.allocator
+    }
+
+    /// Take the first error of writing the trace, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Unwrap the inner allocator and the writer of the trace.
+    pub fn into_inner(self) -> (A, W) {
+        (self.allocator, self.out)
+    }
+
+    fn write(&mut self, line: TraceLine<'_, A::Request>) {
+        if let Err(error) = writeln!(self.out, "{}", line) {
+            self.error.get_or_insert(error);
+        }
+    }
+}
+
+impl<B, A, W> MemoryAllocator<B> for TracingAllocator<B, A, W>
+where
+    B: Backend,
+    A: TraceAllocator<B>,
+    A::Request: TraceRequest,
+    W: Write + fmt::Debug,
+{
+    type Request = A::Request;
+    type Block = A::Block;
+
+    unsafe fn alloc(
+        &mut self,
+        device: &B::Device,
+        request: A::Request,
+        reqs: Requirements,
+    ) -> Result<A::Block, MemoryError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let result = self.allocator.alloc(device, request.clone(), reqs);
+        let block = result.as_ref().ok().map(|block| TracedBlock {
+            memory_type: self.allocator.block_memory_type(block),
+            memory: memory_key(block.memory()) as u64,
+            offset: block.range().start,
+            size: block.size(),
+        });
+        if let Some(ref block) = block {
+            self.ids.insert((block.memory as usize, block.offset), id);
+        }
+        let event = TraceEvent::Alloc {
+            id,
+            request,
+            reqs,
+            block,
+        };
+        self.write(TraceLine(&event, result.as_ref().err()));
+        result
+    }
+
+    unsafe fn free(&mut self, device: &B::Device, block: A::Block) {
+        let key = (memory_key(block.memory()), block.range().start);
+        if let Some(id) = self.ids.remove(&key) {
+            self.write(TraceLine(&TraceEvent::Free { id }, None));
+        }
+        self.allocator.free(device, block);
+    }
+
+    fn is_used(&self) -> bool {
+        self.allocator.is_used()
+    }
+
+    unsafe fn dispose(self, device: &B::Device) -> Result<(), Self> {
+        let TracingAllocator {
+            allocator,
+            out,
+            ids,
+            next_id,
+            error,
+            pd,
+        } = self;
+        allocator
+            .dispose(device)
+            .map_err(|allocator| TracingAllocator {
+                allocator,
+                out,
+                ids,
+                next_id,
+                error,
+                pd,
+            })
+    }
+}
+
+impl<B, A, W> MappingAllocator<B> for TracingAllocator<B, A, W>
+where
+    B: Backend,
+    A: TraceAllocator<B> + MappingAllocator<B>,
+    A::Request: TraceRequest,
+    W: Write + fmt::Debug,
+{
+    unsafe fn map(
+        &mut self,
+        device: &B::Device,
+        block: &A::Block,
+        range: Range<u64>,
+    ) -> Result<*mut u8, MappingError> {
+        self.allocator.map(device, block, range)
+    }
+
+    unsafe fn unmap(&mut self, device: &B::Device, block: &A::Block) {
+        self.allocator.unmap(device, block)
+    }
+
+    unsafe fn flush(
+        &mut self,
+        device: &B::Device,
+        block: &A::Block,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        self.allocator.flush(device, block, range)
+    }
+
+    unsafe fn invalidate(
+        &mut self,
+        device: &B::Device,
+        block: &A::Block,
+        range: Range<u64>,
+    ) -> Result<(), OutOfMemory> {
+        self.allocator.invalidate(device, block, range)
+    }
+}
+
+/// Allocation that failed during `replay`.
+#[derive(Clone, Debug)]
+pub struct ReplayFailure {
+    /// Index of the allocation in the replayed events.
+    pub event: usize,
+
+    /// Identifier of the allocation in the trace.
+    pub id: u64,
+
+    /// Requirements of the allocation.
+    pub reqs: Requirements,
+
+    /// Error returned by the allocator.
+    pub error: MemoryError,
+
+    /// Whether the allocation failed when it was traced as well.
+    pub traced: bool,
+}
+
+/// Results of `replay`.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// Number of replayed allocations, including failed ones.
+    pub allocations: usize,
+
+    /// Allocations that failed.
+    pub failures: Vec<ReplayFailure>,
+
+    /// Highest total size of blocks in use at the same time.
+    pub peak_used: u64,
+
+    /// Highest size of memory objects allocated from each heap at the same time.
+    pub peak_heap_usage: Vec<u64>,
+
+    /// Number of blocks the trace never freed. They are freed at the end of the replay.
+    pub leaked: usize,
+}
+
+/// Replay a trace against a mock device, reporting peak usage and failed allocations.
+///
+/// Configure the memory layout with `MockDevice::new` and the allocator as usual to find out how
+/// they cope with the allocations of the trace. Type masks are replayed as traced, so the device
+/// should have the memory types of the traced one. Frees of blocks that failed to allocate are
+/// skipped.
+///
+/// ### Parameters:
+///
+/// - `device`: mock device to allocate from
+/// - `allocator`: allocator to replay the trace with
+/// - `events`: trace read by `read_trace`
+///
+/// ### Safety
+///
+/// `allocator` must allocate from `device`.
+#[cfg(any(test, feature = "mock"))]
+pub unsafe fn replay<A>(
+    device: &MockDevice,
+    allocator: &mut A,
+    events: &[TraceEvent<A::Request>],
+) -> ReplayReport
+where
+    A: MemoryAllocator<MockBackend>,
+    A::Request: TraceRequest,
+{
+    let heaps = device.memory_properties().memory_heaps.len();
+    let mut report = ReplayReport {
+        peak_heap_usage: vec![0; heaps],
+        ..ReplayReport::default()
+    };
+    let mut blocks = HashMap::new();
+    let mut used = 0;
+    for (index, event) in events.iter().enumerate() {
+        match *event {
+            TraceEvent::Alloc {
+                id,
+                ref request,
+                reqs,
+                block,
+            } => {
+                report.allocations += 1;
+                match allocator.alloc(device, request.clone(), reqs) {
+                    Ok(block) => {
+                        used += block.size();
+                        blocks.insert(id, block);
+                    }
+                    Err(error) => report.failures.push(ReplayFailure {
+                        event: index,
+                        id,
+                        reqs,
+                        error,
+                        traced: block.is_none(),
+                    }),
+                }
+                report.peak_used = report.peak_used.max(used);
+                for (heap, peak) in report.peak_heap_usage.iter_mut().enumerate() {
+                    *peak = (*peak).max(device.heap_usage(heap));
+                }
+            }
+            TraceEvent::Free { id } => {
+                if let Some(block) = blocks.remove(&id) {
+                    used -= block.size();
+                    allocator.free(device, block);
+                }
+            }
+        }
+    }
+    report.leaked = blocks.len();
+    for (_, block) in blocks {
+        allocator.free(device, block);
+    }
+    report
+}
+
+#[test]
+#[allow(dead_code)]
+fn test_send_sync() {
+    fn foo<T: Send + Sync>() {}
+    fn bar<B: Backend>() {
+        foo::<TracingAllocator<B, SmartAllocator<B>, Vec<u8>>>()
+    }
+}
+
+#[test]
+fn test_trace_and_replay() {
+    use gfx_hal::{MemoryProperties, MemoryType};
+    use smart::MemoryUsage;
+
+    let device = MockDevice::default();
+    let smart = || {
+        SmartAllocator::<MockBackend>::new(
+            device.memory_properties(),
+            1 << 16,
+            64,
+            256,
+            1 << 20,
+            device.non_coherent_atom_size(),
+            device.limits().buffer_image_granularity,
+        )
+    };
+    let mut allocator = TracingAllocator::new(smart(), Vec::new());
+    let reqs = |size| Requirements {
+        size,
+        alignment: 256,
+        type_mask: !0,
+    };
+    unsafe {
+        let first = allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(1024),
+            )
+            .unwrap();
+        let second = allocator
+            .alloc(
+                &device,
+                (
+                    Type::Dedicated,
+                    MemoryUsage::GpuOnly.into(),
+                    ResourceKind::Optimal,
+                ),
+                reqs(4 << 20),
+            )
+            .unwrap();
+        allocator.free(&device, first);
+        allocator.free(&device, second);
+        allocator
+            .alloc(
+                &device,
+                (
+                    Type::General,
+                    Properties::DEVICE_LOCAL.into(),
+                    ResourceKind::Linear,
+                ),
+                reqs(2 << 30),
+            )
+            .unwrap_err();
+        let (smart, trace) = allocator.into_inner();
+        smart.dispose(&device).unwrap();
+
+        let events =
+            read_trace::<(Type, RequestedProperties, ResourceKind), _>(&trace[..]).unwrap();
+        assert_eq!(events.len(), 5);
+        match events[1] {
+            TraceEvent::Alloc {
+                id: 1,
+                request: (Type::Dedicated, usage, ResourceKind::Optimal),
+                block: Some(block),
+                ..
+            } => {
+                assert_eq!(usage, MemoryUsage::GpuOnly.into());
+                assert_eq!((block.memory_type, block.size), (MemoryTypeId(0), 4 << 20));
+            }
+            ref event => panic!("Unexpected event {}", event),
+        }
+
+        // Replay with only 2 MiB of device local memory.
+        let device = MockDevice::new(
+            MemoryProperties {
+                memory_heaps: vec![2 << 20, 256 << 20],
+                memory_types: vec![MemoryType {
+                    properties: Properties::DEVICE_LOCAL,
+                    heap_index: 0,
+                }],
+            },
+            256,
+        );
+        let mut allocator = SmartAllocator::<MockBackend>::new(
+            device.memory_properties(),
+            1 << 16,
+            64,
+            256,
+            1 << 20,
+            device.non_coherent_atom_size(),
+            device.limits().buffer_image_granularity,
+        );
+        let report = replay(&device, &mut allocator, &events);
+        assert_eq!(report.allocations, 3);
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(report.failures[0].id, 1);
+        assert!(!report.failures[0].traced);
+        assert!(report.failures[1].traced);
+        assert_eq!(report.peak_used, 1024);
+        assert_eq!(report.leaked, 0);
+        allocator.dispose(&device).unwrap();
+    }
+}